@@ -1,5 +1,9 @@
+use std::fmt;
+
 use itertools::Itertools;
 
+use crate::{builtins::Builtin, error::{Error, Span}};
+
 #[derive(Debug)]
 pub enum Action {
     FunctionDef(FunctionDef),
@@ -12,48 +16,204 @@ pub enum Command {
     ShowCode(String),
     ListFunctions(),
     DeleteFunction(String),
+    ShadowBuiltin(String),
     SwitchMode(String),
+    // Domains of the free variables, which replace the domain of the mode
+    SetRange(i32, i32),
+    SetValues(Vec<i32>),
+    // Number of random values and the seed, if any
+    SetRandom(usize, Option<u64>),
+    SetEdges(usize, Option<u64>),
     SwitchExecutor(String),
     Test(Expr),
+    SetMaxCallDepth(usize),
+    SetThreads(usize),
+    // Checkpoints of subsequent queries are written to the file, if any
+    SetCheckpointFile(Option<String>),
+    Resume(String),
+    // Queries count all counterexamples and list at most this many ranges of them, if set
+    SetCounting(Option<usize>),
+    // Query and the values of its free variables
+    Explain(Expr, Vec<(String, i32)>),
+    // Counterexamples of subsequent queries are explained if set
+    SetExplaining(bool),
+    ShowStats,
     Benchmark
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FunctionDef {
     pub name: String,
-    pub parameter: Option<String>,
+    pub parameters: Vec<String>,
+    pub parameter_spans: Vec<SourceSpan>,
     pub body: Expr,
 }
 
-#[derive(Debug, Clone)]
+/// Span of the token in the input which an expression was parsed from, if it is known. Spans are
+/// ignored by comparisons, so expressions with the same tree are equal wherever they were parsed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SourceSpan(pub Option<Span>);
+
+impl PartialEq for SourceSpan {
+    fn eq(&self, _: &SourceSpan) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Number(i32),
-    Var(String),
-    FunctionCall(String, Option<Box<Expr>>),
+    Var(String, SourceSpan),
+    FunctionCall(String, Vec<Expr>, SourceSpan),
+    BuiltinCall(Builtin, Vec<Expr>, SourceSpan),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    BitNot(Box<Expr>),
     Add(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
     Mul(Box<Expr>, Box<Expr>),
     Div(Box<Expr>, Box<Expr>),
     Rem(Box<Expr>, Box<Expr>),
+    BitAnd(Box<Expr>, Box<Expr>),
+    BitOr(Box<Expr>, Box<Expr>),
+    BitXor(Box<Expr>, Box<Expr>),
+    // Only the lowest 5 bits of the shift amount are used, e.g. x << 33 is x << 1
+    Shl(Box<Expr>, Box<Expr>),
+    // Arithmetic (>>) and logical (>>>) shift to the right
+    Sar(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
     Eq(Box<Expr>, Box<Expr>),
     Neq(Box<Expr>, Box<Expr>),
     Gt(Box<Expr>, Box<Expr>),
     Lt(Box<Expr>, Box<Expr>),
     Gte(Box<Expr>, Box<Expr>),
     Lte(Box<Expr>, Box<Expr>),
+    // The right operand of the connectives is only evaluated if it determines the result
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Implies(Box<Expr>, Box<Expr>),
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
 }
 
 impl Expr {
     pub fn used_variables(&self) -> Vec<String> {
+        self.variable_occurrences().into_iter().map(|(var, _)| var.to_string()).unique().collect_vec()
+    }
+
+    /// Returns all occurrences of variables within the expression in the order of the input.
+    fn variable_occurrences(&self) -> Vec<(&str, SourceSpan)> {
         let mut vars = Vec::new();
-        self.add_used_variables(&mut vars);
-        vars.into_iter().unique().collect_vec()
+        self.add_variable_occurrences(&mut vars);
+        vars
+    }
+
+    fn add_variable_occurrences<'a>(&'a self, vars: &mut Vec<(&'a str, SourceSpan)>) {
+        match self {
+            Expr::Var(v, source) => vars.push((v, *source)),
+            _ => {
+                for expr in self.sub_expressions() {
+                    expr.add_variable_occurrences(vars);
+                }
+            }
+        }
+    }
+
+    /// Returns all function calls within the expression as (name, number of arguments).
+    pub fn function_calls(&self) -> Vec<(String, usize)> {
+        let mut calls = Vec::new();
+        self.add_function_calls(&mut calls);
+        calls
+    }
+
+    fn add_function_calls(&self, calls: &mut Vec<(String, usize)>) {
+        if let Expr::FunctionCall(name, args, _) = self {
+            calls.push((name.clone(), args.len()));
+        }
+        for expr in self.sub_expressions() {
+            expr.add_function_calls(calls);
+        }
+    }
+
+    /// Checks that all calls to functions with a known arity pass the correct number of arguments.
+    /// The arity of built-in functions is always known.
+    pub fn check_arity(&self, arity_of: &dyn Fn(&str) -> Option<usize>) -> Result<(), Error> {
+        self.check_builtin_arity()?;
+        self.check_function_arity(arity_of)
+    }
+
+    fn check_builtin_arity(&self) -> Result<(), Error> {
+        if let Expr::BuiltinCall(builtin, args, source) = self {
+            if args.len() != builtin.arity() {
+                return Err(Error::ArityMismatch { name: builtin.name().to_string(), expected: builtin.arity(), found: args.len(), caller: None, span: source.0 });
+            }
+        }
+        self.sub_expressions().into_iter().try_for_each(Expr::check_builtin_arity)
+    }
+
+    fn check_function_arity(&self, arity_of: &dyn Fn(&str) -> Option<usize>) -> Result<(), Error> {
+        if let Expr::FunctionCall(name, args, source) = self {
+            if let Some(arity) = arity_of(name).filter(|arity| *arity != args.len()) {
+                return Err(Error::ArityMismatch { name: name.clone(), expected: arity, found: args.len(), caller: None, span: source.0 });
+            }
+        }
+        self.sub_expressions().into_iter().try_for_each(|expr| expr.check_function_arity(arity_of))
+    }
+
+    /// Replaces the calls of the shadowed built-in functions by calls of the user-defined functions.
+    pub fn shadow_builtins(&mut self, is_shadowed: &dyn Fn(Builtin) -> bool) {
+        if let Expr::BuiltinCall(builtin, args, source) = self {
+            if is_shadowed(*builtin) {
+                *self = Expr::FunctionCall(builtin.name().to_string(), std::mem::take(args), *source);
+            }
+        }
+        for expr in self.sub_expressions_mut() {
+            expr.shadow_builtins(is_shadowed);
+        }
+    }
+
+    /// Forgets the spans of the expression, e.g. if it was not parsed from the current input.
+    pub fn clear_spans(&mut self) {
+        if let Expr::Var(_, source) | Expr::FunctionCall(_, _, source) | Expr::BuiltinCall(_, _, source) = self {
+            *source = SourceSpan::default();
+        }
+        for expr in self.sub_expressions_mut() {
+            expr.clear_spans();
+        }
+    }
+
+    pub fn sub_expressions(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Number(_) | Expr::Var(_, _) => vec![],
+            Expr::Neg(operand) | Expr::Not(operand) | Expr::BitNot(operand) => vec![operand],
+            Expr::Add(lhs, rhs)
+            | Expr::Sub(lhs, rhs)
+            | Expr::Mul(lhs, rhs)
+            | Expr::Div(lhs, rhs)
+            | Expr::Eq(lhs, rhs)
+            | Expr::Neq(lhs, rhs)
+            | Expr::Rem(lhs, rhs)
+            | Expr::BitAnd(lhs, rhs)
+            | Expr::BitOr(lhs, rhs)
+            | Expr::BitXor(lhs, rhs)
+            | Expr::Shl(lhs, rhs)
+            | Expr::Sar(lhs, rhs)
+            | Expr::Shr(lhs, rhs)
+            | Expr::Gt(lhs, rhs)
+            | Expr::Lt(lhs, rhs)
+            | Expr::Gte(lhs, rhs)
+            | Expr::Lte(lhs, rhs)
+            | Expr::And(lhs, rhs)
+            | Expr::Or(lhs, rhs)
+            | Expr::Implies(lhs, rhs) => vec![lhs, rhs],
+            Expr::FunctionCall(_, args, _) | Expr::BuiltinCall(_, args, _) => args.iter().collect(),
+            Expr::If(condition, then_expr, else_expr) => vec![condition, then_expr, else_expr],
+        }
     }
 
-    fn add_used_variables(&self, mut vars: &mut Vec<String>) {
+    fn sub_expressions_mut(&mut self) -> Vec<&mut Expr> {
         match self {
-            Expr::Number(_) => {}
-            Expr::Var(v) => vars.push(v.clone()),
+            Expr::Number(_) | Expr::Var(_, _) => vec![],
+            Expr::Neg(operand) | Expr::Not(operand) | Expr::BitNot(operand) => vec![operand],
             Expr::Add(lhs, rhs)
             | Expr::Sub(lhs, rhs)
             | Expr::Mul(lhs, rhs)
@@ -61,18 +221,144 @@ impl Expr {
             | Expr::Eq(lhs, rhs)
             | Expr::Neq(lhs, rhs)
             | Expr::Rem(lhs, rhs)
+            | Expr::BitAnd(lhs, rhs)
+            | Expr::BitOr(lhs, rhs)
+            | Expr::BitXor(lhs, rhs)
+            | Expr::Shl(lhs, rhs)
+            | Expr::Sar(lhs, rhs)
+            | Expr::Shr(lhs, rhs)
             | Expr::Gt(lhs, rhs)
             | Expr::Lt(lhs, rhs)
             | Expr::Gte(lhs, rhs)
-            | Expr::Lte(lhs, rhs) => {
-                lhs.add_used_variables(&mut vars);
-                rhs.add_used_variables(&mut vars);
+            | Expr::Lte(lhs, rhs)
+            | Expr::And(lhs, rhs)
+            | Expr::Or(lhs, rhs)
+            | Expr::Implies(lhs, rhs) => vec![lhs, rhs],
+            Expr::FunctionCall(_, args, _) | Expr::BuiltinCall(_, args, _) => args.iter_mut().collect(),
+            Expr::If(condition, then_expr, else_expr) => vec![condition, then_expr, else_expr],
+        }
+    }
+
+    /// For `&&`, `||` and `=>`: whether the right operand is skipped if the left operand holds (or else
+    /// if it does not hold), and the result in this case. Otherwise, the result is whether the right
+    /// operand holds.
+    pub fn short_circuit(&self) -> Option<(bool, i32)> {
+        match self {
+            Expr::And(_, _) => Some((false, 0)),
+            Expr::Or(_, _) => Some((true, 1)),
+            Expr::Implies(_, _) => Some((false, 1)),
+            _ => None,
+        }
+    }
+
+    /// Operators with a higher precedence bind stronger.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::If(_, _, _) => 0,
+            Expr::Implies(_, _) => 1,
+            Expr::Or(_, _) => 2,
+            Expr::And(_, _) => 3,
+            Expr::Eq(_, _) | Expr::Neq(_, _) | Expr::Gt(_, _) | Expr::Lt(_, _) | Expr::Gte(_, _) | Expr::Lte(_, _) => 4,
+            Expr::BitOr(_, _) => 5,
+            Expr::BitXor(_, _) => 6,
+            Expr::BitAnd(_, _) => 7,
+            Expr::Shl(_, _) | Expr::Sar(_, _) | Expr::Shr(_, _) => 8,
+            Expr::Add(_, _) | Expr::Sub(_, _) => 9,
+            Expr::Mul(_, _) | Expr::Div(_, _) | Expr::Rem(_, _) => 10,
+            Expr::Neg(_) | Expr::Not(_) | Expr::BitNot(_) => 11,
+            Expr::Number(_) | Expr::Var(_, _) | Expr::FunctionCall(_, _, _) | Expr::BuiltinCall(_, _, _) => 12,
+        }
+    }
+}
+
+impl FunctionDef {
+    /// Validates the definition against the already defined functions. The body may only use the
+    /// parameters of the function and all calls between the functions must agree on the arity.
+    pub fn validate<'a>(&self, functions: impl Iterator<Item = &'a FunctionDef>) -> Result<(), Error> {
+        for (i, (parameter, source)) in self.parameters.iter().zip(&self.parameter_spans).enumerate() {
+            if self.parameters[..i].contains(parameter) {
+                return Err(Error::DuplicateParameter { name: parameter.clone(), function: self.name.clone(), span: source.0 });
             }
-            Expr::FunctionCall(_, expr) => {
-                if let Some(expr) = expr {
-                    expr.add_used_variables(&mut vars)
-                }
+        }
+        if let Some((var, source)) = self.body.variable_occurrences().into_iter().find(|(var, _)| !self.parameters.iter().any(|parameter| parameter == var)) {
+            return Err(Error::UndefinedVariable { name: var.to_string(), function: Some(self.name.clone()), span: source.0 });
+        }
+
+        let others = functions.filter(|function| function.name != self.name).collect_vec();
+        self.body.check_arity(&|name| {
+            if name == self.name {
+                return Some(self.parameters.len());
             }
+            others.iter().find(|function| function.name == name).map(|function| function.parameters.len())
+        })?;
+        for other in others {
+            other.body.check_arity(&|name| if name == self.name { Some(self.parameters.len()) } else { None })
+                .map_err(|error| match error {
+                    Error::ArityMismatch { name, expected, found, .. } => Error::ArityMismatch { name, expected, found, caller: Some(other.name.clone()), span: None },
+                    error => error,
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the expression in the syntax of the parser. Operands whose operator binds weaker than the
+/// enclosing one are parenthesized, as are operands with the same precedence on the side against the
+/// associativity of the operator. Thus the printed expression is parsed into the same tree.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (lhs, operator, rhs) = match self {
+            Expr::Number(value) => return write!(f, "{}", value),
+            Expr::Var(name, _) => return write!(f, "{}", name),
+            Expr::FunctionCall(name, args, _) => return write!(f, "{}({})", name, args.iter().join(", ")),
+            Expr::BuiltinCall(builtin, args, _) => return write!(f, "{}({})", builtin.name(), args.iter().join(", ")),
+            Expr::If(condition, then_expr, else_expr) => return write!(f, "if {} then {} else {}", condition, then_expr, else_expr),
+            // -5 would be parsed as a negative literal
+            Expr::Neg(operand) if matches!(**operand, Expr::Number(value) if value >= 0) => return write!(f, "-({})", operand),
+            Expr::Neg(operand) => return write!(f, "-{}", Operand(operand, self.precedence())),
+            Expr::Not(operand) => return write!(f, "!{}", Operand(operand, self.precedence())),
+            Expr::BitNot(operand) => return write!(f, "~{}", Operand(operand, self.precedence())),
+            Expr::Add(lhs, rhs) => (lhs, "+", rhs),
+            Expr::Sub(lhs, rhs) => (lhs, "-", rhs),
+            Expr::Mul(lhs, rhs) => (lhs, "*", rhs),
+            Expr::Div(lhs, rhs) => (lhs, "/", rhs),
+            Expr::Rem(lhs, rhs) => (lhs, "%", rhs),
+            Expr::BitAnd(lhs, rhs) => (lhs, "&", rhs),
+            Expr::BitOr(lhs, rhs) => (lhs, "|", rhs),
+            Expr::BitXor(lhs, rhs) => (lhs, "^", rhs),
+            Expr::Shl(lhs, rhs) => (lhs, "<<", rhs),
+            Expr::Sar(lhs, rhs) => (lhs, ">>", rhs),
+            Expr::Shr(lhs, rhs) => (lhs, ">>>", rhs),
+            Expr::Eq(lhs, rhs) => (lhs, "=", rhs),
+            Expr::Neq(lhs, rhs) => (lhs, "<>", rhs),
+            Expr::Gt(lhs, rhs) => (lhs, ">", rhs),
+            Expr::Lt(lhs, rhs) => (lhs, "<", rhs),
+            Expr::Gte(lhs, rhs) => (lhs, ">=", rhs),
+            Expr::Lte(lhs, rhs) => (lhs, "<=", rhs),
+            Expr::And(lhs, rhs) => (lhs, "&&", rhs),
+            Expr::Or(lhs, rhs) => (lhs, "||", rhs),
+            // The only right-associative operator
+            Expr::Implies(lhs, rhs) => return write!(f, "{} => {}", Operand(lhs, self.precedence() + 1), Operand(rhs, self.precedence())),
+        };
+        write!(f, "{} {} {}", Operand(lhs, self.precedence()), operator, Operand(rhs, self.precedence() + 1))
+    }
+}
+
+/// Operand which is printed without parentheses if its precedence is at least the given one.
+struct Operand<'a>(&'a Expr, u8);
+
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.precedence() >= self.1 {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "({})", self.0)
         }
     }
 }
+
+impl fmt::Display for FunctionDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({}) := {}", self.name, self.parameters.join(", "), self.body)
+    }
+}
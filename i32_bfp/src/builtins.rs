@@ -0,0 +1,82 @@
+use std::fmt;
+
+/// Functions which are known to both executors without a definition. Calls of built-in functions are
+/// evaluated inline instead of calling a function. A user-defined function may replace a built-in
+/// function after `.shadow <name>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Builtin {
+    // abs(-2147483648) is -2147483648
+    Abs,
+    Min,
+    Max,
+    // -1, 0 or 1
+    Sign,
+    // clamp(x, lo, hi) is min(max(x, lo), hi)
+    Clamp,
+    Popcnt,
+    // Leading and trailing zero bits, 32 for 0
+    Clz,
+    Ctz,
+    // Reverses the order of the bytes
+    Bswap,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 9] = [
+        Builtin::Abs, Builtin::Min, Builtin::Max, Builtin::Sign, Builtin::Clamp,
+        Builtin::Popcnt, Builtin::Clz, Builtin::Ctz, Builtin::Bswap,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        Builtin::ALL.iter().copied().find(|builtin| builtin.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Abs => "abs",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+            Builtin::Sign => "sign",
+            Builtin::Clamp => "clamp",
+            Builtin::Popcnt => "popcnt",
+            Builtin::Clz => "clz",
+            Builtin::Ctz => "ctz",
+            Builtin::Bswap => "bswap",
+        }
+    }
+
+    pub fn parameters(self) -> &'static [&'static str] {
+        match self {
+            Builtin::Min | Builtin::Max => &["a", "b"],
+            Builtin::Clamp => &["x", "lo", "hi"],
+            _ => &["x"],
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        self.parameters().len()
+    }
+
+    /// Evaluates the function for arguments of the correct arity. Built-in functions never fail.
+    pub fn eval(self, args: &[i32]) -> i32 {
+        match (self, args) {
+            (Builtin::Abs, [x]) => x.wrapping_abs(),
+            (Builtin::Min, [a, b]) => *a.min(b),
+            (Builtin::Max, [a, b]) => *a.max(b),
+            (Builtin::Sign, [x]) => x.signum(),
+            (Builtin::Clamp, [x, lo, hi]) => *x.max(lo).min(hi),
+            (Builtin::Popcnt, [x]) => x.count_ones() as i32,
+            (Builtin::Clz, [x]) => x.leading_zeros() as i32,
+            (Builtin::Ctz, [x]) => x.trailing_zeros() as i32,
+            (Builtin::Bswap, [x]) => x.swap_bytes(),
+            _ => panic!("{} expects {} argument(s) but is called with {}", self.name(), self.arity(), args.len()),
+        }
+    }
+}
+
+/// Prints the signature, e.g. `min(a, b)`.
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", self.name(), self.parameters().join(", "))
+    }
+}
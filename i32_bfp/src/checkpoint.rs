@@ -0,0 +1,129 @@
+use std::{fmt, fs};
+
+use crate::{ast::{Action, Expr, FunctionDef}, domain::Domain, error::Error, parser::parse, runtime::format_assignment};
+
+/// Progress of a query, which is saved to a file so that the query can be resumed after it was
+/// interrupted or the process ended. The file consists of lines `key: value` and can be inspected and
+/// edited by hand:
+///
+/// ```text
+/// query: f(x) <> x
+/// function: f(x) := x * 2
+/// variable: x in -2147483648..2147483647
+/// last: x = -2139095041
+/// ```
+///
+/// All inputs up to and including the last input were checked. Lines starting with `#` are ignored.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub query: Expr,
+    // Definitions of all functions which may be called by the query
+    pub functions: Vec<FunctionDef>,
+    // Domains of the free variables in the order of `Expr::used_variables`
+    pub domains: Vec<Domain>,
+    pub last_checked: Option<Vec<i32>>,
+}
+
+impl Checkpoint {
+    pub fn read(file: &str) -> Result<Checkpoint, Error> {
+        let text = fs::read_to_string(file).map_err(|error| Error::CheckpointRead { file: file.to_string(), reason: error.to_string() })?;
+        Checkpoint::parse(&text).map_err(|reason| Error::InvalidCheckpoint { file: file.to_string(), reason })
+    }
+
+    /// The file is replaced at once, so it contains a complete checkpoint even if the process ends
+    /// while writing.
+    pub fn write(&self, file: &str) -> Result<(), Error> {
+        let temporary = format!("{}.tmp", file);
+        fs::write(&temporary, self.to_string())
+            .and_then(|_| fs::rename(&temporary, file))
+            .map_err(|error| Error::CheckpointWrite { file: file.to_string(), reason: error.to_string() })
+    }
+
+    fn parse(text: &str) -> Result<Checkpoint, String> {
+        let mut query = None;
+        let mut functions = Vec::new();
+        let mut variables = Vec::new();
+        let mut last = None;
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let (key, value) = line.split_once(':').ok_or_else(|| format!("Expected 'key: value' but found '{}'", line))?;
+            let value = value.trim();
+            match key.trim() {
+                "query" => match parse(value).map_err(|error| error.to_string())? {
+                    // The spans refer to the checkpoint instead of the input
+                    Action::Query(mut expr) => {
+                        expr.clear_spans();
+                        query = Some(expr);
+                    }
+                    _ => return Err(format!("{} is not a query", value)),
+                },
+                "function" => match parse(value).map_err(|error| error.to_string())? {
+                    Action::FunctionDef(function_def) => functions.push(function_def),
+                    _ => return Err(format!("{} is not a function definition", value)),
+                },
+                "variable" => {
+                    let (var, domain) = value.split_once(" in ").ok_or_else(|| format!("Expected '<variable> in <domain>' but found '{}'", value))?;
+                    variables.push((var.trim().to_string(), parse_domain(domain)?));
+                }
+                "last" => last = Some(parse_assignment(value)?),
+                key => return Err(format!("Unknown key {}", key)),
+            }
+        }
+
+        let query = query.ok_or("The query is missing")?;
+        let vars = query.used_variables();
+        if !variables.iter().map(|(var, _)| var).eq(&vars) {
+            return Err(format!("Expected the domains of the variables {}", vars.join(", ")));
+        }
+        let last_checked = match last {
+            Some(last) if !last.iter().map(|(var, _)| var).eq(&vars) => return Err(format!("Expected the last input to assign the variables {}", vars.join(", "))),
+            last => last.map(|last| last.into_iter().map(|(_, value)| value).collect()),
+        };
+        Ok(Checkpoint {
+            query,
+            functions,
+            domains: variables.into_iter().map(|(_, domain)| domain).collect(),
+            last_checked,
+        })
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# Checkpoint of a query. Continue it with .resume <file>")?;
+        writeln!(f, "query: {}", self.query)?;
+        for function in &self.functions {
+            writeln!(f, "function: {}", function)?;
+        }
+        let vars = self.query.used_variables();
+        for (var, domain) in vars.iter().zip(&self.domains) {
+            writeln!(f, "variable: {} in {}", var, domain)?;
+        }
+        if let Some(last_checked) = &self.last_checked {
+            writeln!(f, "last: {}", format_assignment(&vars, last_checked))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a domain in the format of `Domain::fmt`, e.g. `-10..10` or `-1, 0, 1`.
+fn parse_domain(text: &str) -> Result<Domain, String> {
+    let parse_value = |value: &str| value.trim().parse::<i32>().map_err(|error| format!("Invalid value {}: {}", value.trim(), error));
+    match text.split_once("..") {
+        Some((from, to)) => Ok(Domain::Range(parse_value(from)?, parse_value(to)?)),
+        None => Ok(Domain::Values(text.split(',').map(parse_value).collect::<Result<_, _>>()?)),
+    }
+}
+
+/// Parses an assignment in the format of `format_assignment`, e.g. `x = 1, y = 2`.
+fn parse_assignment(text: &str) -> Result<Vec<(String, i32)>, String> {
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    text.split(',')
+        .map(|part| {
+            let (var, value) = part.split_once('=').ok_or_else(|| format!("Expected '<variable> = <value>' but found '{}'", part.trim()))?;
+            let value = value.trim().parse().map_err(|error| format!("Invalid value {}: {}", value.trim(), error))?;
+            Ok((var.trim().to_string(), value))
+        })
+        .collect()
+}
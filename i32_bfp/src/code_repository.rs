@@ -1,66 +1,145 @@
-use std::{collections::HashMap, slice};
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
 use dynasm::dynasm;
-use dynasmrt::{DynasmApi, DynasmLabelApi};
-
-use crate::{ast::{FunctionDef}, compiler::{CompilationContext, Runable, call_function}};
-
+use dynasmrt::{DynasmApi, Register};
 
+use crate::{ast::{Expr, FunctionDef}, error::Error, compiler::{CompilationContext, FunctionSlot, PARAMETER_REGISTERS, Runable, call_stack_size, emit_call}};
 
 #[derive(Debug)]
 pub struct CodeRepository {
+    // The mutex holding the repository. Stubs pass its address to `call_compiler`, which stays valid
+    // when the owner of the repository is moved.
+    this: Weak<Mutex<CodeRepository>>,
+    // Stub or compiled code of every defined function
     code: HashMap<String, Runable>,
+    // Slots of all functions which are defined or called. The stub patches the slot once the function
+    // is compiled. Slots are never removed, as compiled code refers to them. They are boxed so their
+    // address does not change, and only modified through shared references, as other threads may read
+    // them at any time.
+    slots: HashMap<String, Box<FunctionSlot>>,
     ast: HashMap<String, FunctionDef>,
-    // The graveyard should alleviate segfaults which were happening. If our stub code is executed
-    // it generates the real code for the called function and replaces it in the code map. 
-    // This would mean that the existing code would be dropped, but out IP is still within that
-    // code block once the call to the newly compiled code terminates. A simple (but hacky) solution
-    // is to keep the code in memory. This is done by the graveyard.
-    graveyard: Vec<Runable>
+    // Signatures of all defined functions, compiled or not
+    definitions: HashMap<String, FunctionDef>,
+    // Stubs of compiled functions. A stub is still executing when it is replaced by the compiled code,
+    // as `call_compiler` returns into the stub, which then jumps to the compiled code. Other threads
+    // may still be waiting in the stub for the compiler. Therefore, retired stubs are only dropped when
+    // a function is defined or deleted, as no code is executed then.
+    retired_stubs: Vec<Runable>,
+    // Number of stubs called, i.e. of calls of functions which were not compiled yet
+    stub_calls: usize,
+}
+
+/// Memory used by the code repository.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodeStats {
+    pub buffers: usize,
+    pub bytes: usize,
 }
 
 impl CodeRepository {
-    pub fn new() -> CodeRepository {
-        CodeRepository {
+    /// The repository is shared with the stubs of its functions, which may be called by several threads.
+    pub fn new() -> Arc<Mutex<CodeRepository>> {
+        Arc::new_cyclic(|this| Mutex::new(CodeRepository {
+            this: this.clone(),
             code: HashMap::new(),
+            slots: HashMap::new(),
             ast: HashMap::new(),
-            graveyard: Vec::new()
-        }
+            definitions: HashMap::new(),
+            retired_stubs: Vec::new(),
+            stub_calls: 0,
+        }))
     }
     
-    pub fn add_placeholder(&mut self, function_def: FunctionDef) -> Result<(), String> {
+    pub fn add_placeholder(&mut self, function_def: FunctionDef) -> Result<(), Error> {
+        function_def.validate(self.definitions.values())?;
+        self.retired_stubs.clear();
+        self.reserve_slots(&function_def.body);
+        let slot = self.reserve_slot(&function_def.name);
         let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+        let offset = ops.offset();
 
+        let code_repository_ptr = self.this.as_ptr();
+
+        // The stub saves all parameter registers, lets the compiler generate the code of the function
+        // and restores the registers afterwards. Then, the stack looks exactly like when the stub was
+        // called, so it can jump to the compiled code, which returns to the caller of the stub.
+        let save_displacement = call_stack_size(2);
         dynasm!(ops
             ; .arch x64
-            ; ->fn_name:
-            ; .bytes function_def.name.as_bytes()
+            ; push rbp
+            ; mov rbp, rsp
+            ; sub rsp, DWORD (save_displacement + 8 * PARAMETER_REGISTERS.len() as i32 + 15) / 16 * 16
         );
-
-        let offset = ops.offset();
-
-        let code_repository_ptr = self as *const CodeRepository;
-
+        for (i, reg) in PARAMETER_REGISTERS.iter().enumerate() {
+            dynasm!(ops
+                ; mov QWORD [rsp + save_displacement + 8 * i as i32], Rq(reg.code())
+            );
+        }
+        emit_call(&mut ops, call_compiler as *const (), &[code_repository_ptr as i64, slot as i64]);
+        for (i, reg) in PARAMETER_REGISTERS.iter().enumerate() {
+            dynasm!(ops
+                ; mov Rq(reg.code()), QWORD [rsp + save_displacement + 8 * i as i32]
+            );
+        }
         dynasm!(ops
-            ; mov r9, rcx
-            ; mov rcx, QWORD code_repository_ptr as i64
-            ; lea rdx, [->fn_name]
-            ; mov r8, QWORD function_def.name.len() as _
-            ; mov rax, QWORD call_compiler as _
-            ; sub rsp, BYTE 0x28
-            ; call rax
-            ; add rsp, BYTE 0x28
-            ; ret
+            ; mov rsp, rbp
+            ; pop rbp
+            // The slot either points to the compiled code or, if compiling failed, traps.
+            ; mov rax, QWORD slot as _
+            ; jmp QWORD [rax]
         );
 
         let runable = Runable::new(ops.finalize().unwrap(), offset);
-        self.code.insert(function_def.name.clone(), runable);
+        self.set_code(&function_def.name, runable);
+        self.definitions.insert(function_def.name.clone(), function_def.clone());
         self.ast.insert(function_def.name.clone(), function_def);
 
         Ok(())
     }
 
-    pub fn get_fn(&self, name: &str) -> Option<&Runable> {
-        self.code.get(name)
+    /// Replaces the code of the function and patches its slot. The previous code is dropped.
+    fn set_code(&mut self, name: &str, runable: Runable) {
+        self.slots[name].set_entry(runable.ptr());
+        self.code.insert(name.to_string(), runable);
+    }
+
+    fn reserve_slot(&mut self, name: &str) -> *const FunctionSlot {
+        let slot = self.slots.entry(name.to_string()).or_insert_with(|| Box::new(FunctionSlot::new(name)));
+        &**slot
+    }
+
+    /// Makes sure that there is a slot for every function called in the expression. This has to be
+    /// done before the expression is compiled.
+    pub fn reserve_slots(&mut self, expr: &Expr) {
+        for (name, _) in expr.function_calls() {
+            self.reserve_slot(&name);
+        }
+    }
+
+    pub fn slot(&self, name: &str) -> Option<*const FunctionSlot> {
+        self.slots.get(name).map(|slot| &**slot as *const FunctionSlot)
+    }
+
+    pub fn stats(&self) -> CodeStats {
+        let buffers = self.code.values().chain(self.retired_stubs.iter());
+        CodeStats {
+            buffers: buffers.clone().count(),
+            bytes: buffers.map(Runable::size).sum(),
+        }
+    }
+
+    pub fn stub_calls(&self) -> usize {
+        self.stub_calls
+    }
+
+    pub fn arity(&self, name: &str) -> Option<usize> {
+        self.definitions.get(name).map(|function_def| function_def.parameters.len())
+    }
+
+    /// Whether the function is defined and its stub was replaced, even if compiling failed.
+    pub fn is_compiled(&self, name: &str) -> bool {
+        self.definitions.contains_key(name) && !self.ast.contains_key(name)
     }
 
     pub fn pop_ast(&mut self, name: &str) -> Option<FunctionDef> {
@@ -68,50 +147,113 @@ impl CodeRepository {
     }
 
     pub fn delete(&mut self, name: &str) {
+        self.retired_stubs.clear();
         self.code.remove(name);
+        if let Some(slot) = self.slots.get(name) {
+            slot.clear();
+        }
+        self.ast.remove(name);
+        self.definitions.remove(name);
+    }
+
+    /// Compiles the function, which must not be compiled yet, and replaces its stub. If compiling fails,
+    /// the definition is kept and calls of the function report the error. Returns whether compiling
+    /// succeeded.
+    fn compile(&mut self, name: &str) -> bool {
+        let function_def = self.pop_ast(name).expect("Could not find function definition in repository.");
+        let mut ctx = CompilationContext::new(self);
+        ctx.set_function_name(name);
+        for parameter in &function_def.parameters {
+            ctx.add_parameter(parameter.clone());
+        }
+        let compiled = ctx.compile(&function_def.body);
+
+        let stub = self.code.remove(name).expect("Could not remove current code from code repository");
+        self.retired_stubs.push(stub);
+
+        match compiled {
+            Ok(runable) => {
+                self.set_code(name, runable);
+                true
+            },
+            Err(error) => {
+                self.fail(name, error);
+                false
+            }
+        }
+    }
+
+    /// Makes calls of the function report the error. The stub is kept, as it may still be executing.
+    fn fail(&mut self, name: &str, error: Error) {
+        println!("JIT> Compiling failed with error {}.", error);
         self.ast.remove(name);
+        if let Some(stub) = self.code.remove(name) {
+            self.retired_stubs.push(stub);
+        }
+        self.slots[name].set_compile_error(error);
+    }
+
+    /// Compiles all functions which may be called when evaluating the expression, including the callees
+    /// of functions which are already compiled, as they may have been redefined since. Afterwards, the
+    /// compiled code of the expression does not reach any stub. Such code only reads the slots and can
+    /// be executed by several threads at once, as long as the repository is not modified.
+    pub fn compile_reachable_functions(&mut self, expr: &Expr) {
+        let mut visited = HashSet::new();
+        let mut pending: Vec<String> = expr.function_calls().into_iter().map(|(name, _)| name).collect();
+        while let Some(name) = pending.pop() {
+            if !visited.insert(name.clone()) {
+                continue;
+            }
+            if let Some(function_def) = self.definitions.get(&name) {
+                pending.extend(function_def.body.function_calls().into_iter().map(|(name, _)| name));
+                if self.ast.contains_key(&name) {
+                    println!("JIT> Compiling function {} ahead of time ...", name);
+                    self.compile(&name);
+                }
+            }
+        }
     }
 
     pub fn list_functions(&self) {
-        for key in self.code.keys() {
-            println!("{}{}", key, if self.ast.contains_key(key) { " (Not yet compiled)" } else { "" })
+        for key in self.definitions.keys() {
+            match self.slots[key].compile_error() {
+                Some(error) => println!("{} (Compiling failed: {})", key, error),
+                None => println!("{}{}", key, if self.is_compiled(key) { "" } else { " (Not yet compiled)" }),
+            }
         }
     }
 
     pub fn print_code(&self, name: &str) {
-        match self.code.get(name) {
-            Some(runable) => runable.print(),
-            None => println!("No code entry found for fn {}.", name)
+        match (self.code.get(name), self.slots.get(name).and_then(|slot| slot.compile_error())) {
+            (Some(runable), _) => runable.print(),
+            (None, Some(error)) => println!("Compiling fn {} failed: {}", name, error),
+            (None, None) => println!("No code entry found for fn {}.", name)
         }
     }
 }
 
-pub extern "win64" fn call_compiler(code_repository: &mut CodeRepository, buffer: *const u8, length: u64, arg: i32) -> i32 {
-    let fn_name = unsafe { slice::from_raw_parts(buffer, length as usize) };
-    let fn_name = std::str::from_utf8(fn_name).unwrap();
-    println!("JIT> Uncompiled function {} called. Compiling ...", fn_name);
-
-    let function_def = code_repository.pop_ast(fn_name).expect("Could not find function definition in repository.");
-    let mut ctx = CompilationContext::new(code_repository);
-    let mut result = Result::Ok(());
-    if let Some(var) = function_def.parameter.clone() {
-        result = result.and_then(|_| ctx.assign_register_to_variable(var).map(|_| ()));
+/// Compiles the function and patches its slot. It is called by the stub of the function, possibly by
+/// several threads at once. Panics of the compiler must not unwind into the generated code, they are
+/// reported like compile errors instead.
+pub extern "C" fn call_compiler(code_repository: *const Mutex<CodeRepository>, slot: *const FunctionSlot) {
+    // The stub is owned by the repository, which owns the slot as well
+    let (code_repository, slot) = unsafe { (&*code_repository, &*slot) };
+    let mut code_repository = code_repository.lock().unwrap_or_else(PoisonError::into_inner);
+    code_repository.stub_calls += 1;
+    let fn_name = slot.name().to_string();
+    // Another thread compiled the function while this one waited for the lock
+    if !code_repository.ast.contains_key(&fn_name) {
+        return;
     }
-    let compiled = result.and_then(|_| ctx.compile(&function_def.body));
-    
-    let stub = code_repository.code.remove(fn_name).expect("Could not remove current code from code repository");
-    code_repository.graveyard.push(stub);
-
-    match compiled {
-        Ok(runable) => {
-            code_repository.code.insert(fn_name.to_string(), runable);
-            println!("JIT> Calling newly compiled function");
-            call_function(code_repository, buffer, length, arg)
-        },
-        Err(message) => {
-            println!("JIT> Compiling failed with error {}.", message);
-            println!("JIT> Definition was removed.");
-            0
+    println!("JIT> Uncompiled function {} called. Compiling ...", fn_name);
+    match panic::catch_unwind(AssertUnwindSafe(|| code_repository.compile(&fn_name))) {
+        Ok(true) => println!("JIT> Jumping to newly compiled function"),
+        Ok(false) => {}
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "the compiler panicked".to_string());
+            code_repository.fail(&fn_name, Error::CompilerPanic { message });
         }
     }
-}
\ No newline at end of file
+}
@@ -1,42 +1,161 @@
-use crate::{ast, code_repository::CodeRepository, compiler::{CompilationContext}, runtime::Executor};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, atomic::AtomicBool};
+
+use crate::{ast, error::Error, code_repository::{CodeRepository, CodeStats}, compiler::{CompilationContext, ExecutionContext, SCAN_NOT_FOUND}, runtime::{DEFAULT_MAX_CALL_DEPTH, Executor, NEVER_INTERRUPTED, QueryRunable, ScanRunable}, vector_compiler::{self, VectorCompilationContext}};
 
 pub struct CompiledExecutor {
-    code_repository: CodeRepository
+    // Only locked while the repository is used, never while compiled code is executed, as its stubs
+    // lock the repository as well
+    code_repository: Arc<Mutex<CodeRepository>>,
+    max_call_depth: usize,
+    interrupted: &'static AtomicBool,
+    // Scans are vectorized if the CPU supports it
+    vectorize: bool
 }
 
 impl CompiledExecutor {
     pub fn new() -> CompiledExecutor {
         CompiledExecutor {
-            code_repository: CodeRepository::new()
+            code_repository: CodeRepository::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            interrupted: &NEVER_INTERRUPTED,
+            vectorize: vector_compiler::is_supported()
         }
     }
 
+    fn code_repository(&self) -> MutexGuard<'_, CodeRepository> {
+        self.code_repository.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
     pub fn list_functions(&self) {
-        self.code_repository.list_functions();
+        self.code_repository().list_functions();
     }
 
     pub fn print_code(&self, name: &str) {
-        self.code_repository.print_code(name);
+        self.code_repository().print_code(name);
+    }
+
+    pub fn print_stats(&self) {
+        let stats = self.code_stats();
+        println!("{} code buffers with a total size of {} bytes", stats.buffers, stats.bytes);
+        println!("{} calls of functions which were not compiled yet", self.stub_calls());
+    }
+
+    pub fn code_stats(&self) -> CodeStats {
+        self.code_repository().stats()
+    }
+
+    pub fn stub_calls(&self) -> usize {
+        self.code_repository().stub_calls()
+    }
+
+    /// Scans only run the scalar code if vectorizing is turned off.
+    #[cfg(test)]
+    pub fn set_vectorize(&mut self, vectorize: bool) {
+        self.vectorize = vectorize && vector_compiler::is_supported();
+    }
+
+    /// Needed before the code of a query is executed by several threads.
+    pub fn compile_reachable_functions(&mut self, query: &ast::Expr) {
+        self.code_repository().compile_reachable_functions(query);
     }
 }
 
 impl Executor for CompiledExecutor {
-    fn handle_function_def(&mut self, func_def: ast::FunctionDef) -> Result<(), String> {
-        Ok(self.code_repository.add_placeholder(func_def)?)
+    fn handle_function_def(&mut self, func_def: ast::FunctionDef) -> Result<(), Error> {
+        self.code_repository().add_placeholder(func_def)
     }
     
-    fn get_query_runable(&mut self, query: ast::Expr) -> Result<Box<dyn Fn(i32) -> i32>, String> {
+    fn get_query_runable(&mut self, query: ast::Expr) -> Result<QueryRunable<'_>, Error> {
         let used_vars = query.used_variables();
-        let mut ctx = CompilationContext::new(&mut self.code_repository);
+        let mut code_repository = self.code_repository();
+        query.check_arity(&|name| code_repository.arity(name))?;
+        code_repository.reserve_slots(&query);
+        let mut ctx = CompilationContext::new(&code_repository);
     
         for used_var in &used_vars {
-            ctx.assign_register_to_variable(used_var.to_string())?;
+            ctx.add_parameter(used_var.to_string());
         }
         let runable = ctx.compile(&query)?;
-        Ok(Box::new(move |x| runable.call(x)))
+        let max_call_depth = self.max_call_depth;
+        let interrupted = self.interrupted;
+        Ok(Box::new(move |args| {
+            let mut execution_context = ExecutionContext::new(max_call_depth, interrupted);
+            let result = runable.call(&mut execution_context, args);
+            execution_context.result(result)
+        }))
+    }
+
+    fn get_scan_runable(&mut self, query: ast::Expr) -> Result<Option<ScanRunable<'_>>, Error> {
+        let mut used_vars = query.used_variables();
+        let loop_var = match used_vars.pop() {
+            Some(loop_var) => loop_var,
+            None => return Ok(None),
+        };
+        let mut code_repository = self.code_repository();
+        query.check_arity(&|name| code_repository.arity(name))?;
+        code_repository.reserve_slots(&query);
+        let mut ctx = CompilationContext::new(&code_repository);
+
+        for used_var in used_vars.iter().chain(std::iter::once(&loop_var)) {
+            ctx.add_parameter(used_var.to_string());
+        }
+        let runable = ctx.compile_scan(&query, &loop_var)?;
+        // Queries without function calls are additionally compiled to vector code, which skips the
+        // blocks without counterexample. The remaining blocks are checked by the scalar code.
+        let vector_runable = if self.vectorize && vector_compiler::can_vectorize(&query) {
+            let mut ctx = VectorCompilationContext::new();
+            for used_var in used_vars.iter().chain(std::iter::once(&loop_var)) {
+                ctx.add_parameter(used_var.to_string());
+            }
+            Some(ctx.compile_scan(&query, &loop_var)?)
+        } else {
+            None
+        };
+        let max_call_depth = self.max_call_depth;
+        let interrupted = self.interrupted;
+        let scan = move |fixed_args: &[i32], from: i32, to: i32| {
+            let mut args = fixed_args.to_vec();
+            args.push(from);
+            args.push(to);
+            let mut execution_context = ExecutionContext::new(max_call_depth, interrupted);
+            let result = runable.call_wide(&mut execution_context, &args);
+            if result == SCAN_NOT_FOUND {
+                return None;
+            }
+            Some((result as i32, execution_context.result(0)))
+        };
+        let vector_runable = match vector_runable {
+            Some(vector_runable) => vector_runable,
+            None => return Ok(Some(Box::new(scan))),
+        };
+        Ok(Some(Box::new(move |fixed_args, mut from, to| loop {
+            let mut args = fixed_args.to_vec();
+            args.push(from);
+            args.push(to);
+            let block = vector_runable.call_wide(&mut ExecutionContext::new(max_call_depth, interrupted), &args);
+            if block == SCAN_NOT_FOUND {
+                return None;
+            }
+            let block_end = (block + vector_compiler::LANES - 1).min(to as i64) as i32;
+            if let Some(found) = scan(fixed_args, block as i32, block_end) {
+                return Some(found);
+            }
+            if block_end == to {
+                return None;
+            }
+            from = block_end + 1;
+        })))
     }
 
     fn delete(&mut self, name: &str) {
-        self.code_repository.delete(name);
+        self.code_repository().delete(name);
+    }
+
+    fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    fn set_interrupt_flag(&mut self, interrupted: &'static AtomicBool) {
+        self.interrupted = interrupted;
     }
 }
\ No newline at end of file
@@ -1,78 +1,614 @@
 use std::slice;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError, atomic::{AtomicBool, AtomicPtr, Ordering}};
 use std::{collections::HashMap, mem, ops::Deref};
 
 use dynasmrt::x64::Rq;
 use dynasmrt::{Assembler, AssemblyOffset, ExecutableBuffer, Register, x64::{X64Relocation}};
-use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
-use crate::ast::Expr;
+use dynasmrt::{dynasm, DynamicLabel, DynasmApi, DynasmLabelApi};
+use crate::ast::{Expr, SourceSpan};
+use crate::builtins::Builtin;
 use crate::code_repository::CodeRepository;
+use crate::optimizer::Optimizable;
+use crate::error::{Error, Span, Trap};
+
+// Size of the callee-saved registers (rbx, r12 - r15) pushed right below the frame pointer.
+pub const CALLEE_SAVED_SIZE: i32 = 5 * 8;
+// Holds the pointer to the execution context throughout the compiled code.
+const CONTEXT_REGISTER: Rq = Rq::R15;
+// Scratch registers which are not preserved across calls and have to be spilled before calling a function.
+// This is the same set for both calling conventions.
+const CALLER_SAVED_REGISTERS: [Rq; 4] = [Rq::R8, Rq::R9, Rq::R10, Rq::R11];
+
+// The generated code follows the native calling convention of the target (`extern "C"`), which is
+// chosen at build time: win64 on Windows and System V everywhere else. Compiled functions can
+// therefore be called like any other C function. The first parameter of every compiled function is
+// the execution context. Both conventions require rsp to be 16 byte aligned at each call. The red zone
+// of System V is not used, as every function reserves its frame explicitly.
+
+// Registers used for the first parameters. Further parameters are passed on the stack.
+#[cfg(windows)]
+pub const PARAMETER_REGISTERS: &[Rq] = &[Rq::RCX, Rq::RDX, Rq::R8, Rq::R9];
+#[cfg(not(windows))]
+pub const PARAMETER_REGISTERS: &[Rq] = &[Rq::RDI, Rq::RSI, Rq::RDX, Rq::RCX, Rq::R8, Rq::R9];
+// Space the caller has to reserve for the callee right above the return address.
+#[cfg(windows)]
+const SHADOW_SPACE: i32 = 0x20;
+#[cfg(not(windows))]
+const SHADOW_SPACE: i32 = 0;
+
+/// Stack space the caller has to reserve at the bottom of its frame for a call with the given
+/// number of arguments.
+pub fn call_stack_size(argument_count: usize) -> i32 {
+    SHADOW_SPACE + 8 * argument_count.saturating_sub(PARAMETER_REGISTERS.len()) as i32
+}
+
+/// Displacement of a parameter which is passed on the stack, relative to the frame pointer of the callee.
+pub fn stack_parameter_displacement(index: usize) -> i32 {
+    16 + SHADOW_SPACE + 8 * (index - PARAMETER_REGISTERS.len()) as i32
+}
+
+/// Emits a call of `target` with the given arguments according to the native calling convention.
+/// rax is clobbered.
+pub fn emit_call(ops: &mut Assembler<X64Relocation>, target: *const (), arguments: &[i64]) {
+    for (index, argument) in arguments.iter().enumerate() {
+        match PARAMETER_REGISTERS.get(index) {
+            Some(reg) => dynasm!(ops
+                ; .arch x64
+                ; mov Rq(reg.code()), QWORD *argument
+            ),
+            None => dynasm!(ops
+                ; .arch x64
+                ; mov rax, QWORD *argument
+                ; mov QWORD [rsp + call_stack_size(index)], rax
+            ),
+        }
+    }
+    dynasm!(ops
+        ; .arch x64
+        ; mov rax, QWORD target as _
+        ; call rax
+    );
+}
+
+/// Name of the hidden parameter which holds the end of the range scanned by `compile_scan`. It is not
+/// a valid identifier, so it cannot collide with variables.
+pub const SCAN_END: &str = "$end";
+/// Returned by the code of `compile_scan` if the expression does not evaluate to 0 for any value. It
+/// is outside of the i32 range.
+pub const SCAN_NOT_FOUND: i64 = 1 << 32;
+
+/// Handle to an intermediate result of the compiled expression. The value lives either in a scratch
+/// register or, if we ran out of registers, in a stack slot of the current frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Value(usize);
+
+const TRAP_NONE: u64 = 0;
+const TRAP_RECURSION_LIMIT: u64 = 1;
+const TRAP_DIVISION_BY_ZERO: u64 = 2;
+// INT_MIN / -1 and INT_MIN % -1 do not fit into an i32 (and fault on x86).
+const TRAP_DIVISION_OVERFLOW: u64 = 3;
+const TRAP_UNDEFINED_FUNCTION: u64 = 4;
+const TRAP_INTERRUPTED: u64 = 5;
+// The error is stored in the slot of the function
+const TRAP_COMPILATION_FAILED: u64 = 6;
+
+/// State shared by all compiled functions of one evaluation. It is passed to every compiled function
+/// as hidden first argument. If a trap occurs, the trap is recorded here and all active frames return
+/// immediately.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExecutionContext {
+    depth: u64,
+    max_depth: u64,
+    trap: u64,
+    trap_function: *const u8,
+    trap_function_length: u64,
+    // Slot of the function whose call trapped, which holds the error if its compilation failed
+    trap_slot: *const FunctionSlot,
+    // Span of the call in the query whose callee is undefined, if it is known. The end is 0 otherwise.
+    trap_call_start: u64,
+    trap_call_end: u64,
+    // Checked on every function call, so the evaluation can be cancelled
+    interrupted: &'static AtomicBool,
+}
+
+impl ExecutionContext {
+    pub fn new(max_depth: usize, interrupted: &'static AtomicBool) -> ExecutionContext {
+        ExecutionContext {
+            depth: 0,
+            max_depth: max_depth as u64,
+            trap: TRAP_NONE,
+            trap_function: std::ptr::null(),
+            trap_function_length: 0,
+            trap_slot: std::ptr::null(),
+            trap_call_start: 0,
+            trap_call_end: 0,
+            interrupted,
+        }
+    }
+
+    /// Turns the value returned by the compiled code into an error if a trap occurred.
+    pub fn result<T>(&self, value: T) -> Result<T, Error> {
+        let trap = match self.trap {
+            TRAP_NONE => return Ok(value),
+            TRAP_UNDEFINED_FUNCTION => {
+                let span = (self.trap_call_end > 0).then_some(Span { start: self.trap_call_start as usize, end: self.trap_call_end as usize });
+                return Err(Error::UndefinedFunction { name: self.trap_function().to_string(), span });
+            }
+            TRAP_COMPILATION_FAILED => {
+                // Slots are owned by the repository and never removed
+                let error = unsafe { (*self.trap_slot).compile_error() }.expect("The slot of the function holds no error");
+                return Err(Error::Compilation { function: self.trap_function().to_string(), error: Box::new(error) });
+            }
+            TRAP_RECURSION_LIMIT => Trap::RecursionLimit,
+            TRAP_DIVISION_BY_ZERO => Trap::DivisionByZero,
+            TRAP_DIVISION_OVERFLOW => Trap::DivisionOverflow,
+            TRAP_INTERRUPTED => Trap::Interrupted,
+            _ => unreachable!("Unknown trap"),
+        };
+        Err(Error::Trap { trap, function: self.trap_function().to_string() })
+    }
+
+    fn trap_function(&self) -> &str {
+        let function = unsafe { slice::from_raw_parts(self.trap_function, self.trap_function_length as usize) };
+        std::str::from_utf8(function).unwrap()
+    }
+}
+
+/// Entry of the call-slot table. Compiled code calls functions indirectly through the entry of their
+/// slot, which allows to compile, redefine and delete the callee after the caller was compiled. The
+/// callee finds the address of the slot in rax.
+///
+/// Slots are shared with the threads executing compiled code, so they are only modified through atomics
+/// and locks. The entry is published with release ordering. Compiled code reads it with a plain load,
+/// which has acquire semantics on x86.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FunctionSlot {
+    entry: AtomicPtr<u8>,
+    name: *const u8,
+    name_length: u64,
+    // Owns the bytes `name` points to
+    owned_name: String,
+    // Set if compiling the function failed, calls through the slot trap then
+    compile_error: Mutex<Option<Error>>,
+}
+
+impl FunctionSlot {
+    /// Creates the slot of a function which is not defined (yet).
+    pub fn new(name: &str) -> FunctionSlot {
+        let owned_name = name.to_string();
+        FunctionSlot {
+            entry: AtomicPtr::new(slot_trap(TRAP_UNDEFINED_FUNCTION) as *mut u8),
+            name: owned_name.as_ptr(),
+            name_length: owned_name.len() as u64,
+            owned_name,
+            compile_error: Mutex::new(None),
+        }
+    }
+
+    pub fn set_entry(&self, entry: *const u8) {
+        self.entry.store(entry as *mut u8, Ordering::Release);
+        *self.lock_compile_error() = None;
+    }
+
+    /// Calls through the slot trap from now on.
+    pub fn clear(&self) {
+        self.set_entry(slot_trap(TRAP_UNDEFINED_FUNCTION));
+    }
+
+    /// Calls through the slot report the error from now on. The error is stored first, so it is found
+    /// by every call which traps.
+    pub fn set_compile_error(&self, error: Error) {
+        *self.lock_compile_error() = Some(error);
+        self.entry.store(slot_trap(TRAP_COMPILATION_FAILED) as *mut u8, Ordering::Release);
+    }
+
+    pub fn compile_error(&self) -> Option<Error> {
+        self.lock_compile_error().clone()
+    }
+
+    fn lock_compile_error(&self) -> MutexGuard<'_, Option<Error>> {
+        self.compile_error.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.owned_name
+    }
+}
+
+// The name is never modified and the remaining fields are synchronized
+unsafe impl Send for FunctionSlot {}
+unsafe impl Sync for FunctionSlot {}
+
+// Entry of all slots without a function or whose function failed to compile. It records the trap
+// together with the slot and the name stored in it.
+fn slot_trap(trap: u64) -> *const u8 {
+    static UNDEFINED_FUNCTION: OnceLock<ExecutableBuffer> = OnceLock::new();
+    static COMPILATION_FAILED: OnceLock<ExecutableBuffer> = OnceLock::new();
+    let buffer = if trap == TRAP_UNDEFINED_FUNCTION { &UNDEFINED_FUNCTION } else { &COMPILATION_FAILED };
+    buffer.get_or_init(|| {
+        let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+        let context = PARAMETER_REGISTERS[0];
+        dynasm!(ops
+            ; .arch x64
+            ; mov QWORD [Rq(context.code()) + mem::offset_of!(ExecutionContext, trap) as i32], trap as i32
+            ; mov QWORD [Rq(context.code()) + mem::offset_of!(ExecutionContext, trap_slot) as i32], rax
+            ; mov r11, QWORD [rax + mem::offset_of!(FunctionSlot, name) as i32]
+            ; mov QWORD [Rq(context.code()) + mem::offset_of!(ExecutionContext, trap_function) as i32], r11
+            ; mov r11, QWORD [rax + mem::offset_of!(FunctionSlot, name_length) as i32]
+            ; mov QWORD [Rq(context.code()) + mem::offset_of!(ExecutionContext, trap_function_length) as i32], r11
+            ; xor eax, eax
+            ; ret
+        );
+        ops.finalize().unwrap()
+    }).ptr(AssemblyOffset(0))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    Register(Rq),
+    Stack(i32),
+    Freed,
+}
+
 pub struct CompilationContext<'a> {
     ops: Assembler<X64Relocation>,
     available_registers: Vec<Rq>,
-    available_parameter_registers: Vec<Rq>,
-    var: HashMap<String, Rq>,
+    values: Vec<Location>,
+    // Values which currently occupy a register, least recently used first
+    live_values: Vec<Value>,
+    free_slots: Vec<i32>,
+    slot_count: i32,
+    // Stack space needed for arguments of outgoing calls (in addition to the shadow space)
+    outgoing_size: i32,
+    parameter_count: usize,
+    register_parameters: Vec<(Rq, i32)>,
+    // Name of the compiled function. The call depth and interruptions are only checked for functions, not for queries.
+    function_name: Option<String>,
+    // Leaves the function after a trap (the callee has already recorded the trap)
+    return_label: DynamicLabel,
+    // Location of the function name, which is referenced by traps
+    name_label: DynamicLabel,
+    // Maps variables to their location relative to the frame pointer
+    var: HashMap<String, i32>,
     code_repository: &'a CodeRepository,
 }
 
 impl CompilationContext<'_> {
-    pub fn new<'a>(code_repository: &'a CodeRepository) -> CompilationContext<'a> {
+    pub fn new(code_repository: &CodeRepository) -> CompilationContext<'_> {
+        let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+        let return_label = ops.new_dynamic_label();
+        let name_label = ops.new_dynamic_label();
         CompilationContext {
-            ops: dynasmrt::x64::Assembler::new().unwrap(),
-            available_registers: vec![Rq::RBX, Rq::R8, Rq::R9, Rq::R10, Rq::R11, Rq::R12, Rq::R13, Rq::R14, Rq::R15],
-            available_parameter_registers: vec![Rq::RCX],
+            ops,
+            available_registers: vec![Rq::RBX, Rq::R8, Rq::R9, Rq::R10, Rq::R11, Rq::R12, Rq::R13, Rq::R14],
+            values: Vec::new(),
+            live_values: Vec::new(),
+            free_slots: Vec::new(),
+            slot_count: 0,
+            outgoing_size: 0,
+            parameter_count: 0,
+            register_parameters: Vec::new(),
+            function_name: None,
+            return_label,
+            name_label,
             var: HashMap::new(),
-            code_repository: code_repository,
+            code_repository,
+        }
+    }
+
+    /// Compiles the code as function with the given name, which enforces the maximum call depth.
+    pub fn set_function_name(&mut self, name: &str) {
+        self.function_name = Some(name.to_string());
+    }
+
+    pub fn add_parameter(&mut self, var: String) {
+        // The execution context is passed as first parameter
+        let index = self.parameter_count + 1;
+        self.parameter_count += 1;
+        let displacement = match PARAMETER_REGISTERS.get(index) {
+            // Register parameters are stored in their own stack slot in the prologue, so the parameter
+            // register can be clobbered by function calls.
+            Some(reg) => {
+                let displacement = slot_displacement(self.next_slot());
+                self.register_parameters.push((*reg, displacement));
+                displacement
+            }
+            // Stack parameters are located above the return address and the shadow space.
+            None => stack_parameter_displacement(index),
+        };
+        self.var.insert(var, displacement);
+    }
+
+    fn next_slot(&mut self) -> i32 {
+        self.free_slots.pop().unwrap_or_else(|| {
+            self.slot_count += 1;
+            self.slot_count - 1
+        })
+    }
+
+    fn next_register(&mut self, keep: &[Rq]) -> Result<Rq, Error> {
+        if let Some(reg) = self.available_registers.pop() {
+            return Ok(reg);
+        }
+        let victim = self.live_values.iter()
+            .copied()
+            .find(|value| !keep.contains(&self.register_of(*value)))
+            .ok_or(Error::RegisterExhaustion)?;
+        self.spill(victim);
+        Ok(self.available_registers.pop().unwrap())
+    }
+
+    fn register_of(&self, value: Value) -> Rq {
+        match self.values[value.0] {
+            Location::Register(reg) => reg,
+            _ => unreachable!("Value is not located in a register"),
         }
     }
 
-    fn next_register(&mut self) -> Result<Rq, String> {
-        self.available_registers.pop().ok_or("No more registers available!".to_string())
+    fn new_value(&mut self, keep: &[Rq]) -> Result<(Value, Rq), Error> {
+        let reg = self.next_register(keep)?;
+        let value = Value(self.values.len());
+        self.values.push(Location::Register(reg));
+        self.live_values.push(value);
+        Ok((value, reg))
     }
 
-    pub fn assign_register_to_variable(&mut self, var: String) -> Result<Rq, String> {
-        let result= self.available_parameter_registers.pop()
-            .ok_or("No more parameter registers available!".to_string());
-        if let Ok(reg) = result {
-            self.var.insert(var, reg);
+    /// Makes sure that the value is located in a register and returns it. Registers in `keep` are not
+    /// spilled in order to make room for the value.
+    fn load(&mut self, value: Value, keep: &[Rq]) -> Result<Rq, Error> {
+        match self.values[value.0] {
+            Location::Register(reg) => {
+                self.live_values.retain(|v| *v != value);
+                self.live_values.push(value);
+                Ok(reg)
+            }
+            Location::Stack(slot) => {
+                let reg = self.next_register(keep)?;
+                dynasm!(self.ops
+                    ; mov Rq(reg.code()), QWORD [rbp + slot_displacement(slot)]
+                );
+                self.free_slots.push(slot);
+                self.values[value.0] = Location::Register(reg);
+                self.live_values.push(value);
+                Ok(reg)
+            }
+            Location::Freed => unreachable!("Value was already freed"),
         }
-        result
     }
 
-    fn free_if_possible(&mut self, reg: Rq) {
-        if ![Rq::RAX, Rq::RCX, Rq::RDX].contains(&reg) {
-            self.available_parameter_registers.push(reg);
+    /// Copies the value to the register without changing the location of the value.
+    fn copy_to(&mut self, reg: Rq, value: Value) {
+        match self.values[value.0] {
+            Location::Register(source) => dynasm!(self.ops
+                ; mov Rq(reg.code()), Rq(source.code())
+            ),
+            Location::Stack(slot) => dynasm!(self.ops
+                ; mov Rq(reg.code()), QWORD [rbp + slot_displacement(slot)]
+            ),
+            Location::Freed => unreachable!("Value was already freed"),
         }
     }
 
-    pub fn compile(mut self, expr: &Expr) -> Result<Runable, String> {
+    fn spill(&mut self, value: Value) {
+        let reg = self.register_of(value);
+        let slot = self.next_slot();
+        dynasm!(self.ops
+            ; mov QWORD [rbp + slot_displacement(slot)], Rq(reg.code())
+        );
+        self.values[value.0] = Location::Stack(slot);
+        self.live_values.retain(|v| *v != value);
+        self.available_registers.push(reg);
+    }
+
+    fn spill_caller_saved_registers(&mut self) {
+        self.spill_registers(&CALLER_SAVED_REGISTERS);
+    }
+
+    fn spill_all_registers(&mut self) {
+        for value in self.live_values.clone() {
+            self.spill(value);
+        }
+    }
+
+    fn spill_registers(&mut self, registers: &[Rq]) {
+        let to_spill = self.live_values.iter()
+            .copied()
+            .filter(|value| registers.contains(&self.register_of(*value)))
+            .collect::<Vec<_>>();
+        for value in to_spill {
+            self.spill(value);
+        }
+    }
+
+    fn stack_value(&mut self, slot: i32) -> Value {
+        self.values.push(Location::Stack(slot));
+        Value(self.values.len() - 1)
+    }
+
+    fn free(&mut self, value: Value) {
+        match self.values[value.0] {
+            Location::Register(reg) => {
+                self.live_values.retain(|v| *v != value);
+                self.available_registers.push(reg);
+            }
+            Location::Stack(slot) => self.free_slots.push(slot),
+            Location::Freed => unreachable!("Value was already freed"),
+        }
+        self.values[value.0] = Location::Freed;
+    }
+
+    /// Records the trap in the execution context. Afterwards, the function has to be left.
+    fn emit_trap(&mut self, trap: u64) {
+        let name_label = self.name_label;
+        let name_length = self.function_name.as_ref().map(String::len).unwrap_or(0);
+        dynasm!(self.ops
+            ; mov QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, trap) as i32], trap as i32
+            ; lea rax, [=>name_label]
+            ; mov QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, trap_function) as i32], rax
+            ; mov QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, trap_function_length) as i32], name_length as i32
+        );
+    }
+
+    fn frame_size(&self) -> i32 {
+        // rsp must be 16 byte aligned at each call. The return address and the pushed registers take
+        // 8 + 8 + CALLEE_SAVED_SIZE bytes.
+        let size = self.slot_count * 8 + SHADOW_SPACE + self.outgoing_size + CALLEE_SAVED_SIZE;
+        (size + 15) / 16 * 16 - CALLEE_SAVED_SIZE
+    }
+
+    pub fn compile(self, expr: &Expr) -> Result<Runable, Error> {
+        self.compile_with(|ctx| {
+            let result = expr.optimize().compile(ctx)?;
+            let result_register = ctx.load(result, &[])?;
+            dynasm!(ctx.ops
+                ; mov rax, Rq(result_register.code())
+            );
+            Ok(())
+        })
+    }
+
+    /// Compiles a loop which evaluates the expression for all values of the loop variable from its
+    /// initial value up to an end value (inclusive), which is passed as an additional parameter. The
+    /// loop variable has to be the last parameter. The compiled code returns the first value for which
+    /// the expression evaluates to 0 or a trap occurs, or `SCAN_NOT_FOUND`.
+    pub fn compile_scan(mut self, expr: &Expr, loop_var: &str) -> Result<Runable, Error> {
+        let loop_var = *self.var.get(loop_var).ok_or_else(|| Error::UndefinedVariable { name: loop_var.to_string(), function: None, span: None })?;
+        self.add_parameter(SCAN_END.to_string());
+        let end = self.var[SCAN_END];
+        let expr = expr.optimize();
+        self.compile_with(|ctx| {
+            // Traps leave the loop with the current value of the loop variable
+            let return_label = ctx.return_label;
+            let trap_label = ctx.ops.new_dynamic_label();
+            ctx.return_label = trap_label;
+            let loop_label = ctx.ops.new_dynamic_label();
+            let found_label = ctx.ops.new_dynamic_label();
+            let not_found_label = ctx.ops.new_dynamic_label();
+            let end_label = ctx.ops.new_dynamic_label();
+            dynasm!(ctx.ops
+                ; =>loop_label
+            );
+            // No values are live at the start of an iteration, so the register allocation is the same
+            // for every iteration.
+            let result = expr.compile(ctx)?;
+            let result_register = ctx.load(result, &[])?;
+            ctx.free(result);
+            dynasm!(ctx.ops
+                ; test Rd(result_register.code()), Rd(result_register.code())
+                ; jz =>found_label
+                ; mov eax, DWORD [rbp + loop_var]
+                ; cmp eax, DWORD [rbp + end]
+                ; je =>not_found_label
+                ; inc DWORD [rbp + loop_var]
+                ; jmp =>loop_label
+                ; =>not_found_label
+                ; mov rax, QWORD SCAN_NOT_FOUND
+                ; jmp =>end_label
+                ; =>trap_label
+                ; =>found_label
+                ; movsxd rax, DWORD [rbp + loop_var]
+                ; =>end_label
+            );
+            ctx.return_label = return_label;
+            Ok(())
+        })
+    }
+
+    /// Emits the prologue and the epilogue of the function around the code generated by `body`, which
+    /// has to leave the return value in rax.
+    fn compile_with(mut self, body: impl FnOnce(&mut Self) -> Result<(), Error>) -> Result<Runable, Error> {
         println!("JIT> Compiler called. Starting assembly ...");
         let offset = self.ops.offset();
         dynasm!(self.ops
             ; .arch x64
+            ; push rbp
+            ; mov rbp, rsp
             ; push rbx
             ; push r12
             ; push r13
             ; push r14
             ; push r15
         );
-        let result_register = expr.compile(&mut self)?;
+        // The frame size is only known after the body has been compiled and is patched afterwards.
+        let frame_offset = self.ops.offset();
+        dynasm!(self.ops
+            ; sub rsp, DWORD 0
+            ; mov Rq(CONTEXT_REGISTER.code()), Rq(PARAMETER_REGISTERS[0].code())
+        );
+        for (reg, displacement) in self.register_parameters.clone() {
+            dynasm!(self.ops
+                ; mov QWORD [rbp + displacement], Rq(reg.code())
+            );
+        }
+        let exit_label = self.ops.new_dynamic_label();
+        if self.function_name.is_some() {
+            let ok_label = self.ops.new_dynamic_label();
+            dynasm!(self.ops
+                ; mov rax, QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, depth) as i32]
+                ; cmp rax, QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, max_depth) as i32]
+                ; jb =>ok_label
+            );
+            self.emit_trap(TRAP_RECURSION_LIMIT);
+            let run_label = self.ops.new_dynamic_label();
+            dynasm!(self.ops
+                ; jmp =>exit_label
+                ; =>ok_label
+                ; mov rax, QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, interrupted) as i32]
+                ; cmp BYTE [rax], 0
+                ; je =>run_label
+            );
+            self.emit_trap(TRAP_INTERRUPTED);
+            dynasm!(self.ops
+                ; jmp =>exit_label
+                ; =>run_label
+                ; inc QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, depth) as i32]
+            );
+        }
+        body(&mut self)?;
+        dynasm!(self.ops
+            ; =>self.return_label
+        );
+        if self.function_name.is_some() {
+            dynasm!(self.ops
+                ; dec QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, depth) as i32]
+            );
+        }
         dynasm!(self.ops
-            ; mov rax, Rq(result_register.code())
+            ; =>exit_label
+            ; lea rsp, [rbp - CALLEE_SAVED_SIZE]
             ; pop r15
             ; pop r14
             ; pop r13
             ; pop r12
             ; pop rbx
+            ; pop rbp
             ; ret
+            ; =>self.name_label
+            ; .bytes self.function_name.clone().unwrap_or_default().as_bytes()
         );
+
+        let frame_size = self.frame_size();
+        let mut modifier = self.ops.alter_uncommitted();
+        modifier.goto(frame_offset);
+        dynasm!(modifier
+            ; .arch x64
+            ; sub rsp, DWORD frame_size
+        );
+
         let buf = self.ops.finalize().unwrap();
-        
+
         println!("JIT> Compilation finished. Code has size {} @{:p}.", buf.len(), buf.ptr(offset));
 
         Ok(Runable::new(buf, offset))
     }
 }
 
+fn slot_displacement(slot: i32) -> i32 {
+    -CALLEE_SAVED_SIZE - 8 * (slot + 1)
+}
+
 #[derive(Debug)]
 pub struct Runable {
     buf: ExecutableBuffer,
@@ -84,15 +620,30 @@ impl Runable {
         Runable {buf, offset}
     }
 
-    pub fn call(&self, arg1: i32) -> i32 {
-        let expr_fn: extern "win64" fn(i32) -> i32 = unsafe { mem::transmute(self.buf.ptr(self.offset)) };
-        let res = expr_fn(arg1);
-        res
+    /// Entry point of the code. It can be called as `extern "C"` function which takes the execution
+    /// context followed by the parameters.
+    pub fn ptr(&self) -> *const u8 {
+        self.buf.ptr(self.offset)
+    }
+
+    /// Calls the compiled code. The caller has to check the execution context for traps afterwards.
+    pub fn call(&self, ctx: &mut ExecutionContext, args: &[i32]) -> i32 {
+        self.call_wide(ctx, args) as i32
+    }
+
+    /// Like `call`, but returns the whole rax register, which is used by the code of `compile_scan`.
+    pub fn call_wide(&self, ctx: &mut ExecutionContext, args: &[i32]) -> i64 {
+        let trampoline: extern "C" fn(*const u8, *mut ExecutionContext, *const i32, u64) -> i64 = unsafe { mem::transmute(trampoline().ptr(AssemblyOffset(0))) };
+        trampoline(self.ptr(), ctx, args.as_ptr(), args.len() as u64)
+    }
+
+    pub fn size(&self) -> usize {
+        self.buf.len()
     }
 
     pub fn print(&self) {
         println!("Code (size: {}):", self.buf.len());
-        
+
         for byte in self.buf.deref() {
             print!("{:02x}", byte);
         }
@@ -100,46 +651,151 @@ impl Runable {
     }
 }
 
+// Rust cannot call a function with a number of arguments only known at runtime. The trampoline takes
+// the compiled function, the execution context and a slice of arguments and performs the call
+// according to the native calling convention.
+fn trampoline() -> &'static ExecutableBuffer {
+    static TRAMPOLINE: OnceLock<ExecutableBuffer> = OnceLock::new();
+    TRAMPOLINE.get_or_init(|| {
+        let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+        // The parameters of the trampoline are moved to callee-saved registers, as the parameter
+        // registers are needed for the call.
+        dynasm!(ops
+            ; .arch x64
+            ; push rbp
+            ; mov rbp, rsp
+            ; push rbx
+            ; push r12
+            ; push r13
+            ; push r14
+            ; mov rbx, Rq(PARAMETER_REGISTERS[0].code())
+            ; mov r12, Rq(PARAMETER_REGISTERS[1].code())
+            ; mov r13, Rq(PARAMETER_REGISTERS[2].code())
+            ; mov r14, Rq(PARAMETER_REGISTERS[3].code())
+            // Reserve 8 bytes per argument plus the shadow space and keep rsp 16 byte aligned.
+            ; lea rax, [r14 * 8 + SHADOW_SPACE + 15]
+            ; and rax, -16
+            ; sub rsp, rax
+            // Argument i is passed at [rsp + call_stack_size(i + 1) - 8] if it does not fit into a register.
+            ; mov rcx, PARAMETER_REGISTERS.len() as i32 - 1
+            ; ->copy_stack_args:
+            ; cmp rcx, r14
+            ; jae ->copy_register_args
+            ; movsxd rax, DWORD [r13 + rcx * 4]
+            ; mov [rsp + rcx * 8 + SHADOW_SPACE - 8 * (PARAMETER_REGISTERS.len() as i32 - 1)], rax
+            ; inc rcx
+            ; jmp ->copy_stack_args
+            ; ->copy_register_args:
+        );
+        for (i, reg) in PARAMETER_REGISTERS.iter().enumerate().skip(1) {
+            dynasm!(ops
+                ; .arch x64
+                ; cmp r14, i as i32
+                ; jb ->call
+                ; mov Rd(reg.code()), DWORD [r13 + 4 * (i as i32 - 1)]
+            );
+        }
+        dynasm!(ops
+            ; .arch x64
+            ; ->call:
+            ; mov Rq(PARAMETER_REGISTERS[0].code()), r12
+            ; call rbx
+            ; lea rsp, [rbp - 32]
+            ; pop r14
+            ; pop r13
+            ; pop r12
+            ; pop rbx
+            ; pop rbp
+            ; ret
+        );
+        ops.finalize().unwrap()
+    })
+}
 
 pub trait Compilable {
-    fn compile(&self, ctx: &mut CompilationContext) -> Result<Rq, String>;
+    fn compile(&self, ctx: &mut CompilationContext) -> Result<Value, Error>;
 }
 
 impl Compilable for Expr {
-    fn compile(&self, mut ctx: &mut CompilationContext) -> Result<Rq, String> {
+    fn compile(&self, ctx: &mut CompilationContext) -> Result<Value, Error> {
         match self {
-            Expr::Number(number) => compile_number(*number, &mut ctx),
-            Expr::Var(var) => compile_var(&var, &mut ctx),
-            Expr::Add(lhs, rhs) => compile_add(&lhs, &rhs, &mut ctx),
-            Expr::Sub(lhs, rhs) => compile_sub(&lhs, &rhs, &mut ctx),
-            Expr::Mul(lhs, rhs) => compile_mul(&lhs, &rhs, &mut ctx),
-            Expr::Div(lhs, rhs) => compile_div(&lhs, &rhs, &mut ctx),
-            Expr::Rem(lhs, rhs) => compile_rem(&lhs, &rhs, &mut ctx),
-            Expr::Eq(lhs, rhs) => compile_eq(&lhs, &rhs, &mut ctx),
-            Expr::Neq(lhs, rhs) => compile_neq(&lhs, &rhs, &mut ctx),
-            Expr::Gt(lhs, rhs) => compile_gt(&lhs, &rhs, &mut ctx),
-            Expr::Lt(lhs, rhs) => compile_lt(&lhs, &rhs, &mut ctx),
-            Expr::Gte(lhs, rhs) => compile_gte(&lhs, &rhs, &mut ctx),
-            Expr::Lte(lhs, rhs) => compile_lte(&lhs, &rhs, &mut ctx),
-            Expr::FunctionCall(name, param) => compile_function_call(&name, &param, &mut ctx),
+            Expr::Number(number) => compile_number(*number, ctx),
+            Expr::Var(var, source) => compile_var(var, *source, ctx),
+            Expr::Neg(operand) => compile_neg(operand, ctx),
+            Expr::Add(lhs, rhs) => compile_add(lhs, rhs, ctx),
+            Expr::Sub(lhs, rhs) => compile_sub(lhs, rhs, ctx),
+            Expr::Mul(lhs, rhs) => compile_mul(lhs, rhs, ctx),
+            Expr::Div(lhs, rhs) => compile_div(lhs, rhs, ctx),
+            Expr::Rem(lhs, rhs) => compile_rem(lhs, rhs, ctx),
+            Expr::BitAnd(lhs, rhs) => compile_bit_and(lhs, rhs, ctx),
+            Expr::BitOr(lhs, rhs) => compile_bit_or(lhs, rhs, ctx),
+            Expr::BitXor(lhs, rhs) => compile_bit_xor(lhs, rhs, ctx),
+            Expr::Shl(lhs, rhs) => compile_shl(lhs, rhs, ctx),
+            Expr::Sar(lhs, rhs) => compile_sar(lhs, rhs, ctx),
+            Expr::Shr(lhs, rhs) => compile_shr(lhs, rhs, ctx),
+            Expr::Eq(lhs, rhs) => compile_eq(lhs, rhs, ctx),
+            Expr::Neq(lhs, rhs) => compile_neq(lhs, rhs, ctx),
+            Expr::Gt(lhs, rhs) => compile_gt(lhs, rhs, ctx),
+            Expr::Lt(lhs, rhs) => compile_lt(lhs, rhs, ctx),
+            Expr::Gte(lhs, rhs) => compile_gte(lhs, rhs, ctx),
+            Expr::Lte(lhs, rhs) => compile_lte(lhs, rhs, ctx),
+            Expr::FunctionCall(name, args, source) => compile_function_call(name, args, *source, ctx),
+            Expr::BuiltinCall(builtin, args, source) => compile_builtin_call(*builtin, args, *source, ctx),
+            Expr::Not(operand) => compile_not(operand, ctx),
+            Expr::BitNot(operand) => compile_bit_not(operand, ctx),
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) | Expr::Implies(lhs, rhs) => compile_short_circuit(lhs, rhs, self.short_circuit().unwrap(), ctx),
+            Expr::If(condition, then_expr, else_expr) => compile_if(condition, then_expr, else_expr, ctx),
         }
     }
 }
 
-fn compile_number(number: i32, ctx: &mut CompilationContext) -> Result<Rq, String> {
-    let register = ctx.next_register()?;
+fn compile_number(number: i32, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    let (value, register) = ctx.new_value(&[])?;
     dynasm!(ctx.ops
         ; mov Rq(register.code()), QWORD number as _
     );
-    Ok(register)
+    Ok(value)
 }
 
-fn compile_var(name: &String, ctx: &mut CompilationContext) -> Result<Rq, String> {
-    let register = ctx.var.get(name).ok_or("Variable was not defined".to_string()).map(|rq| *rq)?;
-    Ok(register)
+fn compile_var(name: &str, source: SourceSpan, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    let displacement = *ctx.var.get(name).ok_or_else(|| Error::UndefinedVariable { name: name.to_string(), function: ctx.function_name.clone(), span: source.0 })?;
+    let (value, register) = ctx.new_value(&[])?;
+    dynasm!(ctx.ops
+        ; movsxd Rq(register.code()), DWORD [rbp + displacement]
+    );
+    Ok(value)
 }
 
-fn compile_add(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_neg(operand: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    compile_unary(operand, ctx, |operand_reg, new_reg, ctx| {
+        dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(operand_reg.code())
+            ; neg Rd(new_reg.code())
+        );
+    })
+}
+
+fn compile_not(operand: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    compile_unary(operand, ctx, |operand_reg, new_reg, ctx| {
+        dynasm!(ctx.ops
+            ; mov eax, 0
+            ; test Rd(operand_reg.code()), Rd(operand_reg.code())
+            ; sete al
+            ; mov Rd(new_reg.code()), eax
+        );
+    })
+}
+
+fn compile_bit_not(operand: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    compile_unary(operand, ctx, |operand_reg, new_reg, ctx| {
+        dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(operand_reg.code())
+            ; not Rd(new_reg.code())
+        );
+    })
+}
+
+fn compile_add(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
         dynasm!(ctx.ops
             ; mov Rq(new_reg.code()), Rq(lhs_reg.code())
@@ -148,7 +804,7 @@ fn compile_add(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<R
     })
 }
 
-fn compile_sub(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_sub(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
         dynasm!(ctx.ops
             ; mov Rq(new_reg.code()), Rq(lhs_reg.code())
@@ -157,7 +813,11 @@ fn compile_sub(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<R
     })
 }
 
-fn compile_mul(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_mul(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    // The optimizer moves constant factors to the right
+    if let Expr::Number(factor) = *rhs {
+        return compile_with_constant(lhs, ctx, |lhs_reg, new_reg, ctx| emit_mul_by_constant(lhs_reg, factor, new_reg, ctx));
+    }
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
         dynasm!(ctx.ops
             ; mov eax, Rd(lhs_reg.code())
@@ -167,8 +827,18 @@ fn compile_mul(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<R
     })
 }
 
-fn compile_div(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_div(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    // Division by 0 and -1 can fail and is left to idiv
+    if let Expr::Number(divisor @ (i32::MIN..=-2 | 1..=i32::MAX)) = *rhs {
+        return compile_with_constant(lhs, ctx, |lhs_reg, new_reg, ctx| {
+            emit_div_by_constant(lhs_reg, divisor, ctx);
+            dynasm!(ctx.ops
+                ; mov Rd(new_reg.code()), eax
+            );
+        });
+    }
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
+        check_division(lhs_reg, rhs_reg, ctx);
         dynasm!(ctx.ops
             ; mov eax, Rd(lhs_reg.code())
             ; cdq
@@ -178,8 +848,31 @@ fn compile_div(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<R
     })
 }
 
-fn compile_rem(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_rem(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    if let Expr::Number(divisor @ (i32::MIN..=-2 | 1..=i32::MAX)) = *rhs {
+        return compile_with_constant(lhs, ctx, |lhs_reg, new_reg, ctx| {
+            // The remainder only depends on the absolute value of the divisor: x % d = x - x / |d| * |d|
+            // For d = INT_MIN, |d| = 2^31 does not fit into an i32 and the quotient by INT_MIN is
+            // used instead. This only changes the sign of q, which does not matter for q * 2^31.
+            let divisor = divisor.unsigned_abs();
+            emit_div_by_constant(lhs_reg, divisor as i32, ctx);
+            if divisor.is_power_of_two() {
+                dynasm!(ctx.ops
+                    ; shl eax, divisor.trailing_zeros() as i8
+                );
+            } else {
+                dynasm!(ctx.ops
+                    ; imul eax, eax, divisor as i32
+                );
+            }
+            dynasm!(ctx.ops
+                ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+                ; sub Rd(new_reg.code()), eax
+            );
+        });
+    }
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
+        check_division(lhs_reg, rhs_reg, ctx);
         dynasm!(ctx.ops
             ; mov eax, Rd(lhs_reg.code())
             ; cdq
@@ -189,7 +882,220 @@ fn compile_rem(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<R
     })
 }
 
-fn compile_eq(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn emit_mul_by_constant(lhs_reg: Rq, factor: i32, new_reg: Rq, ctx: &mut CompilationContext) {
+    match factor {
+        -1 => dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; neg Rd(new_reg.code())
+        ),
+        3 => dynasm!(ctx.ops
+            ; lea Rd(new_reg.code()), [Rq(lhs_reg.code()) + Rq(lhs_reg.code()) * 2]
+        ),
+        5 => dynasm!(ctx.ops
+            ; lea Rd(new_reg.code()), [Rq(lhs_reg.code()) + Rq(lhs_reg.code()) * 4]
+        ),
+        9 => dynasm!(ctx.ops
+            ; lea Rd(new_reg.code()), [Rq(lhs_reg.code()) + Rq(lhs_reg.code()) * 8]
+        ),
+        factor if factor > 0 && (factor as u32).is_power_of_two() => dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; shl Rd(new_reg.code()), factor.trailing_zeros() as i8
+        ),
+        factor => dynasm!(ctx.ops
+            ; imul Rd(new_reg.code()), Rd(lhs_reg.code()), factor
+        ),
+    }
+}
+
+/// Computes the quotient of the register and the constant in eax, using shifts or a multiplication
+/// with a magic number instead of idiv. The divisor must neither be 0 nor -1. edx is clobbered.
+fn emit_div_by_constant(lhs_reg: Rq, divisor: i32, ctx: &mut CompilationContext) {
+    let abs_divisor = divisor.unsigned_abs();
+    if abs_divisor.is_power_of_two() {
+        // Negative numbers are rounded towards zero by adding |d| - 1 before shifting
+        let shift = abs_divisor.trailing_zeros() as i8;
+        dynasm!(ctx.ops
+            ; mov eax, Rd(lhs_reg.code())
+        );
+        if shift > 0 {
+            dynasm!(ctx.ops
+                ; sar eax, 31
+                ; shr eax, 32 - shift
+                ; add eax, Rd(lhs_reg.code())
+                ; sar eax, shift
+            );
+        }
+        if divisor < 0 {
+            dynasm!(ctx.ops
+                ; neg eax
+            );
+        }
+        return;
+    }
+    // See Hacker's Delight, chapter 10: q = (mulhs(M, x) (+/- x)) >> s, rounded towards zero
+    let (magic, shift) = signed_magic(divisor);
+    dynasm!(ctx.ops
+        ; movsxd rax, Rd(lhs_reg.code())
+        ; imul rax, rax, magic
+        ; sar rax, 32
+    );
+    if divisor > 0 && magic < 0 {
+        dynasm!(ctx.ops
+            ; add eax, Rd(lhs_reg.code())
+        );
+    } else if divisor < 0 && magic > 0 {
+        dynasm!(ctx.ops
+            ; sub eax, Rd(lhs_reg.code())
+        );
+    }
+    if shift > 0 {
+        dynasm!(ctx.ops
+            ; sar eax, shift as i8
+        );
+    }
+    dynasm!(ctx.ops
+        ; mov edx, eax
+        ; shr edx, 31
+        ; add eax, edx
+    );
+}
+
+/// Computes the magic number and the shift amount for the signed division by the constant, which
+/// must not be -1, 0 or 1 (Hacker's Delight, figure 10-1).
+pub fn signed_magic(divisor: i32) -> (i32, u32) {
+    const TWO_31: u32 = 0x8000_0000;
+    let abs_divisor = divisor.unsigned_abs();
+    let t = TWO_31 + ((divisor as u32) >> 31);
+    // Absolute value of nc
+    let anc = t - 1 - t % abs_divisor;
+    let mut p = 31;
+    let (mut q1, mut r1) = (TWO_31 / anc, TWO_31 % anc);
+    let (mut q2, mut r2) = (TWO_31 / abs_divisor, TWO_31 % abs_divisor);
+    loop {
+        p += 1;
+        q1 = q1.wrapping_mul(2);
+        r1 = r1.wrapping_mul(2);
+        if r1 >= anc {
+            q1 = q1.wrapping_add(1);
+            r1 = r1.wrapping_sub(anc);
+        }
+        q2 = q2.wrapping_mul(2);
+        r2 = r2.wrapping_mul(2);
+        if r2 >= abs_divisor {
+            q2 = q2.wrapping_add(1);
+            r2 = r2.wrapping_sub(abs_divisor);
+        }
+        let delta = abs_divisor - r2;
+        if !(q1 < delta || (q1 == delta && r1 == 0)) {
+            break;
+        }
+    }
+    let magic = q2.wrapping_add(1) as i32;
+    (if divisor < 0 { magic.wrapping_neg() } else { magic }, p - 32)
+}
+
+/// Traps instead of executing an `idiv` which would raise a hardware exception.
+fn check_division(lhs_reg: Rq, rhs_reg: Rq, ctx: &mut CompilationContext) {
+    let zero_label = ctx.ops.new_dynamic_label();
+    let ok_label = ctx.ops.new_dynamic_label();
+    dynasm!(ctx.ops
+        ; test Rd(rhs_reg.code()), Rd(rhs_reg.code())
+        ; jz =>zero_label
+        ; cmp Rd(rhs_reg.code()), -1
+        ; jne =>ok_label
+        ; cmp Rd(lhs_reg.code()), i32::MIN
+        ; jne =>ok_label
+    );
+    ctx.emit_trap(TRAP_DIVISION_OVERFLOW);
+    dynasm!(ctx.ops
+        ; jmp =>ctx.return_label
+        ; =>zero_label
+    );
+    ctx.emit_trap(TRAP_DIVISION_BY_ZERO);
+    dynasm!(ctx.ops
+        ; jmp =>ctx.return_label
+        ; =>ok_label
+    );
+}
+
+fn compile_bit_and(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
+        dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; and Rd(new_reg.code()), Rd(rhs_reg.code())
+        );
+    })
+}
+
+fn compile_bit_or(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
+        dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; or Rd(new_reg.code()), Rd(rhs_reg.code())
+        );
+    })
+}
+
+fn compile_bit_xor(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
+        dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; xor Rd(new_reg.code()), Rd(rhs_reg.code())
+        );
+    })
+}
+
+// The shift instructions use only the lowest 5 bits of the shift amount, which is the defined behaviour
+// of the shift operators. The amount has to be in cl unless it is a constant.
+fn compile_shl(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    if let Expr::Number(amount) = *rhs {
+        return compile_with_constant(lhs, ctx, |lhs_reg, new_reg, ctx| dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; shl Rd(new_reg.code()), (amount & 31) as i8
+        ));
+    }
+    compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
+        dynasm!(ctx.ops
+            ; mov ecx, Rd(rhs_reg.code())
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; shl Rd(new_reg.code()), cl
+        );
+    })
+}
+
+fn compile_sar(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    if let Expr::Number(amount) = *rhs {
+        return compile_with_constant(lhs, ctx, |lhs_reg, new_reg, ctx| dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; sar Rd(new_reg.code()), (amount & 31) as i8
+        ));
+    }
+    compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
+        dynasm!(ctx.ops
+            ; mov ecx, Rd(rhs_reg.code())
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; sar Rd(new_reg.code()), cl
+        );
+    })
+}
+
+fn compile_shr(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    if let Expr::Number(amount) = *rhs {
+        return compile_with_constant(lhs, ctx, |lhs_reg, new_reg, ctx| dynasm!(ctx.ops
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; shr Rd(new_reg.code()), (amount & 31) as i8
+        ));
+    }
+    compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
+        dynasm!(ctx.ops
+            ; mov ecx, Rd(rhs_reg.code())
+            ; mov Rd(new_reg.code()), Rd(lhs_reg.code())
+            ; shr Rd(new_reg.code()), cl
+        );
+    })
+}
+
+fn compile_eq(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
         dynasm!(ctx.ops
             ; mov eax, 0
@@ -200,7 +1106,7 @@ fn compile_eq(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq
     })
 }
 
-fn compile_neq(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_neq(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
         dynasm!(ctx.ops
         ; mov eax, 0
@@ -211,7 +1117,7 @@ fn compile_neq(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<R
     })
 }
 
-fn compile_gt(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_gt(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
         dynasm!(ctx.ops
             ; mov eax, 0
@@ -222,7 +1128,7 @@ fn compile_gt(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq
     })
 }
 
-fn compile_lt(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_lt(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
         dynasm!(ctx.ops
             ; mov eax, 0
@@ -233,7 +1139,7 @@ fn compile_lt(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq
     })
 }
 
-fn compile_gte(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_gte(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
         dynasm!(ctx.ops
             ; mov eax, 0
@@ -244,7 +1150,7 @@ fn compile_gte(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<R
     })
 }
 
-fn compile_lte(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Rq, String> {
+fn compile_lte(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
     compile_op(lhs, rhs, ctx, |lhs_reg, rhs_reg, new_reg, ctx| {
         dynasm!(ctx.ops
             ; mov eax, 0
@@ -255,65 +1161,263 @@ fn compile_lte(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext) -> Result<R
     })
 }
 
-fn compile_op(lhs: &Expr, rhs: &Expr, mut ctx: &mut CompilationContext, gen: fn(Rq, Rq, Rq, &mut CompilationContext) -> ()) -> Result<Rq, String> {
-    let lhs_reg = lhs.compile(&mut ctx)?;
-    let rhs_reg = rhs.compile(&mut ctx)?;
-    let new_reg = ctx.next_register()?;
+fn compile_op(lhs: &Expr, rhs: &Expr, ctx: &mut CompilationContext, gen: fn(Rq, Rq, Rq, &mut CompilationContext)) -> Result<Value, Error> {
+    let lhs = lhs.compile(ctx)?;
+    let rhs = rhs.compile(ctx)?;
+    let lhs_reg = ctx.load(lhs, &[])?;
+    let rhs_reg = ctx.load(rhs, &[lhs_reg])?;
+    let (new_value, new_reg) = ctx.new_value(&[lhs_reg, rhs_reg])?;
     gen(lhs_reg, rhs_reg, new_reg, ctx);
-    ctx.free_if_possible(lhs_reg);
-    ctx.free_if_possible(rhs_reg);
-    Ok(new_reg)
+    ctx.free(lhs);
+    ctx.free(rhs);
+    Ok(new_value)
 }
 
-fn compile_function_call(name: &String, param: &Option<Box<Expr>>, mut ctx: &mut CompilationContext) -> Result<Rq, String> {
-    let arg = param.as_ref().map(|e| e.compile(&mut ctx));
-    let new_reg = ctx.next_register()?;
-    let code_repo_ptr = ctx.code_repository as *const CodeRepository;
-    let code_label = ctx.ops.new_dynamic_label();
-    let fn_name_label = ctx.ops.new_dynamic_label();
+fn compile_unary(operand: &Expr, ctx: &mut CompilationContext, gen: fn(Rq, Rq, &mut CompilationContext)) -> Result<Value, Error> {
+    let operand = operand.compile(ctx)?;
+    let operand_reg = ctx.load(operand, &[])?;
+    let (new_value, new_reg) = ctx.new_value(&[operand_reg])?;
+    gen(operand_reg, new_reg, ctx);
+    ctx.free(operand);
+    Ok(new_value)
+}
+
+/// Compiles an operation whose right hand side is a constant, which is encoded into the instructions.
+fn compile_with_constant(lhs: &Expr, ctx: &mut CompilationContext, gen: impl Fn(Rq, Rq, &mut CompilationContext)) -> Result<Value, Error> {
+    let lhs = lhs.compile(ctx)?;
+    let lhs_reg = ctx.load(lhs, &[])?;
+    let (new_value, new_reg) = ctx.new_value(&[lhs_reg])?;
+    gen(lhs_reg, new_reg, ctx);
+    ctx.free(lhs);
+    Ok(new_value)
+}
 
-    // Don't look at it ༼ つ ◕_◕ ༽つ
+fn compile_if(condition: &Expr, then_expr: &Expr, else_expr: &Expr, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    let condition = condition.compile(ctx)?;
+    let condition_reg = ctx.load(condition, &[])?;
+    ctx.free(condition);
+    // Both branches have to leave the registers in the same state. Therefore, all live values are
+    // spilled before branching and the result of the branches is passed via a stack slot.
+    ctx.spill_all_registers();
+    let result_slot = ctx.next_slot();
+    let else_label = ctx.ops.new_dynamic_label();
+    let end_label = ctx.ops.new_dynamic_label();
+    dynasm!(ctx.ops
+        ; test Rd(condition_reg.code()), Rd(condition_reg.code())
+        ; jz =>else_label
+    );
+    compile_branch(then_expr, result_slot, ctx)?;
+    dynasm!(ctx.ops
+        ; jmp =>end_label
+        ; =>else_label
+    );
+    compile_branch(else_expr, result_slot, ctx)?;
+    dynasm!(ctx.ops
+        ; =>end_label
+    );
+    Ok(ctx.stack_value(result_slot))
+}
 
+/// The right operand is compiled like a branch of `compile_if`, which is skipped depending on the left
+/// operand, see `Expr::short_circuit`.
+fn compile_short_circuit(lhs: &Expr, rhs: &Expr, (skip_if, skipped): (bool, i32), ctx: &mut CompilationContext) -> Result<Value, Error> {
+    let lhs = lhs.compile(ctx)?;
+    let lhs_reg = ctx.load(lhs, &[])?;
+    ctx.free(lhs);
+    ctx.spill_all_registers();
+    let result_slot = ctx.next_slot();
+    let end_label = ctx.ops.new_dynamic_label();
     dynasm!(ctx.ops
-        ; lea rax, [=>code_label]
-        ; jmp rax
-        ; =>fn_name_label
-        ; .bytes name.as_bytes()
-        ; =>code_label
-        ; lea rdx, [=>fn_name_label]
-        ; push rcx
-        ; push r8
-        ; push r9
-        ; push r10
-        ; push r11);
-    if let Some(arg_reg) = arg {
-        let arg_reg = arg_reg?;
+        ; mov QWORD [rbp + slot_displacement(result_slot)], skipped
+        ; test Rd(lhs_reg.code()), Rd(lhs_reg.code())
+    );
+    if skip_if {
+        dynasm!(ctx.ops
+            ; jnz =>end_label
+        );
+    } else {
         dynasm!(ctx.ops
-            ; mov r9, Rq(arg_reg.code())
+            ; jz =>end_label
         );
-        ctx.free_if_possible(arg_reg);
     }
+    let rhs = rhs.compile(ctx)?;
+    let rhs_reg = ctx.load(rhs, &[])?;
     dynasm!(ctx.ops
-        ; mov rcx, QWORD code_repo_ptr as i64
-        ; mov r8, QWORD name.len() as _
-        ; mov rax, QWORD call_function as _ 
-        ; sub rsp, BYTE 0x28
-        ; call rax
-        ; add rsp, BYTE 0x28
-        ; pop r11
-        ; pop r10
-        ; pop r9
-        ; pop r8
-        ; pop rcx
-        ; mov Rq(new_reg.code()), rax
+        ; mov eax, 0
+        ; test Rd(rhs_reg.code()), Rd(rhs_reg.code())
+        ; setnz al
+        ; mov QWORD [rbp + slot_displacement(result_slot)], rax
+        ; =>end_label
+    );
+    ctx.free(rhs);
+    Ok(ctx.stack_value(result_slot))
+}
+
+fn compile_branch(expr: &Expr, result_slot: i32, ctx: &mut CompilationContext) -> Result<(), Error> {
+    let value = expr.compile(ctx)?;
+    let reg = ctx.load(value, &[])?;
+    dynasm!(ctx.ops
+        ; mov QWORD [rbp + slot_displacement(result_slot)], Rq(reg.code())
     );
-    Ok(new_reg)
+    ctx.free(value);
+    Ok(())
 }
 
-pub extern "win64" fn call_function(repository: &CodeRepository, buffer: *const u8, length: u64, arg: i32) -> i32 {
-    let fn_name = unsafe { slice::from_raw_parts(buffer, length as usize) };
-    let fn_name = std::str::from_utf8(fn_name).unwrap();
-    repository.get_fn(fn_name)
-        .map(|func| func.call(arg))
-        .unwrap_or(0)
-}
\ No newline at end of file
+/// Built-in functions are compiled inline. The bit counting instructions are only used if the CPU
+/// supports them, otherwise they are replaced by equivalent sequences.
+fn compile_builtin_call(builtin: Builtin, args: &[Expr], source: SourceSpan, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    let args = args.iter()
+        .map(|arg| arg.compile(ctx))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut regs = Vec::new();
+    for arg in &args {
+        let reg = ctx.load(*arg, &regs)?;
+        regs.push(reg);
+    }
+    let (new_value, new_reg) = ctx.new_value(&regs)?;
+    let new = new_reg.code();
+    let codes = regs.iter().map(|reg| reg.code()).collect::<Vec<_>>();
+    match (builtin, &codes[..]) {
+        (Builtin::Abs, &[x]) => dynasm!(ctx.ops
+            ; mov Rd(new), Rd(x)
+            ; neg Rd(new)
+            // x is not negative or -2147483648
+            ; cmovs Rd(new), Rd(x)
+        ),
+        (Builtin::Min, &[a, b]) => dynasm!(ctx.ops
+            ; mov Rd(new), Rd(a)
+            ; cmp Rd(a), Rd(b)
+            ; cmovg Rd(new), Rd(b)
+        ),
+        (Builtin::Max, &[a, b]) => dynasm!(ctx.ops
+            ; mov Rd(new), Rd(a)
+            ; cmp Rd(a), Rd(b)
+            ; cmovl Rd(new), Rd(b)
+        ),
+        // (x >> 31) | (x > 0)
+        (Builtin::Sign, &[x]) => dynasm!(ctx.ops
+            ; mov eax, 0
+            ; test Rd(x), Rd(x)
+            ; setg al
+            ; mov Rd(new), Rd(x)
+            ; sar Rd(new), 31
+            ; or Rd(new), eax
+        ),
+        (Builtin::Clamp, &[x, lo, hi]) => dynasm!(ctx.ops
+            ; mov Rd(new), Rd(x)
+            ; cmp Rd(new), Rd(lo)
+            ; cmovl Rd(new), Rd(lo)
+            ; cmp Rd(new), Rd(hi)
+            ; cmovg Rd(new), Rd(hi)
+        ),
+        (Builtin::Popcnt, &[x]) if is_x86_feature_detected!("popcnt") => dynasm!(ctx.ops
+            ; popcnt Rd(new), Rd(x)
+        ),
+        // Sums the bits of pairs, nibbles and bytes in parallel
+        (Builtin::Popcnt, &[x]) => dynasm!(ctx.ops
+            ; mov eax, Rd(x)
+            ; mov edx, eax
+            ; shr edx, 1
+            ; and edx, 0x5555_5555
+            ; sub eax, edx
+            ; mov edx, eax
+            ; shr eax, 2
+            ; and edx, 0x3333_3333
+            ; and eax, 0x3333_3333
+            ; add eax, edx
+            ; mov edx, eax
+            ; shr edx, 4
+            ; add eax, edx
+            ; and eax, 0x0F0F_0F0F
+            ; imul eax, eax, 0x0101_0101
+            ; shr eax, 24
+            ; mov Rd(new), eax
+        ),
+        // The feature check is required, as CPUs without lzcnt silently execute it as bsr, which yields
+        // the index of the highest set bit instead (likewise, tzcnt is executed as bsf)
+        (Builtin::Clz, &[x]) if is_x86_feature_detected!("lzcnt") => dynasm!(ctx.ops
+            ; lzcnt Rd(new), Rd(x)
+        ),
+        // bsr sets the zero flag and leaves the index undefined for 0
+        (Builtin::Clz, &[x]) => dynasm!(ctx.ops
+            ; bsr eax, Rd(x)
+            ; mov edx, -1
+            ; cmovz eax, edx
+            ; mov Rd(new), 31
+            ; sub Rd(new), eax
+        ),
+        (Builtin::Ctz, &[x]) if is_x86_feature_detected!("bmi1") => dynasm!(ctx.ops
+            ; tzcnt Rd(new), Rd(x)
+        ),
+        (Builtin::Ctz, &[x]) => dynasm!(ctx.ops
+            ; bsf eax, Rd(x)
+            ; mov edx, 32
+            ; cmovz eax, edx
+            ; mov Rd(new), eax
+        ),
+        (Builtin::Bswap, &[x]) => dynasm!(ctx.ops
+            ; mov Rd(new), Rd(x)
+            ; bswap Rd(new)
+        ),
+        _ => return Err(Error::ArityMismatch { name: builtin.name().to_string(), expected: builtin.arity(), found: args.len(), caller: None, span: source.0 }),
+    }
+    for arg in args {
+        ctx.free(arg);
+    }
+    Ok(new_value)
+}
+
+fn compile_function_call(name: &str, args: &[Expr], source: SourceSpan, ctx: &mut CompilationContext) -> Result<Value, Error> {
+    let slot = ctx.code_repository.slot(name).expect("No slot reserved for function");
+    let args = args.iter()
+        .map(|arg| arg.compile(ctx))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Afterwards, no value is located in a parameter register, so the arguments can be set up in any order.
+    ctx.spill_caller_saved_registers();
+    // The execution context is passed as first parameter
+    ctx.outgoing_size = ctx.outgoing_size.max(call_stack_size(args.len() + 1) - SHADOW_SPACE);
+    for (i, arg) in args.iter().enumerate() {
+        let index = i + 1;
+        match PARAMETER_REGISTERS.get(index) {
+            Some(reg) => ctx.copy_to(*reg, *arg),
+            None => {
+                ctx.copy_to(Rq::RAX, *arg);
+                dynasm!(ctx.ops
+                    ; mov QWORD [rsp + call_stack_size(index)], rax
+                );
+            }
+        }
+        ctx.free(*arg);
+    }
+    dynasm!(ctx.ops
+        ; mov Rq(PARAMETER_REGISTERS[0].code()), Rq(CONTEXT_REGISTER.code())
+        ; mov rax, QWORD slot as _
+        ; call QWORD [rax]
+        ; cmp QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, trap) as i32], TRAP_NONE as i32
+    );
+    match source.0.filter(|_| ctx.function_name.is_none()) {
+        // If the callee is undefined, calls in the query record their span. The trap may also stem
+        // from a call within the callee.
+        Some(span) => {
+            let continue_label = ctx.ops.new_dynamic_label();
+            dynasm!(ctx.ops
+                ; je =>continue_label
+                ; mov rax, QWORD slot as _
+                ; cmp QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, trap_slot) as i32], rax
+                ; jne =>ctx.return_label
+                ; mov QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, trap_call_start) as i32], span.start as i32
+                ; mov QWORD [Rq(CONTEXT_REGISTER.code()) + mem::offset_of!(ExecutionContext, trap_call_end) as i32], span.end as i32
+                ; jmp =>ctx.return_label
+                ; =>continue_label
+            );
+        }
+        None => dynasm!(ctx.ops
+            ; jne =>ctx.return_label
+        ),
+    }
+    let (new_value, new_reg) = ctx.new_value(&[])?;
+    dynasm!(ctx.ops
+        ; mov Rq(new_reg.code()), rax
+    );
+    Ok(new_value)
+}
@@ -0,0 +1,122 @@
+use crate::{error::Error, runtime::format_assignment};
+
+/// Consecutive values of the innermost variable for which the query does not hold, while the other
+/// variables are fixed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterexampleRange {
+    // Values of the other variables
+    pub args: Vec<i32>,
+    pub from: i32,
+    pub to: i32,
+}
+
+impl CounterexampleRange {
+    /// E.g. `x = 1, y in [-5, 3]` or `x = 1, y = 2` for a single counterexample.
+    pub fn format(&self, vars: &[String]) -> String {
+        let (inner, outer) = match vars.split_last() {
+            Some(vars) => vars,
+            None => return String::new(),
+        };
+        let inner = if self.from == self.to {
+            format!("{} = {}", inner, self.from)
+        } else {
+            format!("{} in [{}, {}]", inner, self.from, self.to)
+        };
+        if outer.is_empty() {
+            inner
+        } else {
+            format!("{}, {}", format_assignment(outer, &self.args), inner)
+        }
+    }
+
+    fn is_continued_by(&self, args: &[i32], from: i32) -> bool {
+        self.args == args && self.to.checked_add(1) == Some(from)
+    }
+}
+
+/// Counts the counterexamples and errors of a query. Consecutive counterexamples are combined into
+/// ranges, but only the first ranges are kept. Tallies of consecutive parts of the inputs are combined
+/// with `append`.
+#[derive(Debug, Clone, Default)]
+pub struct Tally {
+    pub failing: u128,
+    pub errors: u128,
+    pub first_error: Option<Error>,
+    // Number of ranges of counterexamples
+    pub ranges: u128,
+    // Number of ranges which are listed
+    limit: usize,
+    // The first ranges, at least one so that it can be continued by an appended tally
+    head: Vec<CounterexampleRange>,
+    // The last range, if it is not part of the head
+    tail: Option<CounterexampleRange>,
+}
+
+impl Tally {
+    pub fn new(limit: usize) -> Tally {
+        Tally {
+            limit,
+            ..Tally::default()
+        }
+    }
+
+    /// The first ranges of counterexamples, at most as many as the limit.
+    pub fn listed(&self) -> &[CounterexampleRange] {
+        &self.head[..self.head.len().min(self.limit)]
+    }
+
+    /// Counterexamples have to be added in the order of the inputs.
+    pub fn add_counterexample(&mut self, args: &[i32], value: i32) {
+        self.failing += 1;
+        match self.last_mut() {
+            Some(last) if last.is_continued_by(args, value) => last.to = value,
+            _ => self.push(CounterexampleRange { args: args.to_vec(), from: value, to: value }),
+        }
+    }
+
+    pub fn add_error(&mut self, error: Error) {
+        self.errors += 1;
+        self.first_error.get_or_insert(error);
+    }
+
+    /// Adds the tally of the inputs following the inputs of this tally.
+    pub fn append(&mut self, other: Tally) {
+        self.failing += other.failing;
+        self.errors += other.errors;
+        if self.first_error.is_none() {
+            self.first_error = other.first_error;
+        }
+        // Ranges between the head and the tail of the other tally, which are only counted
+        let dropped = other.ranges - other.head.len() as u128 - other.tail.is_some() as u128;
+        let mut head = other.head.into_iter();
+        if let Some(first) = head.next() {
+            match self.last_mut() {
+                Some(last) if last.is_continued_by(&first.args, first.from) => last.to = first.to,
+                _ => self.push(first),
+            }
+        }
+        for range in head {
+            self.push(range);
+        }
+        self.ranges += dropped;
+        if let Some(tail) = other.tail {
+            self.push(tail);
+        }
+    }
+
+    fn push(&mut self, range: CounterexampleRange) {
+        self.ranges += 1;
+        if self.head.len() < self.limit.max(1) {
+            self.head.push(range);
+        } else {
+            self.tail = Some(range);
+        }
+    }
+
+    fn last_mut(&mut self) -> Option<&mut CounterexampleRange> {
+        match &mut self.tail {
+            Some(tail) => Some(tail),
+            None => self.head.last_mut(),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+use std::fmt;
+
+/// Maximum number of random values of a domain.
+pub const MAX_SAMPLES: usize = 10_000_000;
+
+/// Values a single free variable of a query is checked for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Domain {
+    Range(i32, i32),
+    Values(Vec<i32>),
+}
+
+impl Domain {
+    pub fn iter(&self) -> Box<dyn Iterator<Item = i32> + '_> {
+        match self {
+            Domain::Range(from, to) => Box::new(*from..=*to),
+            Domain::Values(values) => Box::new(values.iter().copied()),
+        }
+    }
+
+    pub fn get(&self, index: u128) -> i32 {
+        match self {
+            Domain::Range(from, _) => (*from as i64 + index as i64) as i32,
+            Domain::Values(values) => values[index as usize],
+        }
+    }
+
+    pub fn len(&self) -> u128 {
+        match self {
+            Domain::Range(from, to) => (*to as i64 - *from as i64 + 1).max(0) as u128,
+            Domain::Values(values) => values.len() as u128,
+        }
+    }
+
+    pub fn index_of(&self, value: i32) -> Option<u128> {
+        match self {
+            Domain::Range(from, to) if (*from..=*to).contains(&value) => Some((value as i64 - *from as i64) as u128),
+            Domain::Range(_, _) => None,
+            Domain::Values(values) => values.iter().position(|v| *v == value).map(|index| index as u128),
+        }
+    }
+
+    /// Like `fmt`, but long lists of values are only counted.
+    pub fn summary(&self) -> String {
+        match self {
+            Domain::Values(values) if values.len() > 10 => format!("{} values", values.len()),
+            _ => self.to_string(),
+        }
+    }
+
+    /// `count` different random values. The same seed always gives the same values.
+    pub fn random(count: usize, seed: u64) -> Domain {
+        Domain::Values(sample(Vec::new(), count, seed))
+    }
+
+    /// The edge values followed by `count` different random values.
+    pub fn edges(count: usize, seed: u64) -> Domain {
+        Domain::Values(sample(edge_values(), count, seed))
+    }
+}
+
+/// Random values for the free variables, see `Domain::random` and `Domain::edges`. Each variable gets
+/// its own values, so the count applies per variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampling {
+    pub count: usize,
+    pub seed: u64,
+    // The random values follow the edge values
+    pub edges: bool,
+}
+
+impl Sampling {
+    /// The values of the free variable at the given position. The first variable uses the seed itself,
+    /// the seeds of the others are derived from it.
+    pub fn domain(&self, variable: usize) -> Domain {
+        let seed = if variable == 0 { self.seed } else { Random(self.seed ^ variable as u64).next() };
+        if self.edges {
+            Domain::edges(self.count, seed)
+        } else {
+            Domain::random(self.count, seed)
+        }
+    }
+}
+
+impl fmt::Display for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Domain::Range(from, to) => write!(f, "{}..{}", from, to),
+            Domain::Values(values) => write!(f, "{}", values.iter().map(i32::to_string).collect::<Vec<_>>().join(", ")),
+        }
+    }
+}
+
+/// MIN, MAX, 0, 1, -1 and the powers of two (and their negations) +- 1, for which overflows and
+/// off-by-one errors are likely.
+fn edge_values() -> Vec<i32> {
+    let mut values = vec![i32::MIN, i32::MAX, 0, 1, -1, i32::MIN + 1, i32::MAX - 1];
+    for shift in 1..31 {
+        let power = 1 << shift;
+        values.extend([power - 1, power, power + 1, -power + 1, -power, -power - 1]);
+    }
+    let mut seen = HashSet::new();
+    values.retain(|value| seen.insert(*value));
+    values
+}
+
+/// Appends random values to the values until `count` values were added. Values are not repeated.
+fn sample(mut values: Vec<i32>, count: usize, seed: u64) -> Vec<i32> {
+    let mut seen: HashSet<i32> = values.iter().copied().collect();
+    let mut random = Random(seed);
+    let len = values.len() + count;
+    while values.len() < len {
+        let value = (random.next() >> 32) as i32;
+        if seen.insert(value) {
+            values.push(value);
+        }
+    }
+    values
+}
+
+/// SplitMix64. It is good enough for sampling inputs and gives the same values on every platform.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+
+/// Location of a token in the input line as byte offsets (`end` is exclusive).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Reasons why the evaluation of an expression is aborted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trap {
+    DivisionByZero,
+    // -2147483648 / -1 and -2147483648 % -1
+    DivisionOverflow,
+    RecursionLimit,
+    Interrupted,
+}
+
+/// Errors of parsing, defining, compiling and evaluating expressions and of the commands of the REPL.
+/// Errors which refer to a token of the input carry its span, if it is known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // The input does not match the grammar, or a number does not fit into an i32
+    Parse { message: String, span: Option<Span> },
+    // A variable which is not a parameter of the function, or not known to the compiler for queries
+    UndefinedVariable { name: String, function: Option<String>, span: Option<Span> },
+    DuplicateParameter { name: String, function: String, span: Option<Span> },
+    // Calls of undefined functions fail when they are evaluated
+    UndefinedFunction { name: String, span: Option<Span> },
+    // The call is part of the definition of the caller, if any, instead of the input
+    ArityMismatch { name: String, expected: usize, found: usize, caller: Option<String>, span: Option<Span> },
+    // The compiler ran out of registers for an expression
+    RegisterExhaustion,
+    // Compiling the function failed when it was called. The definition is kept.
+    Compilation { function: String, error: Box<Error> },
+    // The compiler panicked while compiling a function which was called
+    CompilerPanic { message: String },
+    // Only queries without function calls can be compiled to vector code
+    NotVectorizable { expr: String },
+    // Evaluation aborted in the function, which is empty for the query itself
+    Trap { trap: Trap, function: String },
+    // Evaluation of a query failed for the assignment of its free variables
+    Evaluation { input: String, error: Box<Error> },
+    // A query, test or benchmark was cancelled. The message describes the progress.
+    Interrupted(String),
+    // Built-in functions can only be (re)defined and deleted after `.shadow`
+    BuiltinRedefinition { name: String },
+    BuiltinDeletion { name: String },
+    NotBuiltin { name: String },
+    // Invalid settings of the commands
+    EmptyRange { from: i32, to: i32 },
+    NoSamples,
+    TooManySamples { max: usize },
+    NoThreads,
+    // The operating system refused to start a worker thread
+    ThreadSpawn { reason: String },
+    // `.explain` got a value for a variable which does not occur in the query, or none for one which does
+    NotFreeVariable { name: String },
+    MissingValue { name: String },
+    // Reading or writing the checkpoint file failed, the reason is the message of the I/O error
+    CheckpointRead { file: String, reason: String },
+    CheckpointWrite { file: String, reason: String },
+    // The file is not a checkpoint in the format of `Checkpoint::fmt`
+    InvalidCheckpoint { file: String, reason: String },
+    // The function was redefined since the checkpoint was written
+    CheckpointMismatch { function: String },
+    // The last input of the checkpoint lies outside the domains of its variables
+    CheckpointOutsideDomains,
+}
+
+impl Error {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::Parse { span, .. }
+            | Error::UndefinedVariable { span, .. }
+            | Error::DuplicateParameter { span, .. }
+            | Error::UndefinedFunction { span, .. }
+            | Error::ArityMismatch { span, .. } => *span,
+            Error::Evaluation { error, .. } => error.span(),
+            _ => None,
+        }
+    }
+
+    /// The input line with a caret under the span of the error, if any, followed by the message.
+    pub fn display_in(&self, line: &str) -> String {
+        match self.span().filter(|span| span.start <= span.end && span.end <= line.len()) {
+            Some(span) => {
+                let indent = line[..span.start].chars().count();
+                let width = line[span.start..span.end].chars().count().max(1);
+                format!("{}\n{:indent$}{}\n{}", line, "", "^".repeat(width), self, indent = indent)
+            }
+            None => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse { message, .. } => write!(f, "{}", message),
+            Error::UndefinedVariable { name, function: Some(function), .. } => write!(f, "Variable {} is not a parameter of {}.", name, function),
+            Error::UndefinedVariable { name, function: None, .. } => write!(f, "Variable {} was not defined.", name),
+            Error::DuplicateParameter { name, function, .. } => write!(f, "Parameter {} of {} is defined more than once.", name, function),
+            Error::UndefinedFunction { name, .. } => write!(f, "undefined function {}", name),
+            Error::ArityMismatch { name, expected, found, caller, .. } => {
+                write!(f, "{} expects {} argument(s) but is called with {}.", name, expected, found)?;
+                match caller {
+                    Some(caller) => write!(f, " (called in {})", caller),
+                    None => Ok(()),
+                }
+            }
+            Error::RegisterExhaustion => write!(f, "No more registers available!"),
+            Error::Compilation { function, error } => write!(f, "compiling {} failed: {}", function, error),
+            Error::CompilerPanic { message } => write!(f, "the compiler panicked: {}", message),
+            Error::NotVectorizable { expr } => write!(f, "{} cannot be vectorized", expr),
+            Error::Trap { trap, function } => match trap {
+                Trap::DivisionByZero => write!(f, "{}", with_location("division by zero", function)),
+                Trap::DivisionOverflow => write!(f, "{}", with_location("division overflow", function)),
+                Trap::RecursionLimit => write!(f, "recursion limit exceeded in {}", function),
+                Trap::Interrupted => write!(f, "{}", with_location("interrupted", function)),
+            },
+            Error::Evaluation { input, error } => write!(f, "Evaluation failed for {}: {}", input, error),
+            Error::Interrupted(message) => write!(f, "{}", message),
+            Error::BuiltinRedefinition { name } => write!(f, "{} is a built-in function. Use .shadow {} to define your own.", name, name),
+            Error::BuiltinDeletion { name } => write!(f, "{} is a built-in function and cannot be deleted.", name),
+            Error::NotBuiltin { name } => write!(f, "{} is not a built-in function.", name),
+            Error::EmptyRange { from, to } => write!(f, "The range {}..{} is empty", from, to),
+            Error::NoSamples => write!(f, "At least one value is needed"),
+            Error::TooManySamples { max } => write!(f, "At most {} random values are supported", max),
+            Error::NoThreads => write!(f, "At least one thread is needed"),
+            Error::ThreadSpawn { reason } => write!(f, "Could not start a worker thread: {}", reason),
+            Error::NotFreeVariable { name } => write!(f, "{} is not a free variable of the query.", name),
+            Error::MissingValue { name } => write!(f, "The value of {} is missing.", name),
+            Error::CheckpointRead { file, reason } => write!(f, "Could not read checkpoint {}: {}", file, reason),
+            Error::CheckpointWrite { file, reason } => write!(f, "Could not write checkpoint {}: {}", file, reason),
+            Error::InvalidCheckpoint { file, reason } => write!(f, "Invalid checkpoint {}: {}", file, reason),
+            Error::CheckpointMismatch { function } => write!(f, "Definition of {} differs from the checkpoint.", function),
+            Error::CheckpointOutsideDomains => write!(f, "The last input of the checkpoint is not part of its domains."),
+        }
+    }
+}
+
+/// Appends the function in which an evaluation error occurred. Errors in the query itself have no function.
+fn with_location(error: &str, function: &str) -> String {
+    if function.is_empty() {
+        error.to_string()
+    } else {
+        format!("{} in {}", error, function)
+    }
+}
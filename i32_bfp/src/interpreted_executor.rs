@@ -1,92 +1,344 @@
-use std::{collections::HashMap, num::Wrapping};
+use std::{cell::Cell, collections::HashMap, fmt, num::Wrapping, sync::atomic::{AtomicBool, Ordering}};
 
-use crate::{ast::{self, Expr}, runtime::Executor};
+use crate::{ast::{self, Expr, SourceSpan}, builtins::Builtin, error::{Error, Trap}, runtime::{DEFAULT_MAX_CALL_DEPTH, Executor, NEVER_INTERRUPTED, QueryRunable, format_assignment}};
+
+/// Number of function calls whose evaluation is shown by an explanation. Further calls are only evaluated.
+const MAX_EXPLAINED_CALLS: usize = 100;
 
 pub struct InterpretedExecutor {
-    asts: HashMap<String, ast::FunctionDef>
+    asts: HashMap<String, ast::FunctionDef>,
+    max_call_depth: usize,
+    interrupted: &'static AtomicBool
 }
 
 impl InterpretedExecutor {
     pub fn new() -> InterpretedExecutor {
         InterpretedExecutor {
-            asts: HashMap::new()
+            asts: HashMap::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            interrupted: &NEVER_INTERRUPTED
+        }
+    }
+
+    /// Returns the definitions of all functions which may be called when evaluating the expression.
+    pub fn reachable_functions(&self, expr: &Expr) -> Vec<ast::FunctionDef> {
+        let mut reachable: Vec<ast::FunctionDef> = Vec::new();
+        let mut pending: Vec<String> = expr.function_calls().into_iter().map(|(name, _)| name).collect();
+        while let Some(name) = pending.pop() {
+            if reachable.iter().any(|function_def| function_def.name == name) {
+                continue;
+            }
+            if let Some(function_def) = self.asts.get(&name) {
+                pending.extend(function_def.body.function_calls().into_iter().map(|(name, _)| name));
+                reachable.push(function_def.clone());
+            }
         }
+        reachable
+    }
+
+    /// Evaluates the query for the values of its free variables and records the value of every
+    /// sub-expression, including the evaluation of the called functions.
+    pub fn explain(&self, query: &Expr, args: &[i32]) -> Result<Trace, Error> {
+        query.check_arity(&|name| self.asts.get(name).map(|func_def| func_def.parameters.len()))?;
+        let mut ctx = InterpretationContext::new(self, "", 0);
+        ctx.vars.extend(query.used_variables().into_iter().zip(args.iter().copied()));
+        Ok(ctx.trace(query, &Cell::new(MAX_EXPLAINED_CALLS)))
     }
 }
 
 impl Executor for InterpretedExecutor {
-    fn handle_function_def(&mut self, func_def: ast::FunctionDef) -> Result<(), String> {
+    fn handle_function_def(&mut self, func_def: ast::FunctionDef) -> Result<(), Error> {
+        func_def.validate(self.asts.values())?;
         self.asts.insert(func_def.name.to_string(), func_def);
         Ok(())
     }
     
-    fn get_query_runable<'a>(&'a mut self, query: ast::Expr) -> Result<Box<dyn 'a + Fn(i32) -> i32>, String> {
-        Ok(Box::new(move |x| {
-            let ctx = InterpretationContext::new(&self);
-            ctx.eval(&query, x) 
+    fn get_query_runable<'a>(&'a mut self, query: ast::Expr) -> Result<QueryRunable<'a>, Error> {
+        query.check_arity(&|name| self.asts.get(name).map(|func_def| func_def.parameters.len()))?;
+        let used_vars = query.used_variables();
+        Ok(Box::new(move |args| {
+            let ctx = InterpretationContext::new(self, "", 0);
+            ctx.eval(&query, &used_vars, args)
         }))
     }
 
     fn delete(&mut self, name: &str) {
         self.asts.remove(name);
     }
+
+    fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    fn set_interrupt_flag(&mut self, interrupted: &'static AtomicBool) {
+        self.interrupted = interrupted;
+    }
 }
 
 struct InterpretationContext<'a> {
     executor: &'a InterpretedExecutor,
-    vars: HashMap<String, i32>
+    // Name of the evaluated function, empty for the query itself
+    function: &'a str,
+    vars: HashMap<String, i32>,
+    // Number of active function calls
+    depth: usize
 }
 
 impl<'a> InterpretationContext<'a> {
-    fn new(executor: &InterpretedExecutor) -> InterpretationContext {
+    fn new(executor: &'a InterpretedExecutor, function: &'a str, depth: usize) -> InterpretationContext<'a> {
         InterpretationContext {
-            executor: executor,
-            vars: HashMap::new()
+            executor,
+            function,
+            vars: HashMap::new(),
+            depth
         }
     }
 
-    fn run(&self, name: &str, arg: i32) -> i32 {
-        let ast = self.executor.asts.get(name).unwrap();
-        self.eval(&ast.body, arg)
+    fn run(&self, name: &str, source: SourceSpan, args: &[i32]) -> Result<i32, Error> {
+        if self.depth >= self.executor.max_call_depth {
+            return Err(Error::Trap { trap: Trap::RecursionLimit, function: name.to_string() });
+        }
+        if self.executor.interrupted.load(Ordering::Relaxed) {
+            return Err(Error::Trap { trap: Trap::Interrupted, function: name.to_string() });
+        }
+        let ast = self.function_def(name, source)?;
+        let mut inner = InterpretationContext::new(self.executor, &ast.name, self.depth + 1);
+        inner.vars.extend(ast.parameters.iter().cloned().zip(args.iter().copied()));
+        ast.body.eval(&inner)
     }
 
-    fn eval(&self, expr: &Expr, arg: i32) -> i32 {
-        let mut inner = InterpretationContext::new(&self.executor);
-        let callee_vars = expr.used_variables();
-        if callee_vars.len() > 0 {
-            inner.vars.insert(callee_vars[0].to_string(), arg);
-        }
+    /// Calls of undefined functions only carry their span if they are part of the query, as the bodies
+    /// of the functions were parsed from other inputs.
+    fn function_def(&self, name: &str, source: SourceSpan) -> Result<&'a ast::FunctionDef, Error> {
+        let span = source.0.filter(|_| self.function.is_empty());
+        self.executor.asts.get(name).ok_or_else(|| Error::UndefinedFunction { name: name.to_string(), span })
+    }
+
+    fn eval(&self, expr: &Expr, parameters: &[String], args: &[i32]) -> Result<i32, Error> {
+        let mut inner = InterpretationContext::new(self.executor, self.function, self.depth);
+        inner.vars.extend(parameters.iter().cloned().zip(args.iter().copied()));
         expr.eval(&inner)
     }
+
+    /// Evaluates the expression like `eval` and records the values of its sub-expressions. The bodies
+    /// of at most `calls` function calls are recorded.
+    fn trace(&self, expr: &Expr, calls: &Cell<usize>) -> Trace {
+        let mut children = Vec::new();
+        let mut note = None;
+        let value = match expr {
+            Expr::Number(_) | Expr::Var(_, _) => expr.eval(self),
+            Expr::FunctionCall(name, arg_exprs, source) => self.trace_args(arg_exprs, calls, &mut children).and_then(|args| {
+                if calls.get() == 0 {
+                    note = Some("body not shown".to_string());
+                    return self.run(name, *source, &args);
+                }
+                calls.set(calls.get() - 1);
+                self.trace_call(name, *source, &args, calls, &mut children)
+            }),
+            Expr::BuiltinCall(builtin, arg_exprs, _) => self.trace_args(arg_exprs, calls, &mut children).map(|args| {
+                if *builtin == Builtin::Abs && args[0] == i32::MIN {
+                    note = Some(format!("overflow, the exact result is {}", -(args[0] as i64)));
+                }
+                builtin.eval(&args)
+            }),
+            Expr::Neg(operand) => self.trace_child(operand, calls, &mut children).map(|value| {
+                if value == i32::MIN {
+                    note = Some(format!("overflow, the exact result is {}", -(value as i64)));
+                }
+                value.wrapping_neg()
+            }),
+            Expr::Not(operand) => self.trace_child(operand, calls, &mut children).map(|value| (value == 0) as i32),
+            Expr::BitNot(operand) => self.trace_child(operand, calls, &mut children).map(|value| !value),
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) | Expr::Implies(lhs, rhs) => {
+                let (skip_if, skipped) = expr.short_circuit().unwrap();
+                let lhs = self.trace_child(lhs, calls, &mut children);
+                lhs.and_then(|lhs| if (lhs != 0) == skip_if { Ok(skipped) } else { self.trace_child(rhs, calls, &mut children).map(|rhs| (rhs != 0) as i32) })
+            }
+            Expr::If(condition, then_expr, else_expr) => {
+                let condition = self.trace_child(condition, calls, &mut children);
+                condition.and_then(|condition| self.trace_child(if condition != 0 { then_expr } else { else_expr }, calls, &mut children))
+            }
+            _ => {
+                let operands = expr.sub_expressions();
+                self.trace_args(&operands, calls, &mut children).and_then(|operands| {
+                    let (a, b) = (operands[0] as i64, operands[1] as i64);
+                    let exact = match expr {
+                        Expr::Add(_, _) => Some(a + b),
+                        Expr::Sub(_, _) => Some(a - b),
+                        Expr::Mul(_, _) => Some(a * b),
+                        _ => None,
+                    };
+                    if let Some(exact) = exact.filter(|exact| !(i32::MIN as i64..=i32::MAX as i64).contains(exact)) {
+                        note = Some(format!("overflow, the exact result is {}", exact));
+                    }
+                    // The operator is applied to the values of the operands by the interpreter itself
+                    with_operands(expr, operands[0], operands[1]).eval(self)
+                })
+            }
+        };
+        Trace { label: expr.to_string(), value, note, children }
+    }
+
+    /// Evaluates the expressions in order until one fails. Their traces are added to the children.
+    fn trace_args<E: std::borrow::Borrow<Expr>>(&self, exprs: &[E], calls: &Cell<usize>, children: &mut Vec<Trace>) -> Result<Vec<i32>, Error> {
+        exprs.iter().map(|expr| self.trace_child(expr.borrow(), calls, children)).collect()
+    }
+
+    /// Numbers and variables are not recorded, their values are obvious from the surrounding trace.
+    fn trace_child(&self, expr: &Expr, calls: &Cell<usize>, children: &mut Vec<Trace>) -> Result<i32, Error> {
+        let trace = self.trace(expr, calls);
+        let value = trace.value.clone();
+        if !matches!(expr, Expr::Number(_) | Expr::Var(_, _)) {
+            children.push(trace);
+        }
+        value
+    }
+
+    /// Like `run`, but records the evaluation of the body.
+    fn trace_call(&self, name: &str, source: SourceSpan, args: &[i32], calls: &Cell<usize>, children: &mut Vec<Trace>) -> Result<i32, Error> {
+        if self.depth >= self.executor.max_call_depth {
+            return Err(Error::Trap { trap: Trap::RecursionLimit, function: name.to_string() });
+        }
+        if self.executor.interrupted.load(Ordering::Relaxed) {
+            return Err(Error::Trap { trap: Trap::Interrupted, function: name.to_string() });
+        }
+        let ast = self.function_def(name, source)?;
+        let mut inner = InterpretationContext::new(self.executor, &ast.name, self.depth + 1);
+        inner.vars.extend(ast.parameters.iter().cloned().zip(args.iter().copied()));
+        let mut body = inner.trace(&ast.body, calls);
+        body.label = format!("{}({}) := {}", name, format_assignment(&ast.parameters, args), body.label);
+        let value = body.value.clone();
+        children.push(body);
+        value
+    }
+}
+
+/// The same operator applied to two numbers.
+fn with_operands(expr: &Expr, lhs: i32, rhs: i32) -> Expr {
+    let (lhs, rhs) = (Box::new(Expr::Number(lhs)), Box::new(Expr::Number(rhs)));
+    match expr {
+        Expr::Add(_, _) => Expr::Add(lhs, rhs),
+        Expr::Sub(_, _) => Expr::Sub(lhs, rhs),
+        Expr::Mul(_, _) => Expr::Mul(lhs, rhs),
+        Expr::Div(_, _) => Expr::Div(lhs, rhs),
+        Expr::Rem(_, _) => Expr::Rem(lhs, rhs),
+        Expr::BitAnd(_, _) => Expr::BitAnd(lhs, rhs),
+        Expr::BitOr(_, _) => Expr::BitOr(lhs, rhs),
+        Expr::BitXor(_, _) => Expr::BitXor(lhs, rhs),
+        Expr::Shl(_, _) => Expr::Shl(lhs, rhs),
+        Expr::Sar(_, _) => Expr::Sar(lhs, rhs),
+        Expr::Shr(_, _) => Expr::Shr(lhs, rhs),
+        Expr::Eq(_, _) => Expr::Eq(lhs, rhs),
+        Expr::Neq(_, _) => Expr::Neq(lhs, rhs),
+        Expr::Gt(_, _) => Expr::Gt(lhs, rhs),
+        Expr::Lt(_, _) => Expr::Lt(lhs, rhs),
+        Expr::Gte(_, _) => Expr::Gte(lhs, rhs),
+        Expr::Lte(_, _) => Expr::Lte(lhs, rhs),
+        _ => unreachable!("Not a binary operator"),
+    }
+}
+
+/// Evaluation of an expression with the values of its sub-expressions, see `InterpretedExecutor::explain`.
+/// It is printed as a tree, one sub-expression per line:
+///
+/// ```text
+/// f(x) > x -> 0
+///   f(x) -> -2147483648
+///     f(x = 2147483647) := x + 1 -> -2147483648 (overflow, the exact result is 2147483648)
+/// ```
+pub struct Trace {
+    // The expression, or the called function with its arguments and body
+    label: String,
+    value: Result<i32, Error>,
+    note: Option<String>,
+    children: Vec<Trace>,
+}
+
+impl Trace {
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        write!(f, "{:indent$}{} -> ", "", self.label, indent = indent)?;
+        match &self.value {
+            Ok(value) => write!(f, "{}", value)?,
+            Err(error) => write!(f, "error: {}", error)?,
+        }
+        match &self.note {
+            Some(note) => writeln!(f, " ({})", note)?,
+            None => writeln!(f)?,
+        }
+        for child in &self.children {
+            child.fmt_indented(f, indent + 2)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
 }
 
 trait Interpretable {
-    fn eval(&self, ctx: &InterpretationContext) -> i32;
+    fn eval(&self, ctx: &InterpretationContext) -> Result<i32, Error>;
 }
 
 impl Interpretable for Expr {
-    fn eval(&self, ctx: &InterpretationContext) -> i32 {
+    fn eval(&self, ctx: &InterpretationContext) -> Result<i32, Error> {
         match self {
-            Expr::Number(x) => *x,
-            Expr::Var(v) => *ctx.vars.get(v).unwrap(),
-            Expr::FunctionCall(name, arg_expr) => {
-                let arg = match arg_expr {
-                    Some(exp) => exp.eval(ctx),
-                    None => 0
-                };
-                ctx.run(name, arg)
+            Expr::Number(x) => Ok(*x),
+            Expr::Var(v, _) => Ok(*ctx.vars.get(v).unwrap()),
+            Expr::FunctionCall(name, arg_exprs, source) => {
+                let args = arg_exprs.iter().map(|exp| exp.eval(ctx)).collect::<Result<Vec<_>, _>>()?;
+                ctx.run(name, *source, &args)
             },
-            Expr::Add(a, b) => (Wrapping(a.eval(ctx)) + Wrapping(b.eval(ctx))).0,
-            Expr::Sub(a, b) => (Wrapping(a.eval(ctx)) - Wrapping(b.eval(ctx))).0,
-            Expr::Mul(a, b) => (Wrapping(a.eval(ctx)) * Wrapping(b.eval(ctx))).0,
-            Expr::Div(a, b) => (Wrapping(a.eval(ctx)) / Wrapping(b.eval(ctx))).0,
-            Expr::Rem(a, b) => (Wrapping(a.eval(ctx)) % Wrapping(b.eval(ctx))).0,
-            Expr::Eq(a, b) => if a.eval(ctx) == b.eval(ctx) { 1 } else { 0 },
-            Expr::Neq(a, b) => if a.eval(ctx) != b.eval(ctx) { 1 } else { 0 }
-            Expr::Gt(a, b) => if a.eval(ctx) > b.eval(ctx) { 1 } else { 0 },
-            Expr::Lt(a, b) => if a.eval(ctx) < b.eval(ctx) { 1 } else { 0 },
-            Expr::Gte(a, b) => if a.eval(ctx) >= b.eval(ctx) { 1 } else { 0 },
-            Expr::Lte(a, b) => if a.eval(ctx) <= b.eval(ctx) { 1 } else { 0 },
+            Expr::BuiltinCall(builtin, arg_exprs, _) => eval_builtin(*builtin, arg_exprs, ctx),
+            Expr::Neg(a) => Ok(a.eval(ctx)?.wrapping_neg()),
+            Expr::Add(a, b) => eval_op(a, b, ctx, |a, b| (Wrapping(a) + Wrapping(b)).0),
+            Expr::Sub(a, b) => eval_op(a, b, ctx, |a, b| (Wrapping(a) - Wrapping(b)).0),
+            Expr::Mul(a, b) => eval_op(a, b, ctx, |a, b| (Wrapping(a) * Wrapping(b)).0),
+            Expr::Div(a, b) => eval_division(a, b, ctx, i32::checked_div),
+            Expr::Rem(a, b) => eval_division(a, b, ctx, i32::checked_rem),
+            Expr::BitAnd(a, b) => eval_op(a, b, ctx, |a, b| a & b),
+            Expr::BitOr(a, b) => eval_op(a, b, ctx, |a, b| a | b),
+            Expr::BitXor(a, b) => eval_op(a, b, ctx, |a, b| a ^ b),
+            // The shift amount is taken modulo 32, like the shift instructions of x86 do
+            Expr::Shl(a, b) => eval_op(a, b, ctx, |a, b| a.wrapping_shl(b as u32)),
+            Expr::Sar(a, b) => eval_op(a, b, ctx, |a, b| a.wrapping_shr(b as u32)),
+            Expr::Shr(a, b) => eval_op(a, b, ctx, |a, b| (a as u32).wrapping_shr(b as u32) as i32),
+            Expr::Eq(a, b) => eval_op(a, b, ctx, |a, b| if a == b { 1 } else { 0 }),
+            Expr::Neq(a, b) => eval_op(a, b, ctx, |a, b| if a != b { 1 } else { 0 }),
+            Expr::Gt(a, b) => eval_op(a, b, ctx, |a, b| if a > b { 1 } else { 0 }),
+            Expr::Lt(a, b) => eval_op(a, b, ctx, |a, b| if a < b { 1 } else { 0 }),
+            Expr::Gte(a, b) => eval_op(a, b, ctx, |a, b| if a >= b { 1 } else { 0 }),
+            Expr::Lte(a, b) => eval_op(a, b, ctx, |a, b| if a <= b { 1 } else { 0 }),
+            Expr::Not(a) => Ok(if a.eval(ctx)? == 0 { 1 } else { 0 }),
+            Expr::BitNot(a) => Ok(!a.eval(ctx)?),
+            Expr::And(a, b) | Expr::Or(a, b) | Expr::Implies(a, b) => eval_short_circuit(a, b, ctx, self.short_circuit().unwrap()),
+            Expr::If(condition, a, b) => if condition.eval(ctx)? != 0 { a.eval(ctx) } else { b.eval(ctx) },
         }
     }
-}
\ No newline at end of file
+}
+
+// Kept out of `eval` so that each level of recursion only needs a small stack frame.
+fn eval_op(a: &Expr, b: &Expr, ctx: &InterpretationContext, op: fn(i32, i32) -> i32) -> Result<i32, Error> {
+    Ok(op(a.eval(ctx)?, b.eval(ctx)?))
+}
+
+fn eval_builtin(builtin: Builtin, arg_exprs: &[Expr], ctx: &InterpretationContext) -> Result<i32, Error> {
+    let args = arg_exprs.iter().map(|exp| exp.eval(ctx)).collect::<Result<Vec<_>, _>>()?;
+    Ok(builtin.eval(&args))
+}
+
+fn eval_short_circuit(a: &Expr, b: &Expr, ctx: &InterpretationContext, (skip_if, skipped): (bool, i32)) -> Result<i32, Error> {
+    if (a.eval(ctx)? != 0) == skip_if {
+        Ok(skipped)
+    } else {
+        Ok(if b.eval(ctx)? != 0 { 1 } else { 0 })
+    }
+}
+
+/// Division by zero and INT_MIN / -1 are reported as errors, in the same way as the compiled code does.
+fn eval_division(a: &Expr, b: &Expr, ctx: &InterpretationContext, op: fn(i32, i32) -> Option<i32>) -> Result<i32, Error> {
+    let (a, b) = (a.eval(ctx)?, b.eval(ctx)?);
+    op(a, b).ok_or_else(|| Error::Trap { trap: if b == 0 { Trap::DivisionByZero } else { Trap::DivisionOverflow }, function: ctx.function.to_string() })
+}
@@ -5,26 +5,63 @@ mod compiler;
 mod runtime;
 mod compiled_executor;
 mod interpreted_executor;
+mod optimizer;
+mod vector_compiler;
+mod checkpoint;
+mod counterexamples;
+mod domain;
+mod error;
+mod builtins;
 
 #[macro_use]
 extern crate pest_derive;
 extern crate dynasm;
 use std::io::{self, BufRead, Stdin, Write};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Set by Ctrl-C while a line is handled, which cancels the running command
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static BUSY: AtomicBool = AtomicBool::new(false);
+// Ctrl-C presses since the last line was entered
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
 
 fn main() {
+    ctrlc::set_handler(handle_interrupt).expect("Could not install Ctrl-C handler.");
     let stdin = io::stdin();
     let mut runtime = runtime::Runtime::new();
+    runtime.set_interrupt_flag(&INTERRUPTED);
     loop {
         print!("> ");
         std::io::stdout().flush().expect("flush error.");
         let input = read_line(&stdin);
+        INTERRUPTS.store(0, Ordering::SeqCst);
         match input.as_deref() {
             Some("quit") => { return; },
-            Some(line) => {runtime.handle_line(line);}
+            Some(line) => {
+                INTERRUPTED.store(false, Ordering::SeqCst);
+                BUSY.store(true, Ordering::SeqCst);
+                runtime.handle_line(line);
+                BUSY.store(false, Ordering::SeqCst);
+            }
             None => {}
         }
     }
 }
+
+/// Ctrl-C cancels the running command. At the prompt, a second Ctrl-C exits (the one which cancelled
+/// a command counts as first one).
+fn handle_interrupt() {
+    let interrupts = INTERRUPTS.fetch_add(1, Ordering::SeqCst) + 1;
+    if BUSY.load(Ordering::SeqCst) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    } else if interrupts > 1 {
+        process::exit(0);
+    } else {
+        print!("\n(To exit, press Ctrl-C again or type quit)\n> ");
+        std::io::stdout().flush().expect("flush error.");
+    }
+}
     
 fn read_line(stdin: &Stdin) -> Option<String> {
     let mut iterator = stdin.lock().lines();
@@ -33,7 +70,12 @@ fn read_line(stdin: &Stdin) -> Option<String> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{compiled_executor::CompiledExecutor, interpreted_executor::InterpretedExecutor, parser::parse, runtime::Executor};
+    use std::{env, fs, process};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use itertools::Itertools;
+
+    use crate::{ast::SourceSpan, builtins::Builtin, code_repository::CodeRepository, domain::{Domain, Sampling}, error::{Error, Span, Trap}, compiled_executor::CompiledExecutor, compiler::{CompilationContext, ExecutionContext, SCAN_NOT_FOUND}, interpreted_executor::InterpretedExecutor, optimizer::Optimizable, parser::parse, runtime::{DEFAULT_MAX_CALL_DEPTH, Executor, NEVER_INTERRUPTED, Runtime}, vector_compiler::{self, VectorCompilationContext}};
 
     #[test]
     fn num_is_compiled_correctly() {
@@ -125,6 +167,971 @@ mod tests {
         check_query_equiv("f(x) > x", vec![1189796073], &mut compiled_executor, &mut interpreted_executor);
     }
 
+    #[test]
+    fn long_sum_is_compiled_correctly() {
+        check_equiv("x + 1 + x + 2 + x + 3 + x + 4 + x + 5 + x + 6", vec![i32::MIN, -1, 0, 1, i32::MAX])
+    }
+
+    #[test]
+    fn deeply_nested_add_is_compiled_correctly() {
+        check_equiv(&nested("x + ", "1", 40), vec![i32::MIN, -1, 0, 1, i32::MAX])
+    }
+
+    #[test]
+    fn deeply_nested_mixed_ops_are_compiled_correctly() {
+        let expr = (0..30).fold("x".to_string(), |acc, i| match i % 4 {
+            0 => format!("(x + {}) * ({})", i, acc),
+            1 => format!("({}) - (x % 7)", acc),
+            2 => format!("(x / 3) + ({})", acc),
+            _ => format!("(({}) < x) + ({})", acc, acc),
+        });
+        check_equiv(&expr, vec![i32::MIN, -1, 0, 1, 7, i32::MAX])
+    }
+
+    #[test]
+    fn deeply_nested_function_calls_are_compiled_correctly() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def(&format!("f(x) := {}", nested("x * ", "2", 15)), &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv(&nested("f(x) + ", "x", 20), vec![i32::MIN, -1, 0, 1, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+        let calls_in_args = (0..20).fold("x".to_string(), |acc, _| format!("x + f({})", acc));
+        check_query_equiv(&calls_in_args, vec![i32::MIN, -1, 0, 1, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+    }
+
+    #[test]
+    fn multi_parameter_function_call_is_compiled_correctly() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x, y, z) := x * 100 + y * 10 - z", &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("g(a, b) := f(b, a, a + b) + f(a, a, a)", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("f(x, 2, 3) + g(x, 7)", vec![i32::MIN, -1, 0, 1, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+    }
+
+    #[test]
+    fn function_with_stack_parameters_is_compiled_correctly() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(a, b, c, d, e, g, h) := a - b * 2 + c * 3 - d * 4 + e * 5 - g * 6 + h * 7", &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("k(a, b, c, d, e, g) := f(g, e, d, c, b, a, f(a, b, c, d, e, g, a))", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("k(x, x + 1, 3, x * 4, 5, x - 6)", vec![i32::MIN, -1, 0, 1, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+    }
+
+    #[test]
+    fn function_without_parameters_is_compiled_correctly() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("c() := 42", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("c() + x", vec![i32::MIN, 0, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+    }
+
+    #[test]
+    fn arity_mismatch_is_reported() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x, y) := x + y", &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("g(x) := h(x, 1) + f(x, 1)", &mut compiled_executor, &mut interpreted_executor);
+
+        let executors: [&mut dyn Executor; 2] = [&mut compiled_executor, &mut interpreted_executor];
+        for executor in executors {
+            assert!(executor.get_query_runable(parse_query("f(x)")).is_err());
+            assert!(executor.handle_function_def(parse_function_def("k(x) := f(x, x, x)")).is_err());
+            assert!(executor.handle_function_def(parse_function_def("h(x) := x")).is_err());
+            assert!(executor.handle_function_def(parse_function_def("f(x) := x")).is_err());
+            assert!(executor.handle_function_def(parse_function_def("k(x) := y")).is_err());
+            assert!(executor.handle_function_def(parse_function_def("k(x, x) := x")).is_err());
+            assert!(executor.handle_function_def(parse_function_def("h(x, y) := x - y")).is_ok());
+        }
+    }
+
+    #[test]
+    fn query_with_several_variables_is_compiled_correctly() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x, y) := x * 3 - y", &mut compiled_executor, &mut interpreted_executor);
+        let expr = parse_query("f(y, x) + z / 7 - x * y");
+        let compiled = compiled_executor.get_query_runable(expr.clone()).unwrap();
+        let interpreted = interpreted_executor.get_query_runable(expr).unwrap();
+        for args in [[1, 2, 3], [i32::MIN, -1, i32::MAX], [0, i32::MAX, -8], [-5, 17, 100]] {
+            assert_eq!(compiled(&args), interpreted(&args), "The values were not equal for input {:?}.", args);
+        }
+    }
+
+    #[test]
+    fn query_with_several_variables_is_checked_for_all_assignments() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line(".mode fast");
+        assert_eq!(runtime.execute_query(parse_query("x + y = y + x")).unwrap(), None);
+        assert_eq!(runtime.execute_query(parse_query("x + y * z = z * y + x")).unwrap(), None);
+        assert_eq!(runtime.execute_query(parse_query("x - y < x")).unwrap(), Some(vec![i32::MIN, i32::MIN]));
+        // Variables are enumerated in the order of their first occurrence
+        assert_eq!(runtime.execute_query(parse_query("y <> x + 1")).unwrap(), Some(vec![i32::MIN, i32::MAX]));
+        runtime.handle_line(".executor interpreted");
+        assert_eq!(runtime.execute_query(parse_query("y <> x + 1")).unwrap(), Some(vec![i32::MIN, i32::MAX]));
+    }
+
+    #[test]
+    fn scan_finds_the_first_failing_input() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x, y) := if y < x then 0 else x * y", &mut compiled_executor, &mut interpreted_executor);
+        let expr = parse_query("f(y, x) + y / x");
+        let interpreted = interpreted_executor.get_query_runable(expr.clone()).unwrap();
+        let scan = compiled_executor.get_scan_runable(expr).unwrap().unwrap();
+        for (y, from, to) in [(3, -10, 10), (-4, -10, 10), (0, -10, 10), (5, 20, 30), (7, i32::MAX - 3, i32::MAX)] {
+            let expected = (from..=to).map(|x| (x, interpreted(&[y, x]))).find(|(_, result)| *result == Ok(0) || result.is_err());
+            assert_eq!(scan(&[y], from, to), expected, "The scan was not correct for y = {} and x in {}..={}.", y, from, to);
+        }
+    }
+
+    #[test]
+    fn proof_mode_checks_the_whole_range() {
+        let mut runtime = Runtime::new();
+        // The counterexamples lie in the second chunk of the scan
+        assert_eq!(runtime.execute_query(parse_query("x <> 0 - 2000000000")).unwrap(), Some(vec![-2_000_000_000]));
+        assert_eq!(runtime.execute_query(parse_query("y * 0 <> x + 2000000000")).unwrap(), Some(vec![i32::MIN, -2_000_000_000]));
+        assert_eq!(runtime.execute_query(parse_query("7 <> x / (x + 2000000000)")).map_err(|error| error.to_string()), Err("Evaluation failed for x = -2000000000: division by zero".to_string()));
+        assert_eq!(runtime.execute_query(parse_query("x - 1 <> x")).unwrap(), None);
+    }
+
+    #[test]
+    fn threads_report_the_first_counterexample() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line(".threads 4");
+        assert_eq!(runtime.execute_query(parse_query("(x > 100) + (x < 0 - 100)")).unwrap(), Some(vec![-100]));
+        assert_eq!(runtime.execute_query(parse_query("x < 2000000000 - 5")).unwrap(), Some(vec![2_000_000_000 - 5]));
+        assert_eq!(runtime.execute_query(parse_query("7 <> x / (x - 1000)")).map_err(|error| error.to_string()), Err("Evaluation failed for x = 1000: division by zero".to_string()));
+        assert_eq!(runtime.execute_query(parse_query("y * 0 <> x + 2000000000")).unwrap(), Some(vec![i32::MIN, -2_000_000_000]));
+        assert_eq!(runtime.execute_query(parse_query("1")).unwrap(), None);
+    }
+
+    #[test]
+    fn threads_compile_functions_ahead_of_time() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line(".threads 3");
+        runtime.handle_line(".mode fast");
+        runtime.handle_line("f(x) := if x < 0 then g(0) else x");
+        runtime.handle_line("g(x) := f(x) + 1");
+        runtime.handle_line("h(x) := x / 0");
+        for executor in [".executor compiled", ".executor interpreted"] {
+            runtime.handle_line(executor);
+            assert_eq!(runtime.execute_query(parse_query("f(x) + f(y) <> f(z)")).unwrap(), Some(vec![i32::MIN, 0, i32::MIN]));
+            assert_eq!(runtime.execute_query(parse_query("y < 1 + z + x / z")).map_err(|error| error.to_string()), Err("Evaluation failed for y = -2147483648, z = -1, x = -2147483648: division overflow".to_string()));
+            assert_eq!(runtime.execute_query(parse_query("if x = 1 then h(y) else 1")).map_err(|error| error.to_string()), Err("Evaluation failed for x = 1, y = -2147483648: division by zero in h".to_string()));
+            assert_eq!(runtime.execute_query(parse_query("f(x) >= 0")).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn threads_compile_redefined_callees_of_compiled_functions() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line(".mode fast");
+        runtime.handle_line("f(x) := x");
+        runtime.handle_line("g(x) := f(x) + 1");
+        assert_eq!(runtime.execute_query(parse_query("g(x) <> 1")).unwrap(), Some(vec![0]));
+        // g stays compiled, but calls the stub of f
+        runtime.handle_line("f(x) := x * 2");
+        runtime.handle_line(".threads 8");
+        assert_eq!(runtime.execute_query(parse_query("g(x) <> 1")).unwrap(), Some(vec![i32::MIN]));
+
+        let code_repository = CodeRepository::new();
+        let mut code_repository = code_repository.lock().unwrap();
+        code_repository.add_placeholder(parse_function_def("f(x) := x")).unwrap();
+        code_repository.add_placeholder(parse_function_def("g(x) := f(x) + 1")).unwrap();
+        let query = parse_query("g(x)");
+        code_repository.compile_reachable_functions(&query);
+        assert!(code_repository.is_compiled("f") && code_repository.is_compiled("g"));
+        code_repository.add_placeholder(parse_function_def("f(x) := x * 2")).unwrap();
+        assert!(!code_repository.is_compiled("f"));
+        code_repository.compile_reachable_functions(&query);
+        assert!(code_repository.is_compiled("f"));
+    }
+
+    #[test]
+    fn threads_do_not_call_stubs() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line(".range 0 100000");
+        runtime.handle_line(".threads 4");
+        runtime.handle_line("f(x) := x");
+        runtime.handle_line("g(x) := f(x) + 1");
+        assert_eq!(runtime.execute_query(parse_query("g(x) % 1000 <> 999")).unwrap(), Some(vec![998]));
+        runtime.handle_line("f(x) := x * 3");
+        assert_eq!(runtime.execute_query(parse_query("g(x) % 1000 <> 999")).unwrap(), Some(vec![666]));
+        assert_eq!(runtime.stub_calls(), 0);
+    }
+
+    #[test]
+    fn interrupted_queries_keep_the_state() {
+        let interrupted: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(true)));
+        let mut runtime = Runtime::new();
+        runtime.set_interrupt_flag(interrupted);
+        runtime.handle_line("f(x) := x * 2");
+        for executor in [".executor compiled", ".executor interpreted"] {
+            runtime.handle_line(executor);
+            assert_eq!(runtime.execute_query(parse_query("f(x) <> x")).map_err(|error| error.to_string()), Err("Interrupted after checking 0 of 4294967296 inputs.".to_string()));
+        }
+        interrupted.store(false, Ordering::Relaxed);
+        assert_eq!(runtime.execute_query(parse_query("f(x) <> x + x")).unwrap(), Some(vec![i32::MIN]));
+    }
+
+    #[test]
+    fn interrupts_cancel_running_function_calls() {
+        let interrupted: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(true)));
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        for executor in [&mut compiled_executor as &mut dyn Executor, &mut interpreted_executor] {
+            executor.set_interrupt_flag(interrupted);
+            // Takes 2^60 calls
+            executor.handle_function_def(parse_function_def("f(x) := if x < 1 then 0 else f(x - 1) + f(x - 1)")).unwrap();
+            let query = executor.get_query_runable(parse_query("f(60)")).unwrap();
+            assert_eq!(query(&[]), Err(Error::Trap { trap: Trap::Interrupted, function: "f".to_string() }));
+        }
+    }
+
+    #[test]
+    fn interrupted_proofs_report_their_progress() {
+        let interrupted: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+        let mut runtime = Runtime::new();
+        runtime.set_interrupt_flag(interrupted);
+        runtime.set_progress_hook(move |checked_blocks| if checked_blocks >= 3 {
+            interrupted.store(true, Ordering::Relaxed);
+        });
+        runtime.handle_line(".threads 2");
+        let error = runtime.execute_query(parse_query("x <> x + 1")).unwrap_err().to_string();
+        assert!(error.starts_with("Interrupted after checking "), "{}", error);
+        // Inputs are checked in blocks of 2^20 values
+        let last_checked: i64 = error.split("up to and including x = ").nth(1).and_then(|rest| rest.strip_suffix(" were checked.")).unwrap().parse().unwrap();
+        assert!(last_checked >= i32::MIN as i64 + 3 * (1 << 20) - 1, "{}", error);
+        assert_eq!((last_checked + 1 - i32::MIN as i64) % (1 << 20), 0);
+    }
+
+    #[test]
+    fn printed_expressions_are_parsed_into_the_same_tree() {
+        for expr in ["x - y - z", "(x - y) - z", "x * (y + 1) % 3", "(x = y) = (if x < 0 then f(x, 2) else g())", "x + (if x then 1 else 2) * 3", "f(g(x) / 2) <> x >= 1"] {
+            let query = parse_query(expr);
+            assert_eq!(parse_query(&query.to_string()), query, "{}", query);
+        }
+        assert_eq!(parse_function_def("f(x, y) := if x > y then x else f(y, x)").to_string(), "f(x, y) := if x > y then x else f(y, x)");
+    }
+
+    #[test]
+    fn negative_hex_and_binary_literals_are_parsed() {
+        let literals = [("-2147483648", i32::MIN), ("2147483647", i32::MAX), ("-1", -1), ("0xFF", 255), ("0b101", 5), ("0xFFFFFFFF", -1), ("-0x80000000", i32::MIN), ("0b10000000000000000000000000000000", i32::MIN)];
+        for (literal, value) in literals.iter() {
+            assert_eq!(parse_query(literal), crate::ast::Expr::Number(*value), "{}", literal);
+        }
+        for literal in ["2147483648", "-2147483649", "0x100000000", "0b2"] {
+            assert!(parse(literal).is_err(), "{}", literal);
+        }
+        let var = |name: &str| Box::new(crate::ast::Expr::Var(name.to_string(), SourceSpan::default()));
+        assert_eq!(parse_query("-x * y"), crate::ast::Expr::Mul(Box::new(crate::ast::Expr::Neg(var("x"))), var("y")));
+        assert_eq!(parse_query("x - -1"), crate::ast::Expr::Sub(var("x"), Box::new(crate::ast::Expr::Number(-1))));
+        assert_eq!(parse_query("x-1"), crate::ast::Expr::Sub(var("x"), Box::new(crate::ast::Expr::Number(1))));
+        assert_eq!(parse_query("- 1"), crate::ast::Expr::Neg(Box::new(crate::ast::Expr::Number(1))));
+        for expr in ["-(5)", "--5", "--x", "-(x + 1) * -3", "x - -y", "-f(x) % 2"] {
+            let query = parse_query(expr);
+            assert_eq!(query.to_string(), expr);
+            assert_eq!(parse_query(&query.to_string()), query, "{}", query);
+        }
+
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x) := -x - 1", &mut compiled_executor, &mut interpreted_executor);
+        for expr in ["-x", "--x", "-x * -3", "x - -2147483648", "-(x + 1) <> 0xFFFF", "f(-x)", "-f(x) = x + 1"] {
+            check_query_equiv(expr, vec![i32::MIN, -1, 0, 1, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+        }
+        assert_eq!(interpreted_executor.get_query_runable(parse_query("-x")).unwrap()(&[i32::MIN]), Ok(i32::MIN));
+        assert_eq!(parse_query("-(-5 + 2)").optimize(), crate::ast::Expr::Number(3));
+        assert_eq!(parse_query("--x").optimize(), *var("x"));
+        check_vector_scan("-x <> 7", &[], -100, 100);
+        check_vector_scan("-x < 0 = (x > 0)", &[], i32::MIN, i32::MIN + 100);
+    }
+
+    #[test]
+    fn connectives_are_evaluated_with_short_circuit() {
+        let var = |name: &str| Box::new(crate::ast::Expr::Var(name.to_string(), SourceSpan::default()));
+        assert_eq!(parse_query("a => b => c"), crate::ast::Expr::Implies(var("a"), Box::new(crate::ast::Expr::Implies(var("b"), var("c")))));
+        assert_eq!(parse_query("a || b && c"), crate::ast::Expr::Or(var("a"), Box::new(crate::ast::Expr::And(var("b"), var("c")))));
+        assert_eq!(parse_query("!a = b"), crate::ast::Expr::Eq(Box::new(crate::ast::Expr::Not(var("a"))), var("b")));
+        for expr in ["a => b => c", "(a => b) => c", "a || b && c", "(a || b) && c", "a && b && c", "a && (b && c)", "!(x > 0) => !-x", "x >= 0 => x + 1 > x"] {
+            let query = parse_query(expr);
+            assert_eq!(query.to_string(), expr);
+            assert_eq!(parse_query(&query.to_string()), query, "{}", query);
+        }
+
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x) := x <= 0 || f(x - 1) && 1", &mut compiled_executor, &mut interpreted_executor);
+        let cases = [
+            ("x > 0 && x < 10", [0, 0, 0, 1, 0]), ("x < 0 || x = 5", [1, 1, 0, 0, 0]), ("x > 0 => x > 1", [1, 1, 1, 0, 1]), ("!x", [0, 0, 1, 0, 0]), ("!!x", [1, 1, 0, 1, 1]),
+            ("x && 7", [1, 1, 0, 1, 1]), ("x || 0", [1, 1, 0, 1, 1]), ("0 => 100 / 0", [1, 1, 1, 1, 1]), ("x <> 0 && 100 / x > 50", [0, 0, 0, 1, 0]),
+            ("x = 0 || 100 / x", [0, 1, 1, 1, 0]), ("x <> 0 => 100 % x = 0", [0, 1, 1, 1, 0]), ("f(10) && x >= 0", [0, 0, 1, 1, 1]),
+        ];
+        for (expr, expected) in cases.iter() {
+            let query = parse_query(expr);
+            let compiled = compiled_executor.get_query_runable(query.clone()).unwrap();
+            let interpreted = interpreted_executor.get_query_runable(query).unwrap();
+            for (x, expected) in [i32::MIN, -1, 0, 1, i32::MAX].iter().zip(expected) {
+                assert_eq!(compiled(&[*x]), Ok(*expected), "{} for x = {}", expr, x);
+                assert_eq!(interpreted(&[*x]), Ok(*expected), "{} for x = {}", expr, x);
+            }
+        }
+        check_vector_scan("x <> 0 => 1000 / x <> 7", &[], -300, 300);
+        check_vector_scan("x = 0 || 1000 % x < 900 && !(x = 150)", &[], -300, 300);
+    }
+
+    #[test]
+    fn bitwise_operators_are_compiled_correctly() {
+        let var = |name: &str| Box::new(crate::ast::Expr::Var(name.to_string(), SourceSpan::default()));
+        let one = || Box::new(crate::ast::Expr::Number(1));
+        assert_eq!(parse_query("x & x - 1"), crate::ast::Expr::BitAnd(var("x"), Box::new(crate::ast::Expr::Sub(var("x"), one()))));
+        assert_eq!(parse_query("x & 1 = 0"), crate::ast::Expr::Eq(Box::new(crate::ast::Expr::BitAnd(var("x"), one())), Box::new(crate::ast::Expr::Number(0))));
+        assert_eq!(parse_query("a | b ^ c"), crate::ast::Expr::BitOr(var("a"), Box::new(crate::ast::Expr::BitXor(var("b"), var("c")))));
+        assert_eq!(parse_query("x >>> 1 >> 1"), crate::ast::Expr::Sar(Box::new(crate::ast::Expr::Shr(var("x"), one())), one()));
+        for expr in ["a | b ^ c & d", "(a | b) & ~c", "x << y + 1", "(x << y) + 1", "~-x >>> 31 <= x", "x & 1 = 0 && x >> 1 > 0"] {
+            let query = parse_query(expr);
+            assert_eq!(query.to_string(), expr);
+            assert_eq!(parse_query(&query.to_string()), query, "{}", query);
+        }
+
+        let values = vec![i32::MIN, -33, -32, -31, -1, 0, 1, 5, 31, 32, 33, i32::MAX];
+        for expr in ["x & (x - 1)", "x | 0xF0", "x ^ -x", "~x", "1 << x", "-8 >> x", "-8 >>> x", "x << 3", "x >> 33", "x >>> -1", "(x & -x) = (x ^ (x - 1)) & x"] {
+            check_equiv(expr, values.clone());
+        }
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x, y) := x << y | x >>> 32 - y", &mut compiled_executor, &mut interpreted_executor);
+        for (expr, expected) in [("f(x, 4)", 0x2345_6781), ("f(x, 36)", 0x2345_6781), ("x >> 28", 1), ("-x >> 28", -2), ("-x >>> 28", 14), ("~x & 0xFF", 0x87)] {
+            let query = parse_query(expr);
+            assert_eq!(compiled_executor.get_query_runable(query.clone()).unwrap()(&[0x1234_5678]), Ok(expected), "{}", expr);
+            assert_eq!(interpreted_executor.get_query_runable(query).unwrap()(&[0x1234_5678]), Ok(expected), "{}", expr);
+        }
+        check_vector_scan("(x & (x - 1)) <> 0 || x = 64", &[], -300, 300);
+        check_vector_scan("(1 << x | ~y) ^ x >> x <> x >>> 3", &[5], -300, 300);
+    }
+
+    #[test]
+    fn builtins_are_compiled_inline() {
+        assert_eq!(parse_query("abs(x - 1)"), crate::ast::Expr::BuiltinCall(Builtin::Abs, vec![parse_query("x - 1")], SourceSpan::default()));
+        assert_eq!(parse_query("clamp(x, -1, 1)").to_string(), "clamp(x, -1, 1)");
+        assert_eq!(parse_query("popcnt(-1) + clz(1) + ctz(0) + bswap(0x12345678) + sign(-5)").optimize(), crate::ast::Expr::Number(32 + 31 + 32 + 0x7856_3412 - 1));
+
+        let values = vec![i32::MIN, i32::MIN + 1, -256, -5, -1, 0, 1, 2, 5, 255, 0x1234_5678, i32::MAX - 1, i32::MAX];
+        for expr in ["abs(x)", "min(x, 5)", "max(-x, x)", "sign(x)", "clamp(x, -3, 7)", "clamp(x, x / 2, 100)", "popcnt(x)", "clz(x)", "ctz(x)", "bswap(x)", "abs(min(x, 3) - max(x, -3)) + sign(x - 1)"] {
+            check_equiv(expr, values.clone());
+        }
+        let cases = [
+            ("abs(x)", [i32::MIN, 5, 0, 5]), ("min(x, 0)", [i32::MIN, -5, 0, 0]), ("max(x, 0)", [0, 0, 0, 5]),
+            ("sign(x)", [-1, -1, 0, 1]), ("clamp(x, -3, 3)", [-3, -3, 0, 3]), ("popcnt(x)", [1, 31, 0, 2]),
+            ("clz(x)", [0, 0, 32, 29]), ("ctz(x)", [31, 0, 32, 0]), ("bswap(x)", [0x80, -0x0400_0001, 0, 0x0500_0000]),
+        ];
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        for (expr, expected) in cases.iter() {
+            let query = parse_query(expr);
+            let compiled = compiled_executor.get_query_runable(query.clone()).unwrap();
+            let interpreted = interpreted_executor.get_query_runable(query).unwrap();
+            for (input, expected) in [i32::MIN, -5, 0, 5].iter().zip(expected) {
+                assert_eq!(compiled(&[*input]), Ok(*expected), "{} for {}", expr, input);
+                assert_eq!(interpreted(&[*input]), Ok(*expected), "{} for {}", expr, input);
+            }
+        }
+        assert!(!vector_compiler::can_vectorize(&parse_query("popcnt(x) > 0")));
+        check_vector_scan("abs(x) > 250 || sign(x) = 0", &[], -300, 300);
+        check_vector_scan("clamp(x, -y, y) <> min(max(x, -y), y) || x = 17", &[20], -300, 300);
+
+        let mut runtime = Runtime::new();
+        let error = |result: Result<(), Error>| result.map_err(|error| error.to_string());
+        assert_eq!(error(runtime.handle_str("abs(x) := x")), Err("abs is a built-in function. Use .shadow abs to define your own.".to_string()));
+        assert_eq!(error(runtime.handle_str(".delete abs")), Err("abs is a built-in function and cannot be deleted.".to_string()));
+        assert_eq!(error(runtime.handle_str("min(x)")), Err("min expects 2 argument(s) but is called with 1.".to_string()));
+        runtime.handle_str(".values -2, 2").unwrap();
+        runtime.handle_str("f(x) := abs(x)").unwrap();
+        runtime.handle_str(".shadow abs").unwrap();
+        runtime.handle_str("abs(x) := x").unwrap();
+        // f was defined before abs was shadowed
+        assert_eq!(runtime.execute_query(parse_query("f(x) > 0")).unwrap(), None);
+        let mut query = parse_query("abs(x) > 0");
+        query.shadow_builtins(&|builtin| builtin == Builtin::Abs);
+        assert_eq!(runtime.execute_query(query).unwrap(), Some(vec![-2]));
+        runtime.handle_str(".delete abs").unwrap();
+        assert_eq!(error(runtime.handle_str("abs(x) := x")), Err("abs is a built-in function. Use .shadow abs to define your own.".to_string()));
+    }
+
+    #[test]
+    fn operators_are_left_associative_and_respect_precedence() {
+        let var = |name: &str| Box::new(crate::ast::Expr::Var(name.to_string(), SourceSpan::default()));
+        assert_eq!(parse_query("x - y - z"), crate::ast::Expr::Sub(Box::new(crate::ast::Expr::Sub(var("x"), var("y"))), var("z")));
+        assert_eq!(parse_query("x - y - z").to_string(), "x - y - z");
+        assert_eq!(parse_query("x - (y - z)").to_string(), "x - (y - z)");
+
+        let cases = [
+            ("x - 3 - 2", 5), ("x - 3 + 2", 9), ("x + 3 - 2", 11), ("x * 10 / 5 / 2", 10), ("x / 5 * 2", 4),
+            ("x * 100 % 7 % 3", 0), ("x % 7 % 2", 1), ("x % 6 * 2", 8), ("100 / x / 5", 2), ("2 + x * 4", 42),
+            ("x - 2 * 3 - 1", 3), ("x - 4 / 2 + 1", 9), ("(x - 3) - 2", 5), ("x - (3 - 2)", 9), ("x / (10 / 5)", 5),
+            ("1 + x > x + 1 - 1", 1), ("x - 5 = 5", 1), ("x = 10 = 1", 1), ("x < 5 = 0", 1),
+        ];
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        for (expr, expected) in cases.iter() {
+            let query = parse_query(expr);
+            let compiled = compiled_executor.get_query_runable(query.clone()).unwrap();
+            let interpreted = interpreted_executor.get_query_runable(query).unwrap();
+            assert_eq!(compiled(&[10]), Ok(*expected), "{}", expr);
+            assert_eq!(interpreted(&[10]), Ok(*expected), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn checkpoints_are_resumed_after_the_last_input() {
+        let file = checkpoint_file("resumed_after_the_last_input");
+        let mut runtime = Runtime::new();
+        runtime.handle_line("f(x, y) := x + y");
+        for executor in [".executor compiled", ".executor interpreted"] {
+            runtime.handle_line(executor);
+            fs::write(&file, "query: f(x, y) <> 3\nfunction: f(x, y) := x + y\nvariable: x in 0..10\nvariable: y in -1, 1, 2, 3\nlast: x = 1, y = -1\n").unwrap();
+            assert_eq!(runtime.resume(&file).unwrap(), Some(vec![1, 2]));
+            fs::write(&file, "query: f(x, y) <> 3\nfunction: f(x, y) := x + y\nvariable: x in 0..10\nvariable: y in -1, 1, 2, 3\nlast: x = 1, y = 2\n").unwrap();
+            assert_eq!(runtime.resume(&file).unwrap(), Some(vec![2, 1]));
+            // The checkpoint is updated with the inputs before the counterexample
+            assert!(fs::read_to_string(&file).unwrap().ends_with("variable: y in -1, 1, 2, 3\nlast: x = 1, y = 3\n"));
+            fs::write(&file, "query: f(x, y) <> 3\nfunction: f(x, y) := x + y\nvariable: x in 0..10\nvariable: y in -1, 1, 2, 3\nlast: x = 10, y = 3\n").unwrap();
+            assert_eq!(runtime.resume(&file).unwrap(), None);
+        }
+        fs::write(&file, "query: f(x, y) <> 3\nvariable: x in 0..10\nvariable: y in -1, 1, 2, 3\n").unwrap();
+        assert_eq!(runtime.resume(&file).map_err(|error| error.to_string()), Err("Definition of f differs from the checkpoint.".to_string()));
+        fs::write(&file, "query: x <> 3\nvariable: x in 0..10\nlast: x = 11\n").unwrap();
+        assert_eq!(runtime.resume(&file).map_err(|error| error.to_string()), Err("The last input of the checkpoint is not part of its domains.".to_string()));
+        fs::write(&file, "query: x <> y\nvariable: x in 0..10\n").unwrap();
+        assert_eq!(runtime.resume(&file).map_err(|error| error.to_string()), Err(format!("Invalid checkpoint {}: Expected the domains of the variables x, y", file)));
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn interrupted_proofs_can_be_resumed() {
+        let file = checkpoint_file("interrupted_proofs_can_be_resumed");
+        let interrupted: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+        let mut runtime = Runtime::new();
+        runtime.set_interrupt_flag(interrupted);
+        runtime.handle_line(&format!(".checkpoint {}", file));
+        runtime.handle_line("g(x) := x * 2");
+        runtime.handle_line("f(x) := g(x) + 0 * h(x)");
+        runtime.handle_line("h(x) := x");
+        runtime.set_progress_hook(move |_| interrupted.store(true, Ordering::Relaxed));
+        assert!(runtime.execute_query(parse_query("f(x) <> x - 1647483648")).unwrap_err().to_string().starts_with("Interrupted after checking "));
+        let checkpoint = fs::read_to_string(&file).unwrap();
+        assert!(checkpoint.contains("\nquery: f(x) <> x - 1647483648\nfunction: f(x) := g(x) + 0 * h(x)\nfunction: h(x) := x\nfunction: g(x) := x * 2\nvariable: x in -2147483648..2147483647\nlast: x = "), "{}", checkpoint);
+
+        interrupted.store(false, Ordering::Relaxed);
+        runtime.set_progress_hook(|_| {});
+        runtime.handle_line("g(x) := x + x");
+        assert_eq!(runtime.resume(&file).map_err(|error| error.to_string()), Err("Definition of g differs from the checkpoint.".to_string()));
+        runtime.handle_line("g(x) := x * 2");
+        assert_eq!(runtime.resume(&file).unwrap(), Some(vec![-1_647_483_648]));
+        fs::remove_file(&file).unwrap();
+    }
+
+    fn checkpoint_file(name: &str) -> String {
+        env::temp_dir().join(format!("i32_bfp_{}_{}", name, process::id())).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn queries_are_checked_for_the_chosen_domain() {
+        let mut runtime = Runtime::new();
+        for executor in [".executor compiled", ".executor interpreted"] {
+            runtime.handle_line(executor);
+            runtime.handle_line(".range -3 3");
+            assert_eq!(runtime.execute_query(parse_query("x * x < 9")).unwrap(), Some(vec![-3]));
+            assert_eq!(runtime.execute_query(parse_query("x * x < 9 + y * 0")).unwrap(), Some(vec![-3, -3]));
+            runtime.handle_line(".range -2 2");
+            assert_eq!(runtime.execute_query(parse_query("x * x < 9")).unwrap(), None);
+            runtime.handle_line(".values 5, -7, 3");
+            assert_eq!(runtime.execute_query(parse_query("x <> 3")).unwrap(), Some(vec![3]));
+            assert_eq!(runtime.execute_query(parse_query("x > 0")).unwrap(), Some(vec![-7]));
+            runtime.handle_line(".edges 0");
+            assert_eq!(runtime.execute_query(parse_query("x + 1 > x")).unwrap(), Some(vec![i32::MAX]));
+            assert_eq!(runtime.execute_query(parse_query("x <> 1073741825")).unwrap(), Some(vec![1 << 30 | 1]));
+            runtime.handle_line(".mode fast");
+            assert_eq!(runtime.execute_query(parse_query("x <> 1")).unwrap(), Some(vec![1]));
+        }
+        // Empty ranges are rejected and the domain is kept
+        runtime.handle_line(".range 3 2");
+        assert_eq!(runtime.execute_query(parse_query("x <> 0")).unwrap(), Some(vec![0]));
+    }
+
+    #[test]
+    fn counterexamples_are_counted_in_ranges() {
+        let mut runtime = Runtime::new();
+        for (executor, threads) in [(".executor compiled", ".threads 1"), (".executor compiled", ".threads 3"), (".executor interpreted", ".threads 3")] {
+            runtime.handle_line(executor);
+            runtime.handle_line(threads);
+            // The ranges span several blocks
+            runtime.handle_line(".range -1500000 1500000");
+            let tally = runtime.count_query(parse_query("x >= 0 - 1200000"), 1).unwrap();
+            assert_eq!((tally.failing, tally.errors, tally.ranges), (300000, 0, 1));
+            assert_eq!(tally.listed().iter().map(|range| (range.from, range.to)).collect_vec(), [(-1500000, -1200001)]);
+
+            runtime.handle_line(".range -10 10");
+            let tally = runtime.count_query(parse_query("x < 0 - 5 + y * 0"), 2).unwrap();
+            assert_eq!((tally.failing, tally.ranges), (16 * 21, 16));
+            assert_eq!(tally.listed()[0].format(&["x".to_string(), "y".to_string()]), "x = -5, y in [-10, 10]");
+            let tally = runtime.count_query(parse_query("x % 4 <> 1 + 10 / (x - 3)"), 3).unwrap();
+            assert_eq!((tally.failing, tally.errors, tally.ranges), (4, 1, 4));
+            assert_eq!(tally.listed().iter().map(|range| range.from).collect_vec(), [-4, -1, 7]);
+            assert_eq!(tally.first_error.map(|error| error.to_string()).as_deref(), Some("Evaluation failed for x = 3: division by zero"));
+            let tally = runtime.count_query(parse_query("x < 20"), 3).unwrap();
+            assert_eq!((tally.failing, tally.ranges), (0, 0));
+            assert!(tally.listed().is_empty());
+            let tally = runtime.count_query(parse_query("1 = 2"), 3).unwrap();
+            assert_eq!((tally.failing, tally.ranges), (1, 1));
+        }
+    }
+
+    #[test]
+    fn counterexamples_are_explained() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line("f(x) := x + 1");
+        runtime.handle_line("g(x) := if x < 1 then 0 else g(x - 1) + 1");
+        let explain = |runtime: &Runtime, query: &str, assignment: &[(&str, i32)]| {
+            let assignment = assignment.iter().map(|(var, value)| (var.to_string(), *value)).collect_vec();
+            runtime.explain(&parse_query(query), &assignment).map(|trace| trace.to_string())
+        };
+        assert_eq!(explain(&runtime, "f(x) > x", &[("x", i32::MAX)]).unwrap(), "\
+            f(x) > x -> 0\n  \
+              f(x) -> -2147483648\n    \
+                f(x = 2147483647) := x + 1 -> -2147483648 (overflow, the exact result is 2147483648)\n");
+        assert_eq!(explain(&runtime, "if y < x then 10 / (x - 2) else y", &[("y", 1), ("x", 2)]).unwrap(), "\
+            if y < x then 10 / (x - 2) else y -> error: division by zero\n  \
+              y < x -> 1\n  \
+              10 / (x - 2) -> error: division by zero\n    \
+                x - 2 -> 0\n");
+        // Only the bodies of the first calls are shown
+        let trace = explain(&runtime, "g(x) = 150", &[("x", 150)]).unwrap();
+        assert!(trace.starts_with("g(x) = 150 -> 1\n  g(x) -> 150\n    g(x = 150) := if x < 1 then 0 else g(x - 1) + 1 -> 150\n"));
+        assert_eq!(trace.matches(":=").count(), 100);
+        assert_eq!(trace.matches("(body not shown)").count(), 1);
+        assert_eq!(explain(&runtime, "x = y", &[("x", 1)]).map_err(|error| error.to_string()), Err("The value of y is missing.".to_string()));
+        assert_eq!(explain(&runtime, "x = 1", &[("x", 1), ("z", 1)]).map_err(|error| error.to_string()), Err("z is not a free variable of the query.".to_string()));
+    }
+
+    #[test]
+    fn random_values_depend_on_the_seed() {
+        let values = match Domain::random(1000, 42) {
+            Domain::Values(values) => values,
+            Domain::Range(_, _) => panic!("Random values are a list"),
+        };
+        assert_eq!(values.iter().unique().count(), 1000);
+        assert_eq!(Domain::random(1000, 42), Domain::Values(values.clone()));
+        assert_ne!(Domain::random(1000, 43), Domain::Values(values.clone()));
+        match Domain::edges(1000, 42) {
+            Domain::Values(edges) => {
+                assert_eq!(&edges[..7], [i32::MIN, i32::MAX, 0, 1, -1, i32::MIN + 1, i32::MAX - 1]);
+                assert_eq!(edges.iter().unique().count(), edges.len());
+                assert!(edges.contains(&(1 << 16)) && edges.contains(&(-(1 << 16) - 1)));
+            }
+            Domain::Range(_, _) => panic!("Edge values are a list"),
+        }
+
+        let mut runtime = Runtime::new();
+        runtime.handle_line(".random 1000 42");
+        assert_eq!(runtime.execute_query(parse_query("x < 0")).unwrap(), values.iter().find(|value| **value >= 0).map(|value| vec![*value]));
+        // Each variable gets its own values
+        let sampling = Sampling { count: 1000, seed: 42, edges: false };
+        assert_eq!(sampling.domain(0), Domain::Values(values.clone()));
+        assert_ne!(sampling.domain(1), sampling.domain(0));
+        assert_eq!(sampling.domain(1), sampling.domain(1));
+        assert_eq!(runtime.execute_query(parse_query("x <> y")).unwrap(), None);
+        let y = match sampling.domain(1) {
+            Domain::Values(values) => values[0],
+            Domain::Range(_, _) => panic!("Random values are a list"),
+        };
+        assert_eq!(runtime.execute_query(parse_query(&format!("(x <> {}) + (y <> {})", values[0], y))).unwrap(), Some(vec![values[0], y]));
+    }
+
+    #[test]
+    fn free_variables_get_independent_samples() {
+        let sampled = |sampling: Sampling, variable| match sampling.domain(variable) {
+            Domain::Values(values) => values,
+            Domain::Range(_, _) => panic!("Samples are a list"),
+        };
+        let random = Sampling { count: 100, seed: 7, edges: false };
+        let (x, y) = (sampled(random, 0), sampled(random, 1));
+        assert!(x.iter().all(|value| !y.contains(value)), "x and y share values: {:?} {:?}", x, y);
+        let edges = Sampling { count: 100, seed: 7, edges: true };
+        let (x_edges, y_edges) = (sampled(edges, 0), sampled(edges, 1));
+        let edge_count = x_edges.len() - 100;
+        assert_eq!(x_edges[..edge_count], y_edges[..edge_count]);
+        assert!(x_edges[edge_count..].iter().all(|value| !y_edges[edge_count..].contains(value)));
+
+        // Under the same seed, each variable gets the same values again, which differ from the others
+        for _ in 0..2 {
+            let mut runtime = Runtime::new();
+            runtime.handle_line(".random 100 7");
+            assert_eq!(runtime.execute_query(parse_query("x <> y")).unwrap(), None);
+            assert_eq!(runtime.execute_query(parse_query(&format!("(x <> {}) + (y <> {})", x[99], y[42]))).unwrap(), Some(vec![x[99], y[42]]));
+        }
+    }
+
+    #[test]
+    fn conditional_is_compiled_correctly() {
+        check_equiv("if x < 0 then 0 - x else x", vec![i32::MIN, -1, 0, 1, i32::MAX]);
+        check_equiv("if x then 1 else 2", vec![i32::MIN, -1, 0, 1, i32::MAX]);
+        check_equiv("if x > 0 then 1 else if x < 0 then 0 - 1 else 0", vec![i32::MIN, -1, 0, 1, i32::MAX]);
+        check_equiv("x + (if x > 10 then x * 2 else x / 2) * (if x = 1 then 3 else x)", vec![i32::MIN, -1, 0, 1, 11, i32::MAX]);
+        check_equiv(&nested("x + ", "if x > 5 then x else 5", 20), vec![i32::MIN, -1, 0, 1, 6, i32::MAX]);
+    }
+
+    #[test]
+    fn only_the_taken_branch_is_evaluated() {
+        check_equiv("if x = 0 then 0 else 100 / x", vec![-1, 0, 1]);
+        check_equiv("if x <> 0 then 100 % x else 7", vec![-1, 0, 1]);
+    }
+
+    #[test]
+    fn terminating_recursion_is_compiled_correctly() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("fac(n) := if n <= 1 then 1 else n * fac(n - 1)", &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("gcd(a, b) := if b = 0 then a else gcd(b, a % b)", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("fac(x)", vec![-1, 0, 1, 5, 12, 13], &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("gcd(x, 1071) + gcd(462, x)", vec![0, 1, 462, 1071, 1234567, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+    }
+
+    #[test]
+    fn mutual_recursion_is_compiled_correctly() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("even(n) := if n = 0 then 1 else odd(n - 1)", &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("odd(n) := if n = 0 then 0 else even(n - 1)", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("even(x) * 10 + odd(x)", vec![0, 1, 2, 7, 100], &mut compiled_executor, &mut interpreted_executor);
+    }
+
+    #[test]
+    fn recursion_limit_is_reported() {
+        // Test threads have a smaller stack than the main thread of the REPL, which is not enough for
+        // the default call depth of unoptimized builds.
+        std::thread::Builder::new()
+            .stack_size(8 * 1024 * 1024)
+            .spawn(check_recursion_limit)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    fn check_recursion_limit() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x) := f(x + 1) + 1", &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("g(x) := x + f(x)", &mut compiled_executor, &mut interpreted_executor);
+        let compiled = compiled_executor.get_query_runable(parse_query("g(x) > 0")).unwrap();
+        assert_eq!(compiled(&[1]).map_err(|error| error.to_string()), Err("recursion limit exceeded in f".to_string()));
+        // The compiled code must still be usable after a trap
+        assert_eq!(compiled(&[1]).map_err(|error| error.to_string()), Err("recursion limit exceeded in f".to_string()));
+        let interpreted = interpreted_executor.get_query_runable(parse_query("g(x) > 0")).unwrap();
+        assert_eq!(interpreted(&[1]).map_err(|error| error.to_string()), Err("recursion limit exceeded in f".to_string()));
+    }
+
+    #[test]
+    fn recursion_limit_is_configurable() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        compiled_executor.set_max_call_depth(5);
+        interpreted_executor.set_max_call_depth(5);
+        handle_fn_def("fac(n) := if n <= 1 then 1 else n * fac(n - 1)", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("fac(x)", vec![1, 4, 5, 6, 20], &mut compiled_executor, &mut interpreted_executor);
+        let compiled = compiled_executor.get_query_runable(parse_query("fac(x)")).unwrap();
+        assert_eq!(compiled(&[5]), Ok(120));
+        assert_eq!(compiled(&[6]).map_err(|error| error.to_string()), Err("recursion limit exceeded in fac".to_string()));
+    }
+
+    #[test]
+    fn recursion_limit_is_reported_by_runtime() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line(".mode fast");
+        runtime.handle_line("f(x) := if x = 0 then 1 else f(x - 1)");
+        runtime.handle_line(".depth 100");
+        assert_eq!(runtime.execute_query(parse_query("f(x) = 1")).map_err(|error| error.to_string()), Err("Evaluation failed for x = -2147483648: recursion limit exceeded in f".to_string()));
+        runtime.handle_line(".executor interpreted");
+        assert_eq!(runtime.execute_query(parse_query("f(x) = 1")).map_err(|error| error.to_string()), Err("Evaluation failed for x = -2147483648: recursion limit exceeded in f".to_string()));
+    }
+
+    #[test]
+    fn division_by_zero_is_reported() {
+        check_equiv("x / (x - x)", vec![i32::MIN, -1, 0, 1, i32::MAX]);
+        check_equiv("1 + x % 0", vec![i32::MIN, -1, 0, 1, i32::MAX]);
+        let mut compiled_executor = CompiledExecutor::new();
+        let compiled = compiled_executor.get_query_runable(parse_query("10 / x")).unwrap();
+        assert_eq!(compiled(&[0]).map_err(|error| error.to_string()), Err("division by zero".to_string()));
+        assert_eq!(compiled(&[3]), Ok(3));
+    }
+
+    #[test]
+    fn division_overflow_is_reported() {
+        check_equiv("x / (0 - 1)", vec![i32::MIN, -1, 0, 1, i32::MAX]);
+        check_equiv("x % (0 - 1)", vec![i32::MIN, -1, 0, 1, i32::MAX]);
+        let mut interpreted_executor = InterpretedExecutor::new();
+        let interpreted = interpreted_executor.get_query_runable(parse_query("x / (0 - 1)")).unwrap();
+        assert_eq!(interpreted(&[i32::MIN]).map_err(|error| error.to_string()), Err("division overflow".to_string()));
+    }
+
+    #[test]
+    fn division_by_zero_in_function_is_reported() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x, y) := 1 + x / y", &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("g(x) := x + f(x, x - 1) * 2", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("g(x) + 1", vec![i32::MIN, -1, 0, 1, 2, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+        let compiled = compiled_executor.get_query_runable(parse_query("g(x)")).unwrap();
+        assert_eq!(compiled(&[1]).map_err(|error| error.to_string()), Err("division by zero in f".to_string()));
+        assert_eq!(compiled(&[2]), Ok(8));
+    }
+
+    #[test]
+    fn division_by_zero_is_reported_by_runtime() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line(".mode fast");
+        assert_eq!(runtime.execute_query(parse_query("x / x = 1")).map_err(|error| error.to_string()), Err("Evaluation failed for x = 0: division by zero".to_string()));
+        runtime.handle_line(".executor interpreted");
+        assert_eq!(runtime.execute_query(parse_query("x / x = 1")).map_err(|error| error.to_string()), Err("Evaluation failed for x = 0: division by zero".to_string()));
+    }
+
+    #[test]
+    fn compiled_code_can_be_called_as_c_function() {
+        let code_repository = CodeRepository::new();
+        let code_repository = code_repository.lock().unwrap();
+        let mut ctx = CompilationContext::new(&code_repository);
+        for var in &["a", "b", "c", "d", "e", "f", "g"] {
+            ctx.add_parameter(var.to_string());
+        }
+        let runable = ctx.compile(&parse_query("a + b * 2 + c * 3 + d * 4 + e * 5 + f * 6 + g * 7")).unwrap();
+        type Function = extern "C" fn(*mut ExecutionContext, i32, i32, i32, i32, i32, i32, i32) -> i32;
+        let function: Function = unsafe { std::mem::transmute(runable.ptr()) };
+        let mut execution_context = ExecutionContext::new(DEFAULT_MAX_CALL_DEPTH, &NEVER_INTERRUPTED);
+        assert_eq!(function(&mut execution_context, 1, 2, 3, 4, 5, 6, 7), 140);
+        assert_eq!(runable.call(&mut execution_context, &[1, 2, 3, 4, 5, 6, 7]), 140);
+    }
+
+    #[test]
+    fn redefining_functions_does_not_grow_memory() {
+        let mut compiled_executor = CompiledExecutor::new();
+        compiled_executor.handle_function_def(parse_function_def("g(x) := f(x) * 2")).unwrap();
+        let mut stats = Vec::new();
+        for i in 0..50 {
+            compiled_executor.handle_function_def(parse_function_def(&format!("f(x) := x + {}", i))).unwrap();
+            let runable = compiled_executor.get_query_runable(parse_query("f(x) + g(x)")).unwrap();
+            assert_eq!(runable(&[1]), Ok(3 * (1 + i)));
+            drop(runable);
+            stats.push(compiled_executor.code_stats());
+        }
+        assert!(stats[1..].iter().all(|s| *s == stats[1]), "Code size grows: {:?}", stats);
+        assert_eq!(stats[1].buffers, 3);
+    }
+
+    #[test]
+    fn compiled_callers_see_redefined_functions() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x) := x", &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("g(x) := f(x) + 1", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("g(x)", vec![i32::MIN, -1, 0, 1, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("f(x) := x * 10", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("g(x)", vec![i32::MIN, -1, 0, 1, i32::MAX], &mut compiled_executor, &mut interpreted_executor);
+        let compiled = compiled_executor.get_query_runable(parse_query("g(x)")).unwrap();
+        assert_eq!(compiled(&[1]), Ok(11));
+    }
+
+    #[test]
+    fn errors_carry_their_kind_and_span() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line("f(x, y) := x + y");
+        assert_eq!(runtime.handle_str("g(x) := f(x) + 1"), Err(Error::ArityMismatch { name: "f".to_string(), expected: 2, found: 1, caller: None, span: Some(Span { start: 8, end: 12 }) }));
+        assert_eq!(runtime.handle_str("h(x) := y + 1"), Err(Error::UndefinedVariable { name: "y".to_string(), function: Some("h".to_string()), span: Some(Span { start: 8, end: 9 }) }));
+        assert_eq!(runtime.handle_str("k(x, x) := 1"), Err(Error::DuplicateParameter { name: "x".to_string(), function: "k".to_string(), span: Some(Span { start: 5, end: 6 }) }));
+        let error = runtime.handle_str("1 + x * = 3").unwrap_err();
+        assert!(matches!(error, Error::Parse { span: Some(Span { start: 8, .. }), .. }), "{:?}", error);
+        assert_eq!(error.display_in("1 + x * = 3"), "1 + x * = 3\n        ^\nexpected atom");
+        assert!(matches!(runtime.handle_str(".depth 99999999999999999999"), Err(Error::Parse { span: Some(Span { start: 7, end: 27 }), .. })));
+
+        runtime.handle_line(".values 2");
+        let division_by_zero = |function: &str| Box::new(Error::Trap { trap: Trap::DivisionByZero, function: function.to_string() });
+        assert_eq!(runtime.handle_str("f(x, 1) = 10 / (x - 2)"), Err(Error::Evaluation { input: "x = 2".to_string(), error: division_by_zero("") }));
+        let error = runtime.handle_str("f(x, 1) = z(x)").unwrap_err();
+        assert_eq!(error, Error::Evaluation { input: "x = 2".to_string(), error: Box::new(Error::UndefinedFunction { name: "z".to_string(), span: Some(Span { start: 10, end: 14 }) }) });
+        assert_eq!(error.display_in("f(x, 1) = z(x)"), "f(x, 1) = z(x)\n          ^^^^\nEvaluation failed for x = 2: undefined function z");
+        // The span is the one of the failing call, not of the first call of the function
+        let error = runtime.handle_str("x = 3 && z(1) = 0 || z(x) = 1").unwrap_err();
+        assert_eq!(error.span(), Some(Span { start: 21, end: 25 }));
+        assert_eq!(runtime.handle_str("g(x) := f(x, x) + f(x)").unwrap_err().span(), Some(Span { start: 18, end: 22 }));
+        // Errors in the definitions of other functions have no span in the input
+        runtime.handle_line("m(x) := f(x, 1) / 0");
+        assert_eq!(runtime.handle_str("m(x) = 1"), Err(Error::Evaluation { input: "x = 2".to_string(), error: division_by_zero("m") }));
+        assert_eq!(runtime.handle_str(".range 2 1"), Err(Error::EmptyRange { from: 2, to: 1 }));
+    }
+
+    #[test]
+    fn calling_undefined_functions_is_reported() {
+        let mut compiled_executor = CompiledExecutor::new();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        handle_fn_def("f(x) := x", &mut compiled_executor, &mut interpreted_executor);
+        handle_fn_def("g(x) := f(x) + 1", &mut compiled_executor, &mut interpreted_executor);
+        check_query_equiv("g(x)", vec![0], &mut compiled_executor, &mut interpreted_executor);
+        compiled_executor.delete("f");
+        interpreted_executor.delete("f");
+        check_query_equiv("g(x)", vec![0], &mut compiled_executor, &mut interpreted_executor);
+        let compiled = compiled_executor.get_query_runable(parse_query("g(x) + h(x)")).unwrap();
+        assert_eq!(compiled(&[1]).map_err(|error| error.to_string()), Err("undefined function f".to_string()));
+        // Only calls in the query carry their span
+        assert_eq!(compiled(&[1]).unwrap_err().span(), None);
+        let interpreted = interpreted_executor.get_query_runable(parse_query("h(x) + g(x)")).unwrap();
+        assert_eq!(interpreted(&[1]).map_err(|error| error.to_string()), Err("undefined function h".to_string()));
+        assert_eq!(interpreted(&[1]).unwrap_err().span(), Some(Span { start: 0, end: 4 }));
+        drop(compiled);
+        let compiled = compiled_executor.get_query_runable(parse_query("h(x) + g(x)")).unwrap();
+        assert_eq!(compiled(&[1]), interpreted(&[1]));
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        use crate::ast::Expr::{Div, Mul, Number, Sub, Var};
+        let x = || Box::new(Var("x".to_string(), SourceSpan::default()));
+        assert_eq!(parse_query("x * 10 * 4").optimize(), Mul(x(), Box::new(Number(40))));
+        assert_eq!(parse_query("(x - x) + 2 * 3").optimize(), Number(6));
+        assert_eq!(parse_query("(x + 0) * 1 / 1").optimize(), *x());
+        assert_eq!(parse_query("if 1 + 2 < 4 then x else 0").optimize(), *x());
+        assert_eq!(parse_query("2147483647 + 1").optimize(), Number(i32::MIN));
+        // Expressions which may fail are kept
+        assert_eq!(parse_query("x / (x - x)").optimize(), Div(x(), Box::new(Number(0))));
+        assert_eq!(parse_query("(x / 0) * 0").optimize(), Mul(Box::new(Div(x(), Box::new(Number(0)))), Box::new(Number(0))));
+        assert!(matches!(parse_query("f(x) - f(x)").optimize(), Sub(_, _)));
+    }
+
+    #[test]
+    fn optimization_preserves_semantics() {
+        let queries = [
+            "x * 10 * 4", "x - x", "x + 0 - 0", "0 - x", "x - 2147483647 - 1", "x * 0", "x * 1", "x / 1", "x % 1",
+            "x / 0", "x % 0", "(x - x) / 0", "0 / x", "x / (0 - 1)", "x % (0 - 1)", "(x / (0 - 1)) * 0",
+            "x * 0 + f(x) * 0", "f(x) - f(x)", "x = x", "x <> x", "x >= x", "x < x", "3 * (x + 5) * 7",
+            "if x - x then f(x) else x + 1 + 2", "if 2 > 1 then x / x else 1 / 0", "(x % 10 + 4) * 0 + 3 % 2",
+        ];
+        let mut original_executor = InterpretedExecutor::new();
+        let mut optimized_executor = InterpretedExecutor::new();
+        let definition = parse_function_def("f(x) := if x = 3 then 1 / 0 else x * 2");
+        original_executor.handle_function_def(definition.clone()).unwrap();
+        optimized_executor.handle_function_def(definition).unwrap();
+        for query in &queries {
+            let expr = parse_query(query);
+            let original = original_executor.get_query_runable(expr.clone()).unwrap();
+            let optimized = optimized_executor.get_query_runable(expr.optimize()).unwrap();
+            for val in vec![i32::MIN, i32::MIN + 1, -3, -1, 0, 1, 3, i32::MAX - 1, i32::MAX] {
+                assert_eq!(original(&[val]), optimized(&[val]), "Optimizing {} changed the result for input {}", query, val);
+            }
+            drop(original);
+            drop(optimized);
+            check_equiv(query, vec![i32::MIN, -1, 0, 1, i32::MAX]);
+        }
+    }
+
+    const CONSTANT_DIVISORS: [i32; 16] = [1, 2, 3, 5, 7, 10, 16, 641, 1 << 30, i32::MAX, -2, -3, -8, -10, -641, i32::MIN];
+
+    #[test]
+    fn operations_with_constants_are_compiled_correctly() {
+        let mut inputs = vec![i32::MIN, i32::MIN + 1, -1, 0, 1, i32::MAX - 1, i32::MAX];
+        inputs.extend((i32::MIN..=i32::MAX).step_by(262_139));
+        for divisor in CONSTANT_DIVISORS.iter().chain(&[0, -1, 4, 9, 6]) {
+            check_equiv(&format!("x * {}", divisor), inputs.clone());
+            check_equiv(&format!("x / {}", divisor), inputs.clone());
+            check_equiv(&format!("x % {}", divisor), inputs.clone());
+        }
+    }
+
+    // Takes about 17 minutes on a single core, the inputs are split among all cores
+    #[test]
+    fn division_by_constants_is_exact_for_all_inputs() {
+        let mut runtime = Runtime::new();
+        runtime.handle_line(".mode proof");
+        for vectorize in [false, true] {
+            runtime.set_vectorize(vectorize);
+            for divisor in CONSTANT_DIVISORS {
+                // The compiler does not know the divisor of the clamp, so it divides with idiv, which
+                // computes checked_div and checked_rem like the interpreter
+                let query = format!("x / {d} = x / clamp(x, {d}, {d}) && x % {d} = x % clamp(x, {d}, {d})", d = divisor);
+                assert_eq!(runtime.execute_query(parse_query(&query)), Ok(None), "{}", query);
+            }
+        }
+    }
+
+    #[test]
+    fn vector_scan_finds_the_first_failing_block() {
+        check_vector_scan("x * x - 7 * x + 10", &[], -300, 300);
+        check_vector_scan("x <> 10", &[], 0, 9);
+        check_vector_scan("x <> 10", &[], 0, 10);
+        check_vector_scan("x <> 2147483647", &[], i32::MAX - 20, i32::MAX);
+        check_vector_scan("x <> 5", &[], i32::MAX - 20, i32::MAX);
+        check_vector_scan("x + 3 > x", &[], i32::MAX - 20, i32::MAX);
+        check_vector_scan("(x >= 5) + (x <= 0 - 5) + (x = 0) + (x < 0 - 100) * 2 - (x > 50)", &[], -200, 200);
+        check_vector_scan("x * y <> 12", &[3], -100, 100);
+        check_vector_scan("x * y <> 12", &[0], -100, 100);
+        check_vector_scan("y - x * z <> 4", &[1000, 7], -200, 200);
+        check_vector_scan(&nested("1 + ", "x - 40", 30), &[], -200, 200);
+        check_vector_scan(&format!("{} <> 0", nested("(x - 1) * ", "x", 20)), &[], -50, 50);
+    }
+
+    #[test]
+    fn vector_scan_handles_divisions() {
+        check_vector_scan("100 / x", &[], -300, 300);
+        check_vector_scan("100 % x - 1", &[], -300, 300);
+        check_vector_scan("x / (x - 5) <> 7", &[], -300, 300);
+        check_vector_scan("(x + 2147483647 + 1) / (0 - 1) <> 3", &[], -20, 20);
+        check_vector_scan("x % y <> 5", &[0], -20, 20);
+        // Lanes which do not take a branch must not fail
+        check_vector_scan("if x = 0 then 1 else 100 % x + 1", &[], -300, 300);
+        check_vector_scan("if x < 0 then 100 / x else if x > 0 then 0 - 100 / x else 7", &[], -300, 300);
+        check_vector_scan("if x > 20 then 1 / (x - 30) else 1", &[], -100, 100);
+        for divisor in CONSTANT_DIVISORS.iter().chain(&[4, 9, 6]) {
+            for start in [i32::MIN, -1000, -30, 0, i32::MAX - 63] {
+                let mut interpreted_executor = InterpretedExecutor::new();
+                for op in ["/", "%"] {
+                    let expr = format!("x {} {}", op, divisor);
+                    let expected = interpreted_executor.get_query_runable(parse_query(&expr)).unwrap()(&[start + 37]).unwrap();
+                    check_vector_scan(&format!("{} <> {}", expr, expected), &[], start, start + 63);
+                }
+            }
+        }
+    }
+
+    /// Compares the block reported by the vector code with the first input for which the interpreted
+    /// query evaluates to 0 or fails.
+    fn check_vector_scan(expr: &str, fixed_args: &[i32], from: i32, to: i32) {
+        if !vector_compiler::is_supported() {
+            return;
+        }
+        let query = parse_query(expr);
+        let mut used_vars = query.used_variables();
+        let loop_var = used_vars.pop().unwrap();
+        let mut interpreted_executor = InterpretedExecutor::new();
+        let interpreted = interpreted_executor.get_query_runable(query.clone()).unwrap();
+        let mut ctx = VectorCompilationContext::new();
+        for used_var in used_vars.iter().chain(std::iter::once(&loop_var)) {
+            ctx.add_parameter(used_var.to_string());
+        }
+        let runable = ctx.compile_scan(&query, &loop_var).unwrap();
+        let expected = (from..=to)
+            .find(|value| {
+                let mut args = fixed_args.to_vec();
+                args.push(*value);
+                matches!(interpreted(&args), Ok(0) | Err(_))
+            })
+            .map_or(SCAN_NOT_FOUND, |value| from as i64 + (value as i64 - from as i64) / vector_compiler::LANES * vector_compiler::LANES);
+        let mut args = fixed_args.to_vec();
+        args.push(from);
+        args.push(to);
+        let result = runable.call_wide(&mut ExecutionContext::new(DEFAULT_MAX_CALL_DEPTH, &NEVER_INTERRUPTED), &args);
+        assert_eq!(result, expected, "The vector scan of {} was not correct for {:?} and {}..={}.", expr, fixed_args, from, to);
+    }
+
+    fn nested(prefix: &str, innermost: &str, depth: usize) -> String {
+        (0..depth).fold(innermost.to_string(), |acc, _| format!("{}({})", prefix, acc))
+    }
+
     fn check_equiv(expr: &str, test_for: Vec<i32>) {
         let mut compiled_executor = CompiledExecutor::new();
         let mut interpreted_executor = InterpretedExecutor::new();
@@ -132,30 +1139,33 @@ mod tests {
     }
 
     fn handle_fn_def(expr: &str, compiled_executor: &mut CompiledExecutor, interpreted_executor: &mut InterpretedExecutor) {
-        let parsed = parse(expr).unwrap();
-
-        let defintion = match parsed {
-            crate::ast::Action::FunctionDef(defintion) => defintion,
-            _ => panic!("Expected query")
-        };
+        let defintion = parse_function_def(expr);
         compiled_executor.handle_function_def(defintion.clone()).unwrap();
         interpreted_executor.handle_function_def(defintion).unwrap();
     }
 
     fn check_query_equiv(expr: &str, test_for: Vec<i32>, compiled_executor: &mut CompiledExecutor, interpreted_executor: &mut InterpretedExecutor) {
-        let parsed = parse(expr).unwrap();
-
-        let expr = match parsed {
-            crate::ast::Action::Query(expr) => expr,
-            _ => panic!("Expected query")
-        };
-
+        let expr = parse_query(expr);
         let compiled = compiled_executor.get_query_runable(expr.clone()).unwrap();
         let interpreted = interpreted_executor.get_query_runable(expr).unwrap();
         for val in test_for {
-            let res1 = compiled(val);
-            let res2 = interpreted(val);
-            assert_eq!(res1, res2, "The values were not equal for input {}. Compiled: {}, Interpreted: {}.", val, res1, res2)
+            let res1 = compiled(&[val]);
+            let res2 = interpreted(&[val]);
+            assert_eq!(res1, res2, "The values were not equal for input {}. Compiled: {:?}, Interpreted: {:?}.", val, res1, res2)
+        }
+    }
+
+    fn parse_function_def(expr: &str) -> crate::ast::FunctionDef {
+        match parse(expr).unwrap() {
+            crate::ast::Action::FunctionDef(defintion) => defintion,
+            _ => panic!("Expected function definition")
         }
     }
-}
\ No newline at end of file
+
+    fn parse_query(expr: &str) -> crate::ast::Expr {
+        match parse(expr).unwrap() {
+            crate::ast::Action::Query(expr) => expr,
+            _ => panic!("Expected query")
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::num::Wrapping;
+
+use crate::ast::Expr;
+
+/// Simplifies expressions before they are executed. The optimized expression evaluates to the same
+/// value as the original one for all inputs and fails for exactly the same inputs (e.g. division by zero).
+pub trait Optimizable {
+    fn optimize(&self) -> Expr;
+}
+
+impl Optimizable for Expr {
+    fn optimize(&self) -> Expr {
+        match self {
+            Expr::Number(_) | Expr::Var(_, _) => self.clone(),
+            Expr::FunctionCall(name, args, source) => Expr::FunctionCall(name.clone(), args.iter().map(Optimizable::optimize).collect(), *source),
+            Expr::BuiltinCall(builtin, args, source) => {
+                let args: Vec<Expr> = args.iter().map(Optimizable::optimize).collect();
+                match args.iter().map(|arg| match arg { Expr::Number(value) => Some(*value), _ => None }).collect::<Option<Vec<_>>>() {
+                    Some(values) if values.len() == builtin.arity() => Expr::Number(builtin.eval(&values)),
+                    _ => Expr::BuiltinCall(*builtin, args, *source),
+                }
+            }
+            Expr::Neg(operand) => match operand.optimize() {
+                Expr::Number(value) => Expr::Number(value.wrapping_neg()),
+                Expr::Neg(inner) => *inner,
+                operand => Expr::Neg(Box::new(operand)),
+            },
+            Expr::Not(operand) => match operand.optimize() {
+                Expr::Number(value) => Expr::Number((value == 0) as i32),
+                operand => Expr::Not(Box::new(operand)),
+            },
+            Expr::BitNot(operand) => match operand.optimize() {
+                Expr::Number(value) => Expr::Number(!value),
+                Expr::BitNot(inner) => *inner,
+                operand => Expr::BitNot(Box::new(operand)),
+            },
+            Expr::Add(lhs, rhs) => optimize_add(lhs.optimize(), rhs.optimize()),
+            Expr::Sub(lhs, rhs) => optimize_sub(lhs.optimize(), rhs.optimize()),
+            Expr::Mul(lhs, rhs) => optimize_mul(lhs.optimize(), rhs.optimize()),
+            Expr::Div(lhs, rhs) => optimize_div(lhs.optimize(), rhs.optimize()),
+            Expr::Rem(lhs, rhs) => optimize_rem(lhs.optimize(), rhs.optimize()),
+            Expr::BitAnd(lhs, rhs) => optimize_bitwise(lhs.optimize(), rhs.optimize(), Expr::BitAnd, |a, b| a & b),
+            Expr::BitOr(lhs, rhs) => optimize_bitwise(lhs.optimize(), rhs.optimize(), Expr::BitOr, |a, b| a | b),
+            Expr::BitXor(lhs, rhs) => optimize_bitwise(lhs.optimize(), rhs.optimize(), Expr::BitXor, |a, b| a ^ b),
+            Expr::Shl(lhs, rhs) => optimize_bitwise(lhs.optimize(), rhs.optimize(), Expr::Shl, |a, b| a.wrapping_shl(b as u32)),
+            Expr::Sar(lhs, rhs) => optimize_bitwise(lhs.optimize(), rhs.optimize(), Expr::Sar, |a, b| a.wrapping_shr(b as u32)),
+            Expr::Shr(lhs, rhs) => optimize_bitwise(lhs.optimize(), rhs.optimize(), Expr::Shr, |a, b| (a as u32).wrapping_shr(b as u32) as i32),
+            Expr::Eq(lhs, rhs) => optimize_relation(lhs.optimize(), rhs.optimize(), Expr::Eq, |a, b| a == b),
+            Expr::Neq(lhs, rhs) => optimize_relation(lhs.optimize(), rhs.optimize(), Expr::Neq, |a, b| a != b),
+            Expr::Gt(lhs, rhs) => optimize_relation(lhs.optimize(), rhs.optimize(), Expr::Gt, |a, b| a > b),
+            Expr::Lt(lhs, rhs) => optimize_relation(lhs.optimize(), rhs.optimize(), Expr::Lt, |a, b| a < b),
+            Expr::Gte(lhs, rhs) => optimize_relation(lhs.optimize(), rhs.optimize(), Expr::Gte, |a, b| a >= b),
+            Expr::Lte(lhs, rhs) => optimize_relation(lhs.optimize(), rhs.optimize(), Expr::Lte, |a, b| a <= b),
+            Expr::And(lhs, rhs) => optimize_short_circuit(lhs.optimize(), rhs.optimize(), self.short_circuit().unwrap(), Expr::And),
+            Expr::Or(lhs, rhs) => optimize_short_circuit(lhs.optimize(), rhs.optimize(), self.short_circuit().unwrap(), Expr::Or),
+            Expr::Implies(lhs, rhs) => optimize_short_circuit(lhs.optimize(), rhs.optimize(), self.short_circuit().unwrap(), Expr::Implies),
+            Expr::If(condition, then_expr, else_expr) => match condition.optimize() {
+                Expr::Number(0) => else_expr.optimize(),
+                Expr::Number(_) => then_expr.optimize(),
+                condition => Expr::If(Box::new(condition), Box::new(then_expr.optimize()), Box::new(else_expr.optimize())),
+            },
+        }
+    }
+}
+
+/// An expression is pure if its evaluation cannot fail. Only pure expressions may be removed.
+fn is_pure(expr: &Expr) -> bool {
+    match expr {
+        Expr::FunctionCall(_, _, _) => false,
+        Expr::Div(_, rhs) | Expr::Rem(_, rhs) if !matches!(**rhs, Expr::Number(divisor) if divisor != 0 && divisor != -1) => false,
+        _ => expr.sub_expressions().into_iter().all(is_pure),
+    }
+}
+
+fn optimize_add(lhs: Expr, rhs: Expr) -> Expr {
+    match (lhs, rhs) {
+        (Expr::Number(a), Expr::Number(b)) => Expr::Number((Wrapping(a) + Wrapping(b)).0),
+        // Constants are moved to the right, so they can be combined. A constant has no side effects,
+        // therefore the order of evaluation does not matter.
+        (Expr::Number(a), rhs) => optimize_add(rhs, Expr::Number(a)),
+        (lhs, Expr::Number(0)) => lhs,
+        (Expr::Add(lhs, inner), Expr::Number(b)) => match *inner {
+            Expr::Number(a) => optimize_add(*lhs, Expr::Number((Wrapping(a) + Wrapping(b)).0)),
+            inner => Expr::Add(Box::new(Expr::Add(lhs, Box::new(inner))), Box::new(Expr::Number(b))),
+        },
+        (lhs, rhs) => Expr::Add(Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+fn optimize_sub(lhs: Expr, rhs: Expr) -> Expr {
+    match (lhs, rhs) {
+        (Expr::Number(a), Expr::Number(b)) => Expr::Number((Wrapping(a) - Wrapping(b)).0),
+        // x - c = x + (-c) holds for wrapping arithmetic, including c = INT_MIN
+        (lhs, Expr::Number(b)) => optimize_add(lhs, Expr::Number((-Wrapping(b)).0)),
+        (lhs, rhs) if lhs == rhs && is_pure(&lhs) => Expr::Number(0),
+        (lhs, rhs) => Expr::Sub(Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+fn optimize_mul(lhs: Expr, rhs: Expr) -> Expr {
+    match (lhs, rhs) {
+        (Expr::Number(a), Expr::Number(b)) => Expr::Number((Wrapping(a) * Wrapping(b)).0),
+        (Expr::Number(a), rhs) => optimize_mul(rhs, Expr::Number(a)),
+        (lhs, Expr::Number(1)) => lhs,
+        (lhs, Expr::Number(0)) if is_pure(&lhs) => Expr::Number(0),
+        (Expr::Mul(lhs, inner), Expr::Number(b)) => match *inner {
+            Expr::Number(a) => optimize_mul(*lhs, Expr::Number((Wrapping(a) * Wrapping(b)).0)),
+            inner => Expr::Mul(Box::new(Expr::Mul(lhs, Box::new(inner))), Box::new(Expr::Number(b))),
+        },
+        (lhs, rhs) => Expr::Mul(Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+fn optimize_div(lhs: Expr, rhs: Expr) -> Expr {
+    match (lhs, rhs) {
+        // Divisions which fail are kept, so they fail at runtime
+        (Expr::Number(a), Expr::Number(b)) if a.checked_div(b).is_some() => Expr::Number(a / b),
+        (lhs, Expr::Number(1)) => lhs,
+        (lhs, rhs) => Expr::Div(Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+fn optimize_rem(lhs: Expr, rhs: Expr) -> Expr {
+    match (lhs, rhs) {
+        (Expr::Number(a), Expr::Number(b)) if a.checked_rem(b).is_some() => Expr::Number(a % b),
+        (lhs, Expr::Number(1)) if is_pure(&lhs) => Expr::Number(0),
+        (lhs, rhs) => Expr::Rem(Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+fn optimize_bitwise(lhs: Expr, rhs: Expr, operator: fn(Box<Expr>, Box<Expr>) -> Expr, eval: fn(i32, i32) -> i32) -> Expr {
+    match (lhs, rhs) {
+        (Expr::Number(a), Expr::Number(b)) => Expr::Number(eval(a, b)),
+        (lhs, rhs) => operator(Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+fn optimize_relation(lhs: Expr, rhs: Expr, relation: fn(Box<Expr>, Box<Expr>) -> Expr, holds: fn(i32, i32) -> bool) -> Expr {
+    match (lhs, rhs) {
+        (Expr::Number(a), Expr::Number(b)) => Expr::Number(holds(a, b) as i32),
+        // Both sides evaluate to the same value
+        (lhs, rhs) if lhs == rhs && is_pure(&lhs) => Expr::Number(holds(0, 0) as i32),
+        (lhs, rhs) => relation(Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+/// A constant left operand decides whether the right operand is evaluated, see `Expr::short_circuit`.
+fn optimize_short_circuit(lhs: Expr, rhs: Expr, (skip_if, skipped): (bool, i32), connective: fn(Box<Expr>, Box<Expr>) -> Expr) -> Expr {
+    match lhs {
+        Expr::Number(a) if (a != 0) == skip_if => Expr::Number(skipped),
+        Expr::Number(_) => optimize_relation(rhs, Expr::Number(0), Expr::Neq, |a, b| a != b),
+        lhs => connective(Box::new(lhs), Box::new(rhs)),
+    }
+}
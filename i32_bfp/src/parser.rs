@@ -1,28 +1,77 @@
 extern crate pest;
 
-use std::num::ParseIntError;
+use std::{num::ParseIntError, str::FromStr, sync::OnceLock};
 
-use crate::ast;
-use pest::{iterators::Pairs, Parser};
+use crate::{ast, builtins::Builtin, error::{Error, Span}};
+use pest::{error::{ErrorVariant, InputLocation}, iterators::{Pair, Pairs}, pratt_parser::{Assoc, Op, PrattParser}, Parser};
 
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
 struct IdentParser;
 
-pub fn parse(input: &str) -> Result<ast::Action, String> {
-    let mut pairs = IdentParser::parse(Rule::action, input).map_err(|e| e.to_string())?;
-    Ok(build_ast_root(&mut pairs)?)
+pub fn parse(input: &str) -> Result<ast::Action, Error> {
+    let mut pairs = IdentParser::parse(Rule::action, input).map_err(|error| {
+        let span = match error.location {
+            InputLocation::Pos(position) => Span { start: position, end: position },
+            InputLocation::Span((start, end)) => Span { start, end },
+        };
+        // Prefix operators may precede any operand, so they are not listed besides it
+        let variant = match error.variant {
+            ErrorVariant::ParsingError { positives, negatives } => ErrorVariant::ParsingError {
+                positives: positives.into_iter().filter(|rule| !matches!(rule, Rule::neg | Rule::not | Rule::bit_not)).collect(),
+                negatives,
+            },
+            variant => variant,
+        };
+        Error::Parse { message: variant.message().to_string(), span: Some(span) }
+    })?;
+    build_ast_root(&mut pairs)
 }
 
-fn build_ast_root(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Action, String> {
+/// Numbers which do not fit into the type are reported with their span.
+fn parse_number<T: FromStr<Err = ParseIntError>>(pair: &Pair<'_, Rule>) -> Result<T, Error> {
+    pair.as_str().parse().map_err(|error| number_error(pair, error))
+}
+
+/// Decimal literals have to be in the range of i32. Hexadecimal and binary literals may have up to
+/// 32 bits, which are interpreted as two's complement, and a minus negates them (wrapping).
+fn parse_literal(pair: &Pair<'_, Rule>) -> Result<i32, Error> {
+    let text = pair.as_str();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let bits = |digits: &str, radix: u32| u32::from_str_radix(digits, radix).map(|bits| if negative { (bits as i32).wrapping_neg() } else { bits as i32 });
+    if let Some(digits) = digits.strip_prefix("0x") {
+        bits(digits, 16)
+    } else if let Some(digits) = digits.strip_prefix("0b") {
+        bits(digits, 2)
+    } else {
+        text.parse()
+    }
+    .map_err(|error| number_error(pair, error))
+}
+
+fn number_error(pair: &Pair<'_, Rule>, error: ParseIntError) -> Error {
+    Error::Parse {
+        message: format!("invalid number {}: {}", pair.as_str(), error),
+        span: Some(span_of(pair)),
+    }
+}
+
+fn span_of(pair: &Pair<'_, Rule>) -> Span {
+    Span { start: pair.as_span().start(), end: pair.as_span().end() }
+}
+
+fn build_ast_root(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Action, Error> {
     let rule = pairs.next().unwrap();
-    return match rule.as_rule() {
+    match rule.as_rule() {
         Rule::action => build_ast_action(&mut rule.into_inner()),
         _ => unreachable!("Rule cannot be matched in root"),
-    };
+    }
 }
 
-fn build_ast_action(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Action, String> {
+fn build_ast_action(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Action, Error> {
     let rule = pairs.next().unwrap();
     Ok(match rule.as_rule() {
         Rule::function_def => {
@@ -34,130 +83,182 @@ fn build_ast_action(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Action, String>
     })
 }
 
-fn build_ast_function_def(pairs: &mut Pairs<'_, Rule>) -> Result<ast::FunctionDef, String> {
+fn build_ast_function_def(pairs: &mut Pairs<'_, Rule>) -> Result<ast::FunctionDef, Error> {
     let name_rule = pairs.next().unwrap();
-    let second_pair = pairs.next().unwrap();
-    let rule_to_match = second_pair.as_rule();
-    let result = match rule_to_match {
-        Rule::expr => (build_ast_expr(&mut second_pair.into_inner())?, None),
-        Rule::ID => (
-            build_ast_expr(&mut pairs.next().unwrap().into_inner())?,
-            Some(second_pair.as_str().to_string()),
-        ),
-        _ => unreachable!("Rule cannot be matched in function def"),
-    };
+    let (parameters, parameter_spans) = pairs.next().unwrap()
+        .into_inner()
+        .map(|parameter| (parameter.as_str().to_string(), ast::SourceSpan(Some(span_of(&parameter)))))
+        .unzip();
+    let body = build_ast_expr(&mut pairs.next().unwrap().into_inner())?;
 
     Ok(ast::FunctionDef {
         name: name_rule.as_str().to_string(),
-        parameter: result.1,
-        body: result.0,
+        parameters,
+        parameter_spans,
+        body,
     })
 }
 
-fn build_ast_query(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Action, String> {
+fn build_ast_query(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Action, Error> {
     let rule = pairs.next().unwrap();
     Ok(ast::Action::Query(build_ast_expr(&mut rule.into_inner())?))
 }
 
-fn build_ast_expr(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Expr, String> {
+fn build_ast_expr(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Expr, Error> {
     let rule = pairs.next().unwrap();
     Ok(match rule.as_rule() {
-        Rule::relation => build_ast_relation(&mut rule.into_inner())?,
+        Rule::operation => build_ast_operation(rule.into_inner())?,
+        Rule::conditional => build_ast_conditional(&mut rule.into_inner())?,
         _ => unreachable!("Rule cannot be matched in expr"),
     })
 }
 
-fn build_ast_relation(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Expr, String> {
-    let lhs = pairs.next().unwrap();
-    let op = pairs.next();
-
-    if op.is_none() {
-        return build_ast_addsub(&mut lhs.into_inner());
-    }
-    let rhs = pairs.next().unwrap();
-    let lhs = Box::new(build_ast_addsub(&mut lhs.into_inner())?);
-    let rhs = Box::new(build_ast_relation(&mut rhs.into_inner())?);
-    Ok(match op.unwrap().as_str() {
-        "=" => ast::Expr::Eq(lhs,rhs),
-        "<>" => ast::Expr::Neq(lhs,rhs),
-        "<" => ast::Expr::Lt(lhs,rhs),
-        ">" => ast::Expr::Gt(lhs,rhs),
-        "<=" => ast::Expr::Lte(lhs,rhs),
-        ">=" => ast::Expr::Gte(lhs,rhs),
-        _ => unreachable!("Operator cannot be matched in relation"),
-    })
+fn build_ast_conditional(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Expr, Error> {
+    let condition = build_ast_expr(&mut pairs.next().unwrap().into_inner())?;
+    let then_expr = build_ast_expr(&mut pairs.next().unwrap().into_inner())?;
+    let else_expr = build_ast_expr(&mut pairs.next().unwrap().into_inner())?;
+    Ok(ast::Expr::If(Box::new(condition), Box::new(then_expr), Box::new(else_expr)))
 }
 
-fn build_ast_addsub(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Expr, String> {
-    let lhs = pairs.next().unwrap();
-    let op = pairs.next();
-
-    if op.is_none() {
-        return build_ast_muldiv(&mut lhs.into_inner());
-    }
-    let rhs = pairs.next().unwrap();
-    Ok(match op.unwrap().as_str() {
-        "+" => ast::Expr::Add(
-            Box::new(build_ast_muldiv(&mut lhs.into_inner())?),
-            Box::new(build_ast_addsub(&mut rhs.into_inner())?),
-        ),
-        "-" => ast::Expr::Sub(
-            Box::new(build_ast_muldiv(&mut lhs.into_inner())?),
-            Box::new(build_ast_addsub(&mut rhs.into_inner())?),
-        ),
-        _ => unreachable!("Operator cannot be matched in addsub"),
+/// Operators of the same line have the same precedence, which increases from line to line. All binary
+/// operators except for the implication are left-associative, e.g. `10 - 3 - 2` is `(10 - 3) - 2`, but
+/// `a => b => c` is `a => (b => c)`. Negation binds strongest.
+fn pratt_parser() -> &'static PrattParser<Rule> {
+    static PRATT_PARSER: OnceLock<PrattParser<Rule>> = OnceLock::new();
+    PRATT_PARSER.get_or_init(|| {
+        PrattParser::new()
+            .op(Op::infix(Rule::implies, Assoc::Right))
+            .op(Op::infix(Rule::or, Assoc::Left))
+            .op(Op::infix(Rule::and, Assoc::Left))
+            .op(Op::infix(Rule::eq, Assoc::Left) | Op::infix(Rule::neq, Assoc::Left) | Op::infix(Rule::gt, Assoc::Left)
+                | Op::infix(Rule::lt, Assoc::Left) | Op::infix(Rule::gte, Assoc::Left) | Op::infix(Rule::lte, Assoc::Left))
+            .op(Op::infix(Rule::bit_or, Assoc::Left))
+            .op(Op::infix(Rule::bit_xor, Assoc::Left))
+            .op(Op::infix(Rule::bit_and, Assoc::Left))
+            .op(Op::infix(Rule::shl, Assoc::Left) | Op::infix(Rule::sar, Assoc::Left) | Op::infix(Rule::shr, Assoc::Left))
+            .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::sub, Assoc::Left))
+            .op(Op::infix(Rule::mul, Assoc::Left) | Op::infix(Rule::div, Assoc::Left) | Op::infix(Rule::rem, Assoc::Left))
+            .op(Op::prefix(Rule::neg) | Op::prefix(Rule::not) | Op::prefix(Rule::bit_not))
     })
 }
 
-fn build_ast_muldiv(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Expr, String> {
-    let lhs = pairs.next().unwrap();
-    let op = pairs.next();
-
-    if op.is_none() {
-        return build_ast_atom(&mut lhs.into_inner());
-    }
-
-    let rhs = pairs.next().unwrap();
-    let lhs =  Box::new(build_ast_atom(&mut lhs.into_inner())?);
-    let rhs = Box::new(build_ast_muldiv(&mut rhs.into_inner())?);
-    Ok(match op.unwrap().as_str() {
-        "*" => ast::Expr::Mul(lhs, rhs),
-        "/" => ast::Expr::Div(lhs, rhs),
-        "%" => ast::Expr::Rem(lhs, rhs),
-        _ => unreachable!("Operator cannot be matched in muldiv"),
-    })
+fn build_ast_operation(pairs: Pairs<'_, Rule>) -> Result<ast::Expr, Error> {
+    pratt_parser()
+        .map_primary(|atom| build_ast_atom(&mut atom.into_inner()))
+        .map_prefix(|op, operand| match op.as_rule() {
+            Rule::neg => Ok(ast::Expr::Neg(Box::new(operand?))),
+            Rule::not => Ok(ast::Expr::Not(Box::new(operand?))),
+            Rule::bit_not => Ok(ast::Expr::BitNot(Box::new(operand?))),
+            _ => unreachable!("Rule cannot be matched as prefix operator"),
+        })
+        .map_infix(|lhs, op, rhs| {
+            let (lhs, rhs) = (Box::new(lhs?), Box::new(rhs?));
+            Ok(match op.as_rule() {
+                Rule::add => ast::Expr::Add(lhs, rhs),
+                Rule::sub => ast::Expr::Sub(lhs, rhs),
+                Rule::mul => ast::Expr::Mul(lhs, rhs),
+                Rule::div => ast::Expr::Div(lhs, rhs),
+                Rule::rem => ast::Expr::Rem(lhs, rhs),
+                Rule::bit_and => ast::Expr::BitAnd(lhs, rhs),
+                Rule::bit_or => ast::Expr::BitOr(lhs, rhs),
+                Rule::bit_xor => ast::Expr::BitXor(lhs, rhs),
+                Rule::shl => ast::Expr::Shl(lhs, rhs),
+                Rule::sar => ast::Expr::Sar(lhs, rhs),
+                Rule::shr => ast::Expr::Shr(lhs, rhs),
+                Rule::eq => ast::Expr::Eq(lhs, rhs),
+                Rule::neq => ast::Expr::Neq(lhs, rhs),
+                Rule::gt => ast::Expr::Gt(lhs, rhs),
+                Rule::lt => ast::Expr::Lt(lhs, rhs),
+                Rule::gte => ast::Expr::Gte(lhs, rhs),
+                Rule::lte => ast::Expr::Lte(lhs, rhs),
+                Rule::and => ast::Expr::And(lhs, rhs),
+                Rule::or => ast::Expr::Or(lhs, rhs),
+                Rule::implies => ast::Expr::Implies(lhs, rhs),
+                _ => unreachable!("Rule cannot be matched as operator"),
+            })
+        })
+        .parse(pairs)
 }
 
-fn build_ast_atom(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Expr, String> {
+fn build_ast_atom(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Expr, Error> {
     let rule = pairs.next().unwrap();
     Ok(match rule.as_rule() {
-        Rule::NUMBER => ast::Expr::Number(rule.as_str().parse().or_else(|x: ParseIntError| Err(x.to_string()))?),
-        Rule::ID => ast::Expr::Var(rule.as_str().to_string()),
+        Rule::LITERAL => ast::Expr::Number(parse_literal(&rule)?),
+        Rule::ID => ast::Expr::Var(rule.as_str().to_string(), ast::SourceSpan(Some(span_of(&rule)))),
         Rule::expr => build_ast_expr(&mut rule.into_inner())?,
-        Rule::function_call => build_ast_function_call(&mut rule.into_inner())?,
+        Rule::function_call => build_ast_function_call(rule)?,
         _ => unreachable!("Rule cannot be matched in atom"),
     })
 }
 
-fn build_ast_function_call(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Expr, String> {
+/// The span of a call covers its arguments.
+fn build_ast_function_call(call: Pair<'_, Rule>) -> Result<ast::Expr, Error> {
+    let source = ast::SourceSpan(Some(span_of(&call)));
+    let pairs = &mut call.into_inner();
     let rule = pairs.next().unwrap();
-    let arg = match pairs.next() {
-        Some(next_rule) => Some(Box::new(build_ast_expr(&mut next_rule.into_inner())?)),
-        None => None,
-    };
-    Ok (ast::Expr::FunctionCall(rule.as_str().to_string(), arg))
+    let args = pairs.next().unwrap()
+        .into_inner()
+        .map(|arg| build_ast_expr(&mut arg.into_inner()))
+        .collect::<Result<Vec<_>, _>>()?;
+    // The runtime turns calls of shadowed built-in functions back into function calls
+    Ok(match Builtin::from_name(rule.as_str()) {
+        Some(builtin) => ast::Expr::BuiltinCall(builtin, args, source),
+        None => ast::Expr::FunctionCall(rule.as_str().to_string(), args, source),
+    })
 }
 
-fn build_ast_command(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Command, String> {
+fn build_ast_command(pairs: &mut Pairs<'_, Rule>) -> Result<ast::Command, Error> {
     let rule = pairs.next().unwrap();
     Ok(match rule.as_rule() {
         Rule::show_code_command => ast::Command::ShowCode(rule.into_inner().next().unwrap().as_str().to_string()),
         Rule::list_fn_command => ast::Command::ListFunctions(),
         Rule::delete_fn_command => ast::Command::DeleteFunction(rule.into_inner().next().unwrap().as_str().to_string()),
+        Rule::shadow_command => ast::Command::ShadowBuiltin(rule.into_inner().next().unwrap().as_str().to_string()),
         Rule::mode_command => ast::Command::SwitchMode(rule.into_inner().next().unwrap().as_str().to_string()),
+        Rule::range_command => {
+            let mut bounds = rule.into_inner().map(|bound| parse_number(&bound));
+            ast::Command::SetRange(bounds.next().unwrap()?, bounds.next().unwrap()?)
+        }
+        Rule::values_command => ast::Command::SetValues(rule.into_inner().map(|value| parse_number(&value)).collect::<Result<_, _>>()?),
+        Rule::random_command => {
+            let (count, seed) = build_ast_sampling(&mut rule.into_inner())?;
+            ast::Command::SetRandom(count, seed)
+        }
+        Rule::edges_command => {
+            let (count, seed) = build_ast_sampling(&mut rule.into_inner())?;
+            ast::Command::SetEdges(count, seed)
+        }
         Rule::executor_command => ast::Command::SwitchExecutor(rule.into_inner().next().unwrap().as_str().to_string()),
         Rule::test_command => ast::Command::Test(build_ast_expr(&mut rule.into_inner().next().unwrap().into_inner())?),
         Rule::benchmark_command => ast::Command::Benchmark,
+        Rule::depth_command => ast::Command::SetMaxCallDepth(parse_number(&rule.into_inner().next().unwrap())?),
+        Rule::threads_command => ast::Command::SetThreads(parse_number(&rule.into_inner().next().unwrap())?),
+        Rule::checkpoint_command => ast::Command::SetCheckpointFile(rule.into_inner().next().map(|path| path.as_str().to_string())),
+        Rule::resume_command => ast::Command::Resume(rule.into_inner().next().unwrap().as_str().to_string()),
+        Rule::count_command => ast::Command::SetCounting(Some(0)),
+        Rule::counterexamples_command => ast::Command::SetCounting(Some(parse_number(&rule.into_inner().next().unwrap())?)),
+        Rule::first_command => ast::Command::SetCounting(None),
+        Rule::explain_command => {
+            let mut pairs = rule.into_inner();
+            let first = pairs.next().unwrap();
+            if first.as_rule() == Rule::explain_switch {
+                return Ok(ast::Command::SetExplaining(first.as_str() == "on"));
+            }
+            let assignment = pairs.map(|assignment| {
+                let mut pairs = assignment.into_inner();
+                let var = pairs.next().unwrap().as_str().to_string();
+                let value = parse_number(&pairs.next().unwrap())?;
+                Ok((var, value))
+            }).collect::<Result<_, Error>>()?;
+            ast::Command::Explain(build_ast_expr(&mut first.into_inner())?, assignment)
+        }
+        Rule::stats_command => ast::Command::ShowStats,
         _ => unreachable!("Rule cannot be matched in command"),
     })
 }
+
+fn build_ast_sampling(pairs: &mut Pairs<'_, Rule>) -> Result<(usize, Option<u64>), Error> {
+    let count = parse_number(&pairs.next().unwrap())?;
+    let seed = pairs.next().map(|seed| parse_number(&seed)).transpose()?;
+    Ok((count, seed))
+}
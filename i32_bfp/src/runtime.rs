@@ -1,8 +1,18 @@
-use std::time;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::ControlFlow;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::{thread, time};
 
 use crate::ast::{Expr};
+use crate::builtins::Builtin;
+use crate::error::Error;
+use crate::checkpoint::Checkpoint;
+use crate::counterexamples::Tally;
+use crate::domain::{Domain, Sampling, MAX_SAMPLES};
 use crate::compiled_executor::CompiledExecutor;
-use crate::interpreted_executor::InterpretedExecutor;
+use crate::interpreted_executor::{InterpretedExecutor, Trace};
 use crate::parser::parse;
 use crate::ast;
 
@@ -40,24 +50,458 @@ impl ExeuctionMode {
     }
 
     fn should_print_info(&self) -> bool {
+        !matches!(self, ExeuctionMode::Benchmark)
+    }
+
+    fn domain(&self) -> Domain {
         match self {
-            ExeuctionMode::Benchmark => false,
-            _ => true
+            ExeuctionMode::Proof => Domain::Range(i32::MIN, i32::MAX),
+            ExeuctionMode::Fast => Domain::Values(vec![i32::MIN, -1, 0, 1, i32::MAX]),
+            ExeuctionMode::Benchmark => Domain::Range(-5_000_000, 4_999_999),
         }
     }
 }
 
+fn check_sample_count(count: usize) -> Result<(), Error> {
+    if count > MAX_SAMPLES {
+        return Err(Error::TooManySamples { max: MAX_SAMPLES });
+    }
+    Ok(())
+}
+
+/// Without a given seed, a seed is chosen. It is printed with the domain, so the values can be reproduced.
+fn sampling_seed(seed: Option<u64>) -> u64 {
+    seed.unwrap_or_else(|| time::SystemTime::now().duration_since(time::UNIX_EPOCH).map_or(0, |duration| duration.as_nanos() as u64))
+}
+
+/// Returns the position of the assignment in the order of `for_each_assignment`.
+fn index_of_assignment(domains: &[Domain], args: &[i32]) -> Option<u128> {
+    domains.iter().zip(args).try_fold(0, |index, (domain, value)| Some(index * domain.len() + domain.index_of(*value)?))
+}
+
+/// Calls `f` for every element of the cartesian product of the domains. The enumeration stops as
+/// soon as `f` breaks.
+fn for_each_assignment<B>(domains: &[Domain], args: &mut Vec<i32>, f: &mut dyn FnMut(&[i32]) -> ControlFlow<B>) -> ControlFlow<B> {
+    match domains.split_first() {
+        None => f(args),
+        Some((domain, rest)) => {
+            for value in domain.iter() {
+                args.push(value);
+                let result = for_each_assignment(rest, args, f);
+                args.pop();
+                result?;
+            }
+            ControlFlow::Continue(())
+        }
+    }
+}
+
+/// Number of values of the innermost variable which are checked at once by a thread.
+const BLOCK_SIZE: u128 = 1 << 20;
+const WORKER_STACK_SIZE: usize = 8 << 20;
+/// Number of assignments checked between two progress reports.
+const PROGRESS_INTERVAL: u128 = 100_000_000;
+/// Minimum time between two checkpoints of a query.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How the assignments of a query are checked.
+enum Check<'a> {
+    /// A native loop over a range of the innermost variable
+    Scan(ScanRunable<'a>),
+    /// A single call per assignment
+    Single(QueryRunable<'a>),
+}
+
+/// Called with the number of blocks before which all blocks are checked whenever it grows.
+type ProgressHook = dyn Fn(u128) + Send + Sync;
+
+/// A failing assignment or the error which occurred while checking it.
+type Failure = Result<Vec<i32>, Error>;
+
+/// Blocks which are checked completely.
+struct Checked {
+    // Number of blocks before which all blocks are checked
+    prefix: u128,
+    // Tallies of the checked blocks after the prefix
+    later: BTreeMap<u128, Tally>,
+    // Tally of the blocks of the prefix
+    tally: Tally,
+}
+
+/// The assignments of a query, split into blocks which are checked by one or more threads. A block
+/// consists of consecutive values of the innermost variable for a single assignment of the other
+/// variables. The blocks are numbered in the order of `for_each_assignment` and the result of the
+/// first block containing a counterexample or an error is reported, so the result does not depend on
+/// the number of threads. If the work is interrupted, the blocks which are being checked are abandoned.
+///
+/// If counterexamples are counted, all blocks are checked and their tallies are combined in the order
+/// of the blocks.
+///
+/// The work may start at any assignment. Then, the first block starts at this assignment.
+struct Work<'a> {
+    domains: &'a [Domain],
+    vars: &'a [String],
+    blocks_per_assignment: u128,
+    // Index of the first assignment of the other variables and of the first value of the innermost one
+    first_assignment: u128,
+    first_from: u128,
+    // Number of blocks of the first assignment of the other variables
+    first_blocks: u128,
+    blocks: u128,
+    next_block: Mutex<u128>,
+    // The first block known to fail and the failing assignment or the error
+    failure: Mutex<Option<(u128, Failure)>>,
+    checked: Mutex<Checked>,
+    // Maximum number of listed ranges of counterexamples if counterexamples are counted
+    count_limit: Option<usize>,
+    to_check: u128,
+    remaining: Mutex<u128>,
+    should_print_info: bool,
+    interrupted: &'a AtomicBool,
+    // The file and the contents of the checkpoint, which is updated regularly
+    checkpoint: Option<(String, Checkpoint)>,
+    last_checkpoint: Mutex<Instant>,
+    progress_hook: Option<&'a ProgressHook>,
+}
+
+impl<'a> Work<'a> {
+    /// All assignments starting with the `start`-th one are checked.
+    fn new(domains: &'a [Domain], vars: &'a [String], start: u128, count_limit: Option<usize>, should_print_info: bool, interrupted: &'a AtomicBool, checkpoint: Option<(String, Checkpoint)>) -> Work<'a> {
+        let (outer, inner) = match domains.split_last() {
+            Some((inner, outer)) => (outer, inner.len()),
+            // A query without variables is a single block with one assignment
+            None => (domains, 1),
+        };
+        let assignments = outer.iter().map(Domain::len).product::<u128>();
+        let to_check = (assignments * inner).saturating_sub(start);
+        let blocks_per_assignment = inner.div_ceil(BLOCK_SIZE);
+        let (first_assignment, first_from) = if to_check > 0 { (start / inner, start % inner) } else { (0, 0) };
+        let first_blocks = (inner - first_from).div_ceil(BLOCK_SIZE);
+        Work {
+            domains,
+            vars,
+            blocks_per_assignment,
+            first_assignment,
+            first_from,
+            first_blocks,
+            blocks: if to_check > 0 { first_blocks + (assignments - first_assignment - 1) * blocks_per_assignment } else { 0 },
+            next_block: Mutex::new(0),
+            failure: Mutex::new(None),
+            checked: Mutex::new(Checked { prefix: 0, later: BTreeMap::new(), tally: Tally::new(count_limit.unwrap_or(0)) }),
+            count_limit,
+            to_check,
+            remaining: Mutex::new(to_check),
+            should_print_info,
+            interrupted,
+            checkpoint,
+            last_checkpoint: Mutex::new(Instant::now()),
+            progress_hook: None,
+        }
+    }
+
+    /// Checks blocks until all blocks are checked, a block before the next one is known to fail (unless
+    /// counterexamples are counted) or the work is interrupted.
+    fn run(&self, check: &Check) {
+        loop {
+            if self.interrupted.load(Ordering::Relaxed) {
+                return;
+            }
+            let block = {
+                let mut next_block = self.next_block.lock().unwrap();
+                *next_block += 1;
+                *next_block - 1
+            };
+            let first_failure = self.failure.lock().unwrap().as_ref().map(|(block, _)| *block);
+            if block >= self.blocks || first_failure.is_some_and(|first_failure| first_failure < block) {
+                return;
+            }
+            let (args, from, to) = self.decode(block);
+            let result = match self.count_limit {
+                Some(limit) => Ok(self.count_block(check, args, from, to, limit)),
+                None => self.check_block(check, args, from, to).map_or(Ok(Tally::default()), Err),
+            };
+            // The block may not have been checked completely and the result may be caused by the interruption
+            if self.interrupted.load(Ordering::Relaxed) {
+                return;
+            }
+            match result {
+                Ok(tally) => {
+                    self.mark_checked(block, tally);
+                    self.report_progress(to - from + 1);
+                }
+                Err(result) => {
+                    let mut failure = self.failure.lock().unwrap();
+                    if failure.as_ref().is_none_or(|(first_failure, _)| block < *first_failure) {
+                        *failure = Some((block, result));
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns the values of the outer variables and the range of indices of the innermost variable
+    /// which make up the block.
+    fn decode(&self, block: u128) -> (Vec<i32>, u128, u128) {
+        let (outer, inner) = match self.domains.split_last() {
+            Some((inner, outer)) => (outer, inner.len()),
+            None => return (Vec::new(), 0, 0),
+        };
+        let (mut assignment, from) = if block < self.first_blocks {
+            (self.first_assignment, self.first_from + block * BLOCK_SIZE)
+        } else {
+            let block = block - self.first_blocks;
+            (self.first_assignment + 1 + block / self.blocks_per_assignment, block % self.blocks_per_assignment * BLOCK_SIZE)
+        };
+        let mut args = vec![0; outer.len()];
+        for (arg, domain) in args.iter_mut().zip(outer).rev() {
+            *arg = domain.get(assignment % domain.len());
+            assignment /= domain.len();
+        }
+        (args, from, inner.min(from + BLOCK_SIZE) - 1)
+    }
+
+    /// Returns the first failing assignment of the block or the error it caused.
+    fn check_block(&self, check: &Check, mut args: Vec<i32>, from: u128, to: u128) -> Option<Failure> {
+        let inner = match self.domains.last() {
+            Some(inner) => inner,
+            None => return self.check_assignment(check, args),
+        };
+        match check {
+            Check::Scan(scan) => {
+                let (value, result) = scan(&args, inner.get(from), inner.get(to))?;
+                args.push(value);
+                Some(result.map(|_| args.clone()).map_err(|error| self.evaluation_error(&args, error)))
+            }
+            Check::Single(_) => (from..=to).take_while(|_| !self.interrupted.load(Ordering::Relaxed)).find_map(|index| {
+                let mut args = args.clone();
+                args.push(inner.get(index));
+                self.check_assignment(check, args)
+            }),
+        }
+    }
+
+    /// Counts the counterexamples and errors of the block.
+    fn count_block(&self, check: &Check, mut args: Vec<i32>, from: u128, to: u128, limit: usize) -> Tally {
+        let mut tally = Tally::new(limit);
+        let inner = match self.domains.last() {
+            Some(inner) => inner,
+            None => {
+                match self.check_assignment(check, args) {
+                    Some(Ok(_)) => tally.add_counterexample(&[], 0),
+                    Some(Err(error)) => tally.add_error(error),
+                    None => {}
+                }
+                return tally;
+            }
+        };
+        let add = |tally: &mut Tally, args: &[i32], value: i32, error: Option<Error>| match error {
+            None => tally.add_counterexample(args, value),
+            Some(error) => {
+                let mut args = args.to_vec();
+                args.push(value);
+                tally.add_error(self.evaluation_error(&args, error));
+            }
+        };
+        match check {
+            Check::Scan(scan) => {
+                // The scan stops at the first counterexample or error and is continued after it
+                let (mut from, to) = (inner.get(from), inner.get(to));
+                while let Some((value, result)) = scan(&args, from, to) {
+                    add(&mut tally, &args, value, result.err());
+                    if value == to || self.interrupted.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    from = value + 1;
+                }
+            }
+            Check::Single(runable) => {
+                for index in (from..=to).take_while(|_| !self.interrupted.load(Ordering::Relaxed)) {
+                    let value = inner.get(index);
+                    args.push(value);
+                    let result = runable(&args);
+                    args.pop();
+                    match result {
+                        Ok(0) => add(&mut tally, &args, value, None),
+                        Ok(_) => {}
+                        Err(error) => add(&mut tally, &args, value, Some(error)),
+                    }
+                }
+            }
+        }
+        tally
+    }
+
+    fn check_assignment(&self, check: &Check, args: Vec<i32>) -> Option<Failure> {
+        match check {
+            Check::Single(runable) => match runable(&args) {
+                Ok(0) => Some(Ok(args)),
+                Ok(_) => None,
+                Err(error) => Some(Err(self.evaluation_error(&args, error))),
+            },
+            Check::Scan(_) => unreachable!("Scans need a free variable"),
+        }
+    }
+
+    fn evaluation_error(&self, args: &[i32], error: Error) -> Error {
+        Error::Evaluation { input: format_assignment(self.vars, args), error: Box::new(error) }
+    }
+
+    fn mark_checked(&self, block: u128, tally: Tally) {
+        let mut checked = self.checked.lock().unwrap();
+        let checked = &mut *checked;
+        checked.later.insert(block, tally);
+        let prefix = checked.prefix;
+        while let Some(tally) = checked.later.remove(&checked.prefix) {
+            checked.tally.append(tally);
+            checked.prefix += 1;
+        }
+        if let Some(progress_hook) = self.progress_hook.filter(|_| checked.prefix > prefix) {
+            progress_hook(checked.prefix);
+        }
+        if self.checkpoint.is_some() {
+            let mut last_checkpoint = self.last_checkpoint.lock().unwrap();
+            if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                self.write_checkpoint(checked.prefix);
+                *last_checkpoint = Instant::now();
+            }
+        }
+    }
+
+    /// Returns the last assignment of the checked blocks, before which all assignments are checked.
+    fn last_checked(&self, checked_prefix: u128) -> Option<Vec<i32>> {
+        if checked_prefix == 0 {
+            return None;
+        }
+        let (mut args, _, to) = self.decode(checked_prefix - 1);
+        if let Some(inner) = self.domains.last() {
+            args.push(inner.get(to));
+        }
+        Some(args)
+    }
+
+    fn write_checkpoint(&self, checked_prefix: u128) {
+        if let Some((file, checkpoint)) = &self.checkpoint {
+            let mut checkpoint = checkpoint.clone();
+            // Otherwise, the work has not made progress since it was started from the checkpoint
+            if let Some(last_checked) = self.last_checked(checked_prefix) {
+                checkpoint.last_checked = Some(last_checked);
+            }
+            if let Err(error) = checkpoint.write(file) {
+                println!("{}", error);
+            }
+        }
+    }
+
+    /// Writes the final checkpoint, no matter how the work ended.
+    fn finish_checkpoint(&self) {
+        if let Some((file, _)) = &self.checkpoint {
+            self.write_checkpoint(self.checked.lock().unwrap().prefix);
+            println!("Checkpoint written to {}", file);
+        }
+    }
+
+    fn report_progress(&self, checked: u128) {
+        let mut remaining = self.remaining.lock().unwrap();
+        let before = *remaining;
+        *remaining -= checked;
+        if self.should_print_info && *remaining > 0 && before / PROGRESS_INTERVAL != *remaining / PROGRESS_INTERVAL {
+            println!("{} loops remaining...", remaining);
+        }
+    }
+
+    /// An interrupted work results in an error, unless all blocks before the first failure were checked.
+    fn into_result(self) -> Option<Failure> {
+        let checked_prefix = self.checked.lock().unwrap().prefix;
+        let failure = self.failure.lock().unwrap().take();
+        let complete = match &failure {
+            Some((block, _)) => *block == checked_prefix,
+            None => checked_prefix == self.blocks,
+        };
+        if !complete {
+            return Some(Err(self.interruption_error(checked_prefix)));
+        }
+        failure.map(|(_, result)| result)
+    }
+
+    /// The tally of all inputs, or an error if the work was interrupted.
+    fn into_tally(self) -> Result<Tally, Error> {
+        let checked_prefix = self.checked.lock().unwrap().prefix;
+        if checked_prefix != self.blocks {
+            return Err(self.interruption_error(checked_prefix));
+        }
+        Ok(self.checked.into_inner().unwrap().tally)
+    }
+
+    fn interruption_error(&self, checked_prefix: u128) -> Error {
+        let checked = self.to_check - *self.remaining.lock().unwrap();
+        let mut message = format!("Interrupted after checking {} of {} inputs.", checked, self.to_check);
+        if let Some(last_checked) = self.last_checked(checked_prefix) {
+            message += &format!(" All inputs up to and including {} were checked.", format_assignment(self.vars, &last_checked));
+        }
+        Error::Interrupted(message)
+    }
+}
+
+pub fn format_assignment(vars: &[String], args: &[i32]) -> String {
+    vars.iter()
+        .zip(args)
+        .map(|(var, value)| format!("{} = {}", var, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Maximum number of nested function calls during the evaluation of a query if not configured otherwise.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+/// Interrupt flag of executors and runtimes which are not interrupted from the outside.
+pub static NEVER_INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Runables may be called by several threads at once.
+pub type QueryRunable<'a> = Box<dyn 'a + Fn(&[i32]) -> Result<i32, Error> + Sync>;
+/// Checks the query for all values of the last free variable in a range, while the other free variables
+/// are fixed. It takes the values of the other variables, the start and the (inclusive) end of the
+/// range and returns the first value for which the query evaluates to 0 or fails.
+pub type ScanRunable<'a> = Box<dyn 'a + Fn(&[i32], i32, i32) -> Option<(i32, Result<i32, Error>)> + Sync>;
+
 pub trait Executor {
-    fn handle_function_def(&mut self, func_def: ast::FunctionDef) -> Result<(), String>;
-    fn get_query_runable<'a>(&'a mut self, query: ast::Expr) -> Result<Box<dyn 'a + Fn(i32) -> i32>, String>;
+    fn handle_function_def(&mut self, func_def: ast::FunctionDef) -> Result<(), Error>;
+    /// The returned runable takes the values of the free variables in the order of `Expr::used_variables`.
+    fn get_query_runable<'a>(&'a mut self, query: ast::Expr) -> Result<QueryRunable<'a>, Error>;
+    /// Executors which can check a whole range at once return a scan runable for queries with at
+    /// least one free variable.
+    fn get_scan_runable<'a>(&'a mut self, _query: ast::Expr) -> Result<Option<ScanRunable<'a>>, Error> {
+        Ok(None)
+    }
     fn delete(&mut self, name: &str);
+    fn set_max_call_depth(&mut self, depth: usize);
+    /// Evaluations fail as soon as the flag is set.
+    fn set_interrupt_flag(&mut self, interrupted: &'static AtomicBool);
 }
 
 pub struct Runtime {
     mode: ExeuctionMode,
     used_executor: ExecutorType,
     compiled: CompiledExecutor,
-    interpreted: InterpretedExecutor
+    interpreted: InterpretedExecutor,
+    // Number of threads which check the assignments of a query
+    threads: usize,
+    // Cancels the running query, test or benchmark when set
+    interrupted: &'static AtomicBool,
+    // Queries regularly save their progress to this file
+    checkpoint_file: Option<String>,
+    // Queries count all counterexamples and list at most this many ranges of them, if set
+    count_limit: Option<usize>,
+    // The evaluation of counterexamples is printed
+    explain_counterexamples: bool,
+    // Values of each free variable and how they were chosen
+    domain: Domain,
+    domain_description: String,
+    // Set if the values are sampled, then each free variable gets its own values
+    sampling: Option<Sampling>,
+    // Built-in functions whose name refers to a user-defined function in new inputs
+    shadowed_builtins: HashSet<Builtin>,
+    progress_hook: Option<Box<ProgressHook>>,
 }
 
 impl Runtime {
@@ -67,92 +511,414 @@ impl Runtime {
             mode: ExeuctionMode::Proof,
             used_executor: ExecutorType::Compiled,
             compiled: CompiledExecutor::new(),
-            interpreted: InterpretedExecutor::new()
+            interpreted: InterpretedExecutor::new(),
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+            interrupted: &NEVER_INTERRUPTED,
+            checkpoint_file: None,
+            count_limit: None,
+            explain_counterexamples: false,
+            domain: ExeuctionMode::Proof.domain(),
+            domain_description: ExeuctionMode::Proof.domain().to_string(),
+            sampling: None,
+            shadowed_builtins: HashSet::new(),
+            progress_hook: None,
         }
     }
 
+    /// The flag is set from the outside (e.g. by Ctrl-C) to cancel the running command. It is not
+    /// reset by the runtime.
+    pub fn set_interrupt_flag(&mut self, interrupted: &'static AtomicBool) {
+        self.interrupted = interrupted;
+        self.compiled.set_interrupt_flag(interrupted);
+        self.interpreted.set_interrupt_flag(interrupted);
+    }
+
+    /// Lets tests interrupt queries after a known number of blocks.
+    #[cfg(test)]
+    pub fn set_progress_hook(&mut self, progress_hook: impl Fn(u128) + Send + Sync + 'static) {
+        self.progress_hook = Some(Box::new(progress_hook));
+    }
+
+    /// Number of calls of functions which were not compiled yet, made by compiled code.
+    #[cfg(test)]
+    pub fn stub_calls(&self) -> usize {
+        self.compiled.stub_calls()
+    }
+
+    #[cfg(test)]
+    pub fn set_vectorize(&mut self, vectorize: bool) {
+        self.compiled.set_vectorize(vectorize);
+    }
+
     pub fn handle_line(&mut self, line: &str) {
-        let result = parse(line)
-            .and_then(|ast| self.handle_ast(ast));
-        if let Err(error) = result {
-            println!("ERROR>\n{}", error);
+        if let Err(error) = self.handle_str(line) {
+            println!("ERROR>\n{}", error.display_in(line));
         }
     }
 
-    fn handle_str(&mut self, str: &str) -> Result<(), String> {
-        self.handle_ast(parse(str)?)
+    /// Errors which refer to a token of the input carry its span.
+    pub fn handle_str(&mut self, str: &str) -> Result<(), Error> {
+        parse(str).and_then(|ast| self.handle_ast(ast))
     }
     
-    fn handle_ast(&mut self, ast: ast::Action) -> Result<(), String> {
+    fn handle_ast(&mut self, mut ast: ast::Action) -> Result<(), Error> {
+        match &mut ast {
+            ast::Action::FunctionDef(ast::FunctionDef { body: expr, .. })
+            | ast::Action::Query(expr)
+            | ast::Action::Command(ast::Command::Test(expr))
+            | ast::Action::Command(ast::Command::Explain(expr, _)) => self.shadow_builtins(expr),
+            _ => {}
+        }
         match ast {
             ast::Action::FunctionDef(func_def) => {
+                if let Some(builtin) = Builtin::from_name(&func_def.name).filter(|builtin| !self.shadowed_builtins.contains(builtin)) {
+                    return Err(Error::BuiltinRedefinition { name: builtin.name().to_string() });
+                }
                 self.compiled.handle_function_def(func_def.clone())?;
                 self.interpreted.handle_function_def(func_def)?;
             },
-            ast::Action::Query(query) => self.execute_query(query)?,
+            ast::Action::Query(query) => match self.count_limit {
+                Some(limit) => { self.count_query(query, limit)?; },
+                None => { self.execute_query(query)?; },
+            },
             ast::Action::Command(ast::Command::ShowCode(name)) => self.compiled.print_code(&name),
-            ast::Action::Command(ast::Command::ListFunctions()) => self.compiled.list_functions(),
-            ast::Action::Command(ast::Command::DeleteFunction(name)) => { 
+            ast::Action::Command(ast::Command::ListFunctions()) => {
+                self.compiled.list_functions();
+                let builtins = Builtin::ALL.iter()
+                    .map(|builtin| format!("{}{}", builtin, if self.shadowed_builtins.contains(builtin) { " (shadowed)" } else { "" }))
+                    .collect::<Vec<_>>();
+                println!("Built-in functions: {}", builtins.join(", "));
+            },
+            ast::Action::Command(ast::Command::DeleteFunction(name)) => {
+                if let Some(builtin) = Builtin::from_name(&name) {
+                    if !self.shadowed_builtins.remove(&builtin) {
+                        return Err(Error::BuiltinDeletion { name });
+                    }
+                    println!("{} refers to the built-in function again.", name);
+                }
                 self.compiled.delete(&name);
+                self.interpreted.delete(&name);
              },
+            ast::Action::Command(ast::Command::ShadowBuiltin(name)) => {
+                let builtin = Builtin::from_name(&name).ok_or_else(|| Error::NotBuiltin { name: name.clone() })?;
+                self.shadowed_builtins.insert(builtin);
+                println!("{} refers to a user-defined function in new definitions and queries.", name);
+            },
             ast::Action::Command(ast::Command::SwitchMode(mode)) => {
                 self.mode = ExeuctionMode::from(&mode);
                 println!("Switched mode to {:?}", self.mode);
+                let domain = self.mode.domain();
+                let description = domain.to_string();
+                self.set_domain(domain, description);
+            },
+            ast::Action::Command(ast::Command::SetRange(from, to)) => {
+                if from > to {
+                    return Err(Error::EmptyRange { from, to });
+                }
+                let domain = Domain::Range(from, to);
+                let description = domain.to_string();
+                self.set_domain(domain, description);
+            },
+            ast::Action::Command(ast::Command::SetValues(values)) => {
+                let domain = Domain::Values(values);
+                let description = domain.to_string();
+                self.set_domain(domain, description);
+            },
+            ast::Action::Command(ast::Command::SetRandom(count, seed)) => {
+                if count == 0 {
+                    return Err(Error::NoSamples);
+                }
+                check_sample_count(count)?;
+                let sampling = Sampling { count, seed: sampling_seed(seed), edges: false };
+                self.set_domain(sampling.domain(0), format!("{} random values per variable with seed {}", count, sampling.seed));
+                self.sampling = Some(sampling);
+            },
+            ast::Action::Command(ast::Command::SetEdges(count, seed)) => {
+                check_sample_count(count)?;
+                let sampling = Sampling { count, seed: sampling_seed(seed), edges: true };
+                self.set_domain(sampling.domain(0), format!("edge values and {} random values per variable with seed {}", count, sampling.seed));
+                self.sampling = Some(sampling);
             },
             ast::Action::Command(ast::Command::SwitchExecutor(executor)) => {
                 self.used_executor = ExecutorType::from(&executor);
                 println!("Switched executor to {:?}", self.used_executor);
             },
+            ast::Action::Command(ast::Command::SetMaxCallDepth(depth)) => {
+                self.compiled.set_max_call_depth(depth);
+                self.interpreted.set_max_call_depth(depth);
+                println!("Set maximum call depth to {}", depth);
+            },
+            ast::Action::Command(ast::Command::SetThreads(threads)) => {
+                if threads == 0 {
+                    return Err(Error::NoThreads);
+                }
+                self.threads = threads;
+                println!("Using {} threads", threads);
+            },
+            ast::Action::Command(ast::Command::SetCheckpointFile(file)) => {
+                match &file {
+                    Some(file) => println!("Writing checkpoints of queries to {}", file),
+                    None => println!("Checkpoints are no longer written"),
+                }
+                self.checkpoint_file = file;
+            },
+            ast::Action::Command(ast::Command::Resume(file)) => { self.resume(&file)?; },
+            ast::Action::Command(ast::Command::SetCounting(limit)) => {
+                match limit {
+                    Some(0) => println!("Counting the counterexamples of queries"),
+                    Some(limit) => println!("Counting the counterexamples of queries and listing up to {} of them", limit),
+                    None => println!("Queries stop at the first counterexample"),
+                }
+                self.count_limit = limit;
+            },
+            ast::Action::Command(ast::Command::Explain(query, assignment)) => { self.explain(&query, &assignment)?; },
+            ast::Action::Command(ast::Command::SetExplaining(explain)) => {
+                if explain {
+                    println!("Counterexamples of queries are explained");
+                } else {
+                    println!("Counterexamples of queries are no longer explained");
+                }
+                self.explain_counterexamples = explain;
+            },
+            ast::Action::Command(ast::Command::ShowStats) => self.compiled.print_stats(),
             ast::Action::Command(ast::Command::Test(expr)) => self.test_expr(&expr)?,
             ast::Action::Command(ast::Command::Benchmark) => self.benchmark()?
         }
         Ok(())
     }
     
-    fn execute_query(&mut self, query: ast::Expr) -> Result<(), String> {
+    /// Calls of built-in functions are resolved when an input is handled. Thus, functions which were
+    /// defined before a built-in function was shadowed keep calling the built-in function.
+    fn shadow_builtins(&self, expr: &mut Expr) {
+        expr.shadow_builtins(&|builtin| self.shadowed_builtins.contains(&builtin));
+    }
+
+    /// Checks the query for all assignments of the current mode and returns the first counterexample.
+    pub fn execute_query(&mut self, query: ast::Expr) -> Result<Option<Vec<i32>>, Error> {
+        let used_vars = query.used_variables();
+        let domains = self.domains(used_vars.len());
+        let inputs = format!("{} in {}", used_vars.join(", "), self.domain_description);
+        let checkpoint = self.checkpoint_file.clone().map(|file| {
+            let checkpoint = Checkpoint {
+                query: query.clone(),
+                functions: self.interpreted.reachable_functions(&query),
+                domains: domains.clone(),
+                last_checked: None,
+            };
+            (file, checkpoint)
+        });
+        self.check_query(query, domains, 0, checkpoint, &inputs)
+    }
+
+    /// Continues the query of the checkpoint after its last checked input. The checkpoint is updated
+    /// while the query is checked.
+    pub fn resume(&mut self, file: &str) -> Result<Option<Vec<i32>>, Error> {
+        let mut checkpoint = Checkpoint::read(file)?;
+        self.shadow_builtins(&mut checkpoint.query);
+        for function in &mut checkpoint.functions {
+            self.shadow_builtins(&mut function.body);
+        }
+        // The checked inputs are only known to hold for the same definitions
+        let functions = self.interpreted.reachable_functions(&checkpoint.query);
+        let changed = functions.iter().filter(|function| !checkpoint.functions.contains(function))
+            .chain(checkpoint.functions.iter().filter(|function| !functions.contains(function)))
+            .next();
+        if let Some(function) = changed {
+            return Err(Error::CheckpointMismatch { function: function.name.clone() });
+        }
+        let start = match &checkpoint.last_checked {
+            Some(last_checked) => {
+                let index = index_of_assignment(&checkpoint.domains, last_checked).ok_or(Error::CheckpointOutsideDomains)?;
+                println!("Resuming after {}", format_assignment(&checkpoint.query.used_variables(), last_checked));
+                index + 1
+            }
+            None => 0,
+        };
+        let inputs = checkpoint.query.used_variables().iter()
+            .zip(&checkpoint.domains)
+            .map(|(var, domain)| format!("{} in {}", var, domain.summary()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.check_query(checkpoint.query.clone(), checkpoint.domains.clone(), start, Some((file.to_string(), checkpoint)), &inputs)
+    }
+
+    /// Checks the query for all assignments of the current mode and counts the inputs for which it does
+    /// not hold or fails. Contiguous counterexamples are listed as ranges, at most `limit` of them.
+    pub fn count_query(&mut self, query: ast::Expr, limit: usize) -> Result<Tally, Error> {
         let used_vars = query.used_variables();
-        let (first_var_range, mut to_check) = self.get_first_var_range(&used_vars);
+        let domains = self.domains(used_vars.len());
+        let inputs = domains.iter().map(Domain::len).product::<u128>();
+        if self.checkpoint_file.is_some() {
+            println!("Checkpoints are not written while counting counterexamples");
+        }
+        let tally = self.run_work(query, &domains, 0, Some(limit), None, |work| work.into_tally())??;
 
-        let runable = match self.used_executor {
-            ExecutorType::Compiled => self.compiled.get_query_runable(query)?,
-            ExecutorType::Interpreted => self.interpreted.get_query_runable(query)?
+        let listed = tally.listed();
+        if !listed.is_empty() {
+            println!("Counterexamples:");
+            for range in listed {
+                println!("  {}", range.format(&used_vars));
+            }
+            if tally.ranges > listed.len() as u128 {
+                println!("  ... and {} more", tally.ranges - listed.len() as u128);
+            }
+        }
+        let description = if used_vars.is_empty() {
+            "the query".to_string()
+        } else {
+            format!("{} in {}", used_vars.join(", "), self.domain_description)
         };
+        println!(
+            "{} inputs fail, {} inputs pass and {} inputs cause an error ({}).",
+            tally.failing,
+            inputs - tally.failing - tally.errors,
+            tally.errors,
+            description
+        );
+        if let Some(error) = &tally.first_error {
+            println!("First error: {}", error);
+        }
+        Ok(tally)
+    }
 
-        println!("The following free variables were found: {:?}", used_vars);
-        println!("Using {:?} executor...", self.used_executor);
-        println!("{} loops remaining...", to_check);
-        for i in first_var_range {
-            if to_check % 100_000_000 == 0 && self.mode.should_print_info()  {
-                println!("{} loops remaining...", to_check)
+    /// Prints the evaluation of the query for the given values of its free variables by the interpreter.
+    pub fn explain(&self, query: &Expr, assignment: &[(String, i32)]) -> Result<Trace, Error> {
+        let used_vars = query.used_variables();
+        if let Some((var, _)) = assignment.iter().find(|(var, _)| !used_vars.contains(var)) {
+            return Err(Error::NotFreeVariable { name: var.clone() });
+        }
+        let args = used_vars.iter()
+            .map(|var| assignment.iter().find(|(name, _)| name == var).map(|(_, value)| *value).ok_or_else(|| Error::MissingValue { name: var.clone() }))
+            .collect::<Result<Vec<_>, _>>()?;
+        let trace = self.interpreted.explain(query, &args)?;
+        print!("{}", trace);
+        Ok(trace)
+    }
+
+    /// Checks the query for the assignments of the domains, starting with the `start`-th one. The
+    /// description of the inputs is printed with the result.
+    fn check_query(&mut self, query: ast::Expr, domains: Vec<Domain>, start: u128, checkpoint: Option<(String, Checkpoint)>, inputs: &str) -> Result<Option<Vec<i32>>, Error> {
+        let used_vars = query.used_variables();
+        let explained = if self.explain_counterexamples { Some(query.clone()) } else { None };
+        let result = self.run_work(query, &domains, start, None, checkpoint, |work| {
+            work.finish_checkpoint();
+            work.into_result()
+        })?;
+        match result {
+            Some(Ok(counterexample)) => {
+                if used_vars.is_empty() {
+                    println!("Formula does not hold!");
+                } else {
+                    println!("Formula does not hold for {} ({})!", format_assignment(&used_vars, &counterexample), inputs);
+                }
+                if let Some(query) = explained {
+                    let assignment = used_vars.iter().cloned().zip(counterexample.iter().copied()).collect::<Vec<_>>();
+                    self.explain(&query, &assignment)?;
+                }
+                Ok(Some(counterexample))
             }
-            let result = runable(i);
-            if result == 0 {
-                println!("Formula does not hold for {}!", i);
-                return Ok(());
+            Some(Err(error)) => Err(error),
+            None => {
+                if used_vars.is_empty() {
+                    println!("Formula does hold.");
+                } else {
+                    println!("Formula does hold for {}.", inputs);
+                }
+                Ok(None)
             }
-            to_check-=1;
         }
-        println!("Formula does hold.");
-        Ok(())
     }
 
-    fn test_expr(&mut self, expr: &Expr) -> Result<(), String> {
+    /// Checks the assignments of the domains with the configured threads and passes the finished work
+    /// to `finish`.
+    fn run_work<R>(&mut self, query: ast::Expr, domains: &[Domain], start: u128, count_limit: Option<usize>, checkpoint: Option<(String, Checkpoint)>, finish: impl FnOnce(Work) -> R) -> Result<R, Error> {
+        let used_vars = query.used_variables();
+        // Compiled code must not reach a stub while it is executed by several threads
+        if self.threads > 1 && matches!(self.used_executor, ExecutorType::Compiled) {
+            self.compiled.compile_reachable_functions(&query);
+        }
+        // The compiled executor checks a range of the innermost variable in a single native loop
+        let check = match (&self.used_executor, domains.last()) {
+            (ExecutorType::Compiled, Some(Domain::Range(_, _))) => match self.compiled.get_scan_runable(query)? {
+                Some(scan) => Check::Scan(scan),
+                None => unreachable!("Queries with a free variable are checked by a scan"),
+            },
+            (ExecutorType::Compiled, _) => Check::Single(self.compiled.get_query_runable(query)?),
+            (ExecutorType::Interpreted, _) => Check::Single(self.interpreted.get_query_runable(query)?),
+        };
+
+        println!("The following free variables were found: {:?}", used_vars);
+        println!("Using {:?} executor...", self.used_executor);
+        let mut work = Work::new(domains, &used_vars, start, count_limit, self.mode.should_print_info(), self.interrupted, checkpoint);
+        work.progress_hook = self.progress_hook.as_deref();
+        println!("{} loops remaining...", work.to_check);
+        let threads = self.threads;
+        if threads == 1 {
+            work.run(&check);
+        } else {
+            thread::scope(|scope| {
+                for _ in 0..threads {
+                    // The interpreter needs as much stack for deep recursions as the main thread
+                    thread::Builder::new()
+                        .stack_size(WORKER_STACK_SIZE)
+                        .spawn_scoped(scope, || work.run(&check))
+                        .map_err(|error| Error::ThreadSpawn { reason: error.to_string() })?;
+                }
+                Ok::<(), Error>(())
+            })?;
+        }
+        Ok(finish(work))
+    }
+
+    fn test_expr(&mut self, expr: &Expr) -> Result<(), Error> {
+        let used_vars = expr.used_variables();
         let compiler = self.compiled.get_query_runable(expr.clone())?;
         let interpreted = self.interpreted.get_query_runable(expr.clone())?;
 
-        for i in -1000..1000 {
-            let result_compiler = compiler(i);
-            let result_interpreted = interpreted(i);
+        // Keep the number of tested assignments manageable for several free variables
+        let domain = if used_vars.len() > 1 { Domain::Range(-20, 20) } else { Domain::Range(-1000, 999) };
+        // Inputs for which both executors failed with the same error
+        let mut failed_inputs = 0;
+        let mut first_failure = None;
+        let mut tested = 0;
+        let mut last_tested = None;
+        let interrupted = self.interrupted;
+        // Breaks with true if the test was interrupted
+        let result = for_each_assignment(&vec![domain; used_vars.len()], &mut Vec::new(), &mut |args| {
+            let result_compiler = compiler(args);
+            let result_interpreted = interpreted(args);
+            if interrupted.load(Ordering::Relaxed) {
+                return ControlFlow::Break(true);
+            }
             if result_compiler != result_interpreted {
-                println!("Difference between compiled and interpreted exeuction for input {}. Compiled: {}, Interpredted: {}.", i, result_compiler, result_interpreted);
-                return Ok(())
+                println!("Difference between compiled and interpreted exeuction for input {}. Compiled: {:?}, Interpredted: {:?}.", format_assignment(&used_vars, args), result_compiler, result_interpreted);
+                return ControlFlow::Break(false);
+            }
+            if let Err(error) = result_compiler {
+                failed_inputs += 1;
+                first_failure.get_or_insert_with(|| format!("{}: {}", format_assignment(&used_vars, args), error));
+            }
+            tested += 1;
+            last_tested = Some(args.to_vec());
+            ControlFlow::Continue(())
+        });
+        if result == ControlFlow::Break(true) {
+            let mut message = format!("Interrupted after testing {} inputs.", tested);
+            if let Some(last_tested) = last_tested {
+                message += &format!(" Last input tested: {}.", format_assignment(&used_vars, &last_tested));
+            }
+            return Err(Error::Interrupted(message));
+        }
+        if result.is_continue() {
+            if let Some(failure) = first_failure {
+                println!("Both executors failed for {} inputs, e.g. {}", failed_inputs, failure);
             }
+            println!("Test OK");
         }
-        println!("Test OK");
         Ok(())
     }
 
-    fn benchmark(&mut self) -> Result<(), String> {
+    fn benchmark(&mut self) -> Result<(), Error> {
         self.handle_str(".mode benchmark")?;
         self.execute_benchmark("Simple", "x <> x + 1")?;
         self.execute_benchmark("Complex", "(x + 1) % 2 <> x % 2")?;
@@ -161,7 +927,7 @@ impl Runtime {
         Ok(())
     }
 
-    fn execute_benchmark(&mut self, name: &str, expression: &str) -> Result<(), String> {
+    fn execute_benchmark(&mut self, name: &str, expression: &str) -> Result<(), Error> {
         let expr = parse(expression)?;
         if let ast::Action::Query(query) = expr {
             self.handle_str(".executor compiled")?;
@@ -181,15 +947,27 @@ impl Runtime {
         Ok(())
     }
 
-    fn get_first_var_range(&self, used_vars: &Vec<String>) -> (Box<dyn Iterator<Item = i32>>, usize) {
-        if used_vars.len() == 0 {
-            return (Box::new(0..=0), 1);
-        }
+    fn set_domain(&mut self, domain: Domain, description: String) {
+        println!("Domain of the free variables: {}", description);
+        self.domain = domain;
+        self.domain_description = description;
+        self.sampling = None;
+    }
 
-        match self.mode {
-            ExeuctionMode::Proof => (Box::new(i32::MIN..=i32::MAX), (i32::MAX as usize) + 1 + -(i32::MIN as i64) as usize),
-            ExeuctionMode::Fast => (Box::new(vec![i32::MIN, -1, 0, 1, i32::MAX].into_iter()), 5),
-            ExeuctionMode::Benchmark => (Box::new(-5_000_000..5_000_000), 10_000_000),
+    /// The domains of the given number of free variables. Sampled values differ per variable, and all
+    /// combinations of them are checked.
+    fn domains(&self, variables: usize) -> Vec<Domain> {
+        let sampling = match &self.sampling {
+            Some(sampling) => sampling,
+            None => return vec![self.domain.clone(); variables],
+        };
+        let domains: Vec<Domain> = (0..variables)
+            .map(|variable| if variable == 0 { self.domain.clone() } else { sampling.domain(variable) })
+            .collect();
+        let inputs = domains.iter().map(Domain::len).product::<u128>();
+        if variables > 1 && inputs > MAX_SAMPLES as u128 {
+            println!("Warning: {} values per variable give {} inputs for {} variables", self.domain.len(), inputs, variables);
         }
+        domains
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,683 @@
+use std::collections::HashMap;
+
+use dynasmrt::x64::{Rq, X64Relocation};
+use dynasmrt::{dynasm, Assembler, DynamicLabel, DynasmApi, DynasmLabelApi, Register};
+
+use crate::ast::{Expr, SourceSpan};
+use crate::builtins::Builtin;
+use crate::error::Error;
+use crate::compiler::{stack_parameter_displacement, Runable, CALLEE_SAVED_SIZE, PARAMETER_REGISTERS, SCAN_END, SCAN_NOT_FOUND};
+use crate::optimizer::Optimizable;
+
+// The vector compiler generates AVX2 code, which evaluates a query for 8 consecutive values of the loop
+// variable at once. Each lane computes exactly what the scalar code computes for its value. Whenever a
+// lane evaluates to 0 or a division would trap, the code gives up on the whole block of 8 values and
+// returns its first value. The caller checks this block with the scalar code, which reports the
+// counterexample or the error. The code follows the same calling convention as the code of
+// `CompilationContext::compile_scan`.
+
+/// Number of values evaluated at once.
+pub const LANES: i64 = 8;
+
+// Intermediate results are kept on a stack of vectors. The first entries live in registers and the
+// rest in the frame. The remaining registers are scratch registers for the single operations. The
+// upper registers are callee-saved in win64, so fewer registers are used there.
+#[cfg(windows)]
+const STACK_REGISTERS: usize = 2;
+#[cfg(windows)]
+const SCRATCH_REGISTERS: [u8; 4] = [2, 3, 4, 5];
+#[cfg(not(windows))]
+const STACK_REGISTERS: usize = 12;
+#[cfg(not(windows))]
+const SCRATCH_REGISTERS: [u8; 4] = [12, 13, 14, 15];
+
+const S0: u8 = SCRATCH_REGISTERS[0];
+const S1: u8 = SCRATCH_REGISTERS[1];
+const S2: u8 = SCRATCH_REGISTERS[2];
+const S3: u8 = SCRATCH_REGISTERS[3];
+
+// The loop keeps the first value of the current block in r12 and the end of the range in r13, both
+// sign extended to 64 bit.
+const BLOCK_REGISTER: Rq = Rq::R12;
+const END_REGISTER: Rq = Rq::R13;
+
+/// Returns true if the CPU supports the instructions used by the vector compiler.
+pub fn is_supported() -> bool {
+    is_x86_feature_detected!("avx2")
+}
+
+/// Returns true if the vector compiler can compile the expression. Function calls and the built-in
+/// functions which count or reorder bits are not supported.
+pub fn can_vectorize(expr: &Expr) -> bool {
+    match expr {
+        Expr::FunctionCall(_, _, _) => false,
+        Expr::BuiltinCall(Builtin::Popcnt | Builtin::Clz | Builtin::Ctz | Builtin::Bswap, _, _) => false,
+        _ => expr.sub_expressions().into_iter().all(can_vectorize),
+    }
+}
+
+pub struct VectorCompilationContext {
+    ops: Assembler<X64Relocation>,
+    parameter_count: usize,
+    register_parameters: Vec<(Rq, i32)>,
+    // Maps variables to their location relative to the frame pointer
+    var: HashMap<String, i32>,
+    // Number of 8 byte slots used for parameters and the number of 32 byte slots below them
+    scalar_slots: i32,
+    vector_slots: i32,
+    // Slots of the stack entries which do not fit into registers
+    spill_slots: Vec<i32>,
+    // Slots which hold the operands of divisions which are executed lane by lane
+    division_slots: Option<(i32, i32)>,
+    // The loop variable and the slot of its values in the current block
+    loop_var: String,
+    loop_var_slot: i32,
+    // Slot of the mask of the lanes which are evaluated by the current (conditional) code
+    mask_slot: i32,
+    // Leaves the loop and returns the first value of the current block
+    bail_label: DynamicLabel,
+    // Location of the lane indices 0 to 7 and of a mask which selects the odd lanes
+    lanes_label: DynamicLabel,
+    odd_lanes_label: DynamicLabel,
+}
+
+impl VectorCompilationContext {
+    pub fn new() -> VectorCompilationContext {
+        let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+        let bail_label = ops.new_dynamic_label();
+        let lanes_label = ops.new_dynamic_label();
+        let odd_lanes_label = ops.new_dynamic_label();
+        VectorCompilationContext {
+            ops,
+            parameter_count: 0,
+            register_parameters: Vec::new(),
+            var: HashMap::new(),
+            scalar_slots: 0,
+            vector_slots: 0,
+            spill_slots: Vec::new(),
+            division_slots: None,
+            loop_var: String::new(),
+            loop_var_slot: 0,
+            mask_slot: 0,
+            bail_label,
+            lanes_label,
+            odd_lanes_label,
+        }
+    }
+
+    pub fn add_parameter(&mut self, var: String) {
+        // The execution context is passed as first parameter
+        let index = self.parameter_count + 1;
+        self.parameter_count += 1;
+        let displacement = match PARAMETER_REGISTERS.get(index) {
+            Some(reg) => {
+                self.scalar_slots += 1;
+                let displacement = -CALLEE_SAVED_SIZE - 8 * self.scalar_slots;
+                self.register_parameters.push((*reg, displacement));
+                displacement
+            }
+            None => stack_parameter_displacement(index),
+        };
+        self.var.insert(var, displacement);
+    }
+
+    /// Compiles a loop which evaluates the expression for all values of the loop variable from its
+    /// initial value up to an end value (inclusive), which is passed as an additional parameter. The
+    /// loop variable has to be the last parameter. The compiled code returns the first value of the
+    /// first block of 8 values which contains a value for which the expression evaluates to 0 or
+    /// fails, or `SCAN_NOT_FOUND`.
+    pub fn compile_scan(mut self, expr: &Expr, loop_var: &str) -> Result<Runable, Error> {
+        let start = *self.var.get(loop_var).ok_or_else(|| Error::UndefinedVariable { name: loop_var.to_string(), function: None, span: None })?;
+        self.add_parameter(SCAN_END.to_string());
+        let end = self.var[SCAN_END];
+        self.loop_var = loop_var.to_string();
+        let expr = expr.optimize();
+        if !can_vectorize(&expr) {
+            return Err(Error::NotVectorizable { expr: expr.to_string() });
+        }
+
+        println!("JIT> Vector compiler called. Starting assembly ...");
+        let offset = self.ops.offset();
+        dynasm!(self.ops
+            ; .arch x64
+            ; push rbp
+            ; mov rbp, rsp
+            ; push rbx
+            ; push r12
+            ; push r13
+            ; push r14
+            ; push r15
+        );
+        let frame_offset = self.ops.offset();
+        dynasm!(self.ops
+            ; sub rsp, DWORD 0
+        );
+        for (reg, displacement) in self.register_parameters.clone() {
+            dynasm!(self.ops
+                ; mov QWORD [rbp + displacement], Rq(reg.code())
+            );
+        }
+        self.loop_var_slot = self.next_slot();
+        let top_mask_slot = self.next_slot();
+        self.mask_slot = top_mask_slot;
+        let loop_label = self.ops.new_dynamic_label();
+        let not_found_label = self.ops.new_dynamic_label();
+        let exit_label = self.ops.new_dynamic_label();
+        let loop_var_displacement = self.slot_displacement(self.loop_var_slot);
+        dynasm!(self.ops
+            ; movsxd Rq(BLOCK_REGISTER.code()), DWORD [rbp + start]
+            ; movsxd Rq(END_REGISTER.code()), DWORD [rbp + end]
+            ; vpbroadcastd Ry(S0), DWORD [rbp + start]
+            ; vpaddd Ry(S0), Ry(S0), [=>self.lanes_label]
+            ; vmovdqu [rbp + loop_var_displacement], Ry(S0)
+            ; =>loop_label
+            // Only the lanes up to the end of the range are active: lane < min(end - block, 7) + 1
+            ; mov rax, Rq(END_REGISTER.code())
+            ; sub rax, Rq(BLOCK_REGISTER.code())
+            ; mov ecx, (LANES - 1) as i32
+            ; cmp rax, rcx
+            ; cmova rax, rcx
+            ; inc eax
+            ; vmovd Rx(S0), eax
+            ; vpbroadcastd Ry(S0), Rx(S0)
+            ; vpcmpgtd Ry(S0), Ry(S0), [=>self.lanes_label]
+        );
+        self.store_mask(top_mask_slot, S0);
+        self.compile_expr(&expr, 0)?;
+        let result = self.load(0, S0);
+        let mask_displacement = self.slot_displacement(top_mask_slot);
+        dynasm!(self.ops
+            ; vpxor Ry(S1), Ry(S1), Ry(S1)
+            ; vpcmpeqd Ry(S1), Ry(S1), Ry(result)
+            ; vpand Ry(S1), Ry(S1), [rbp + mask_displacement]
+            ; vptest Ry(S1), Ry(S1)
+            ; jnz =>self.bail_label
+            ; lea rax, [Rq(BLOCK_REGISTER.code()) + (LANES - 1) as i32]
+            ; cmp rax, Rq(END_REGISTER.code())
+            ; jge =>not_found_label
+            ; add Rq(BLOCK_REGISTER.code()), LANES as i32
+            ; mov eax, LANES as i32
+            ; vmovd Rx(S0), eax
+            ; vpbroadcastd Ry(S0), Rx(S0)
+            ; vpaddd Ry(S0), Ry(S0), [rbp + loop_var_displacement]
+            ; vmovdqu [rbp + loop_var_displacement], Ry(S0)
+            ; jmp =>loop_label
+            ; =>not_found_label
+            ; mov rax, QWORD SCAN_NOT_FOUND
+            ; jmp =>exit_label
+            ; =>self.bail_label
+            ; mov rax, Rq(BLOCK_REGISTER.code())
+            ; =>exit_label
+            ; vzeroupper
+            ; lea rsp, [rbp - CALLEE_SAVED_SIZE]
+            ; pop r15
+            ; pop r14
+            ; pop r13
+            ; pop r12
+            ; pop rbx
+            ; pop rbp
+            ; ret
+            ; =>self.lanes_label
+            ; .bytes (0..LANES as i32).flat_map(i32::to_le_bytes).collect::<Vec<_>>()
+            ; =>self.odd_lanes_label
+            ; .bytes (0..LANES as i32).flat_map(|lane| (-(lane % 2)).to_le_bytes()).collect::<Vec<_>>()
+        );
+
+        let frame_size = 8 * self.scalar_slots + 32 * self.vector_slots;
+        let mut modifier = self.ops.alter_uncommitted();
+        modifier.goto(frame_offset);
+        dynasm!(modifier
+            ; .arch x64
+            ; sub rsp, DWORD frame_size
+        );
+
+        let buf = self.ops.finalize().unwrap();
+
+        println!("JIT> Vector compilation finished. Code has size {} @{:p}.", buf.len(), buf.ptr(offset));
+
+        Ok(Runable::new(buf, offset))
+    }
+
+    fn next_slot(&mut self) -> i32 {
+        self.vector_slots += 1;
+        self.vector_slots - 1
+    }
+
+    fn slot_displacement(&self, slot: i32) -> i32 {
+        -CALLEE_SAVED_SIZE - 8 * self.scalar_slots - 32 * (slot + 1)
+    }
+
+    fn store_mask(&mut self, slot: i32, reg: u8) {
+        let displacement = self.slot_displacement(slot);
+        dynasm!(self.ops
+            ; vmovdqu [rbp + displacement], Ry(reg)
+        );
+    }
+
+    /// Register which holds the stack entry at the given depth, or the scratch register if the entry
+    /// is located in the frame.
+    fn target(&self, depth: usize, scratch: u8) -> u8 {
+        if depth < STACK_REGISTERS { depth as u8 } else { scratch }
+    }
+
+    /// Makes sure that the stack entry is located in a register and returns it.
+    fn load(&mut self, depth: usize, scratch: u8) -> u8 {
+        if depth < STACK_REGISTERS {
+            return depth as u8;
+        }
+        let displacement = self.spill_displacement(depth);
+        dynasm!(self.ops
+            ; vmovdqu Ry(scratch), [rbp + displacement]
+        );
+        scratch
+    }
+
+    /// Stores the register as the stack entry at the given depth.
+    fn store(&mut self, depth: usize, reg: u8) {
+        if depth < STACK_REGISTERS {
+            if reg != depth as u8 {
+                dynasm!(self.ops
+                    ; vmovdqa Ry(depth as u8), Ry(reg)
+                );
+            }
+            return;
+        }
+        let displacement = self.spill_displacement(depth);
+        dynasm!(self.ops
+            ; vmovdqu [rbp + displacement], Ry(reg)
+        );
+    }
+
+    fn spill_displacement(&mut self, depth: usize) -> i32 {
+        let index = depth - STACK_REGISTERS;
+        while self.spill_slots.len() <= index {
+            let slot = self.next_slot();
+            self.spill_slots.push(slot);
+        }
+        self.slot_displacement(self.spill_slots[index])
+    }
+
+    fn broadcast(&mut self, reg: u8, value: i32) {
+        dynasm!(self.ops
+            ; mov eax, value
+            ; vmovd Rx(reg), eax
+            ; vpbroadcastd Ry(reg), Rx(reg)
+        );
+    }
+
+    /// Compiles the expression and leaves its result in the stack entry at the given depth.
+    fn compile_expr(&mut self, expr: &Expr, depth: usize) -> Result<(), Error> {
+        match expr {
+            Expr::Number(number) => {
+                let target = self.target(depth, S0);
+                self.broadcast(target, *number);
+                self.store(depth, target);
+            }
+            Expr::Var(var, source) => {
+                let target = self.target(depth, S0);
+                if *var == self.loop_var {
+                    let displacement = self.slot_displacement(self.loop_var_slot);
+                    dynasm!(self.ops
+                        ; vmovdqu Ry(target), [rbp + displacement]
+                    );
+                } else {
+                    // The other variables have the same value in all lanes
+                    let displacement = *self.var.get(var).ok_or_else(|| Error::UndefinedVariable { name: var.to_string(), function: None, span: source.0 })?;
+                    dynasm!(self.ops
+                        ; vpbroadcastd Ry(target), DWORD [rbp + displacement]
+                    );
+                }
+                self.store(depth, target);
+            }
+            Expr::Neg(operand) => self.compile_with_constant(operand, depth, |ctx, target, a| dynasm!(ctx.ops
+                ; vpxor Ry(S1), Ry(S1), Ry(S1)
+                ; vpsubd Ry(target), Ry(S1), Ry(a)
+            ))?,
+            Expr::Add(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpaddd Ry(target), Ry(a), Ry(b)
+            ))?,
+            Expr::Sub(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpsubd Ry(target), Ry(a), Ry(b)
+            ))?,
+            Expr::Mul(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpmulld Ry(target), Ry(a), Ry(b)
+            ))?,
+            Expr::Div(lhs, rhs) => match **rhs {
+                Expr::Number(divisor @ (i32::MIN..=-2 | 1..=i32::MAX)) => self.compile_with_constant(lhs, depth, |ctx, target, a| {
+                    ctx.emit_div_by_constant(target, a, divisor)
+                })?,
+                _ => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| ctx.emit_division_by_lanes(target, a, b, false))?,
+            },
+            Expr::Rem(lhs, rhs) => match **rhs {
+                Expr::Number(divisor @ (i32::MIN..=-2 | 1..=i32::MAX)) => self.compile_with_constant(lhs, depth, |ctx, target, a| {
+                    // x % d = x - x / |d| * |d|, see `compile_rem` of the scalar compiler
+                    let divisor = divisor.unsigned_abs() as i32;
+                    ctx.emit_div_by_constant(S1, a, divisor);
+                    ctx.broadcast(S2, divisor);
+                    dynasm!(ctx.ops
+                        ; vpmulld Ry(S1), Ry(S1), Ry(S2)
+                        ; vpsubd Ry(target), Ry(a), Ry(S1)
+                    );
+                })?,
+                _ => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| ctx.emit_division_by_lanes(target, a, b, true))?,
+            },
+            Expr::BitAnd(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpand Ry(target), Ry(a), Ry(b)
+            ))?,
+            Expr::BitOr(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpor Ry(target), Ry(a), Ry(b)
+            ))?,
+            Expr::BitXor(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpxor Ry(target), Ry(a), Ry(b)
+            ))?,
+            // The variable shifts of AVX2 do not mask the shift amount like the scalar shifts do
+            Expr::Shl(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| {
+                ctx.emit_shift_amount(b);
+                dynasm!(ctx.ops
+                    ; vpsllvd Ry(target), Ry(a), Ry(S2)
+                )
+            })?,
+            Expr::Sar(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| {
+                ctx.emit_shift_amount(b);
+                dynasm!(ctx.ops
+                    ; vpsravd Ry(target), Ry(a), Ry(S2)
+                )
+            })?,
+            Expr::Shr(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| {
+                ctx.emit_shift_amount(b);
+                dynasm!(ctx.ops
+                    ; vpsrlvd Ry(target), Ry(a), Ry(S2)
+                )
+            })?,
+            // Comparisons yield -1 (all bits set) or 0 per lane, which is turned into 1 or 0 by a shift
+            // or into 0 or 1 by adding 1.
+            Expr::Eq(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpcmpeqd Ry(target), Ry(a), Ry(b)
+                ; vpsrld Ry(target), Ry(target), 31
+            ))?,
+            Expr::Neq(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpcmpeqd Ry(target), Ry(a), Ry(b)
+                ; vpcmpeqd Ry(S2), Ry(S2), Ry(S2)
+                ; vpsubd Ry(target), Ry(target), Ry(S2)
+            ))?,
+            Expr::Gt(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpcmpgtd Ry(target), Ry(a), Ry(b)
+                ; vpsrld Ry(target), Ry(target), 31
+            ))?,
+            Expr::Lt(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpcmpgtd Ry(target), Ry(b), Ry(a)
+                ; vpsrld Ry(target), Ry(target), 31
+            ))?,
+            Expr::Gte(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpcmpgtd Ry(target), Ry(b), Ry(a)
+                ; vpcmpeqd Ry(S2), Ry(S2), Ry(S2)
+                ; vpsubd Ry(target), Ry(target), Ry(S2)
+            ))?,
+            Expr::Lte(lhs, rhs) => self.compile_op(lhs, rhs, depth, |ctx, target, a, b| dynasm!(ctx.ops
+                ; vpcmpgtd Ry(target), Ry(a), Ry(b)
+                ; vpcmpeqd Ry(S2), Ry(S2), Ry(S2)
+                ; vpsubd Ry(target), Ry(target), Ry(S2)
+            ))?,
+            Expr::Not(operand) => self.compile_with_constant(operand, depth, |ctx, target, a| dynasm!(ctx.ops
+                ; vpxor Ry(S1), Ry(S1), Ry(S1)
+                ; vpcmpeqd Ry(target), Ry(a), Ry(S1)
+                ; vpsrld Ry(target), Ry(target), 31
+            ))?,
+            Expr::BitNot(operand) => self.compile_with_constant(operand, depth, |ctx, target, a| dynasm!(ctx.ops
+                ; vpcmpeqd Ry(S1), Ry(S1), Ry(S1)
+                ; vpxor Ry(target), Ry(a), Ry(S1)
+            ))?,
+            // The right operand is evaluated like a branch, so only for the lanes which need it
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) | Expr::Implies(lhs, rhs) => {
+                let (skip_if, skipped) = expr.short_circuit().unwrap();
+                let holds = Expr::Neq(rhs.clone(), Box::new(Expr::Number(0)));
+                let skipped = Expr::Number(skipped);
+                if skip_if {
+                    self.compile_if(lhs, &skipped, &holds, depth)?
+                } else {
+                    self.compile_if(lhs, &holds, &skipped, depth)?
+                }
+            }
+            Expr::If(condition, then_expr, else_expr) => self.compile_if(condition, then_expr, else_expr, depth)?,
+            Expr::BuiltinCall(builtin, args, _) => self.compile_builtin_call(*builtin, args, depth)?,
+            Expr::FunctionCall(_, _, _) => return Err(Error::NotVectorizable { expr: expr.to_string() }),
+        }
+        Ok(())
+    }
+
+    /// Compiles the arguments to the stack, loads them into registers and computes the built-in function
+    /// in all lanes. abs(-2147483648) is -2147483648, like the scalar code.
+    fn compile_builtin_call(&mut self, builtin: Builtin, args: &[Expr], depth: usize) -> Result<(), Error> {
+        for (i, arg) in args.iter().enumerate() {
+            self.compile_expr(arg, depth + i)?;
+        }
+        let mut regs = Vec::new();
+        for (i, scratch) in [S0, S1, S2].iter().copied().enumerate().take(args.len()) {
+            regs.push(self.load(depth + i, scratch));
+        }
+        let target = self.target(depth, S0);
+        match (builtin, &regs[..]) {
+            (Builtin::Abs, &[x]) => dynasm!(self.ops
+                ; vpabsd Ry(target), Ry(x)
+            ),
+            (Builtin::Min, &[a, b]) => dynasm!(self.ops
+                ; vpminsd Ry(target), Ry(a), Ry(b)
+            ),
+            (Builtin::Max, &[a, b]) => dynasm!(self.ops
+                ; vpmaxsd Ry(target), Ry(a), Ry(b)
+            ),
+            // 1 negated for negative lanes and zeroed for zero lanes
+            (Builtin::Sign, &[x]) => {
+                self.broadcast(S3, 1);
+                dynasm!(self.ops
+                    ; vpsignd Ry(target), Ry(S3), Ry(x)
+                )
+            }
+            (Builtin::Clamp, &[x, lo, hi]) => dynasm!(self.ops
+                ; vpmaxsd Ry(target), Ry(x), Ry(lo)
+                ; vpminsd Ry(target), Ry(target), Ry(hi)
+            ),
+            _ => return Err(Error::NotVectorizable { expr: Expr::BuiltinCall(builtin, args.to_vec(), SourceSpan::default()).to_string() }),
+        }
+        self.store(depth, target);
+        Ok(())
+    }
+
+    /// Compiles both operands to the stack and combines them with `gen`, which gets the target register
+    /// and the registers of the operands. `gen` may use the scratch registers S2 and S3.
+    fn compile_op(&mut self, lhs: &Expr, rhs: &Expr, depth: usize, gen: impl FnOnce(&mut Self, u8, u8, u8)) -> Result<(), Error> {
+        self.compile_expr(lhs, depth)?;
+        self.compile_expr(rhs, depth + 1)?;
+        let a = self.load(depth, S0);
+        let b = self.load(depth + 1, S1);
+        let target = self.target(depth, S0);
+        gen(self, target, a, b);
+        self.store(depth, target);
+        Ok(())
+    }
+
+    /// Leaves the lowest 5 bits of the shift amounts in S2. Uses S3.
+    fn emit_shift_amount(&mut self, amounts: u8) {
+        self.broadcast(S3, 31);
+        dynasm!(self.ops
+            ; vpand Ry(S2), Ry(amounts), Ry(S3)
+        );
+    }
+
+    /// Compiles an operation with a single operand, e.g. a constant right hand side which is encoded into
+    /// the instructions. `gen` may use the scratch registers S1 to S3.
+    fn compile_with_constant(&mut self, lhs: &Expr, depth: usize, gen: impl FnOnce(&mut Self, u8, u8)) -> Result<(), Error> {
+        self.compile_expr(lhs, depth)?;
+        let a = self.load(depth, S0);
+        let target = self.target(depth, S0);
+        gen(self, target, a);
+        self.store(depth, target);
+        Ok(())
+    }
+
+    /// Computes the quotient of the register and the constant, which must neither be 0 nor -1, like
+    /// `emit_div_by_constant` of the scalar compiler. Uses the scratch registers S1 to S3, but `a`
+    /// is only overwritten at the end.
+    fn emit_div_by_constant(&mut self, target: u8, a: u8, divisor: i32) {
+        let abs_divisor = divisor.unsigned_abs();
+        if abs_divisor.is_power_of_two() {
+            let shift = abs_divisor.trailing_zeros() as i8;
+            if shift > 0 {
+                dynasm!(self.ops
+                    ; vpsrad Ry(S3), Ry(a), 31
+                    ; vpsrld Ry(S3), Ry(S3), 32 - shift
+                    ; vpaddd Ry(S3), Ry(S3), Ry(a)
+                    ; vpsrad Ry(target), Ry(S3), shift
+                );
+            } else if target != a {
+                dynasm!(self.ops
+                    ; vmovdqa Ry(target), Ry(a)
+                );
+            }
+            if divisor < 0 {
+                dynasm!(self.ops
+                    ; vpxor Ry(S3), Ry(S3), Ry(S3)
+                    ; vpsubd Ry(target), Ry(S3), Ry(target)
+                );
+            }
+            return;
+        }
+        let (magic, shift) = crate::compiler::signed_magic(divisor);
+        // There is no instruction for the high half of a 32 bit multiplication. vpmuldq computes the
+        // full products of the even lanes, so the odd lanes are moved into the even lanes for a second
+        // multiplication. The shuffle 0xF5 copies lanes 1 and 3 to lanes 0 and 2. The high halves of
+        // the products are merged with vpblendvb, as dynasm 1.2 swaps the sources of vpblendd.
+        self.broadcast(S3, magic);
+        dynasm!(self.ops
+            ; vpmuldq Ry(S2), Ry(a), Ry(S3)
+            ; vpshufd Ry(S2), Ry(S2), 0xF5_u8 as i8
+            ; vpshufd Ry(S1), Ry(a), 0xF5_u8 as i8
+            ; vpmuldq Ry(S1), Ry(S1), Ry(S3)
+            ; vmovdqu Ry(S3), [=>self.odd_lanes_label]
+            ; vpblendvb Ry(S2), Ry(S2), Ry(S1), Ry(S3)
+        );
+        if divisor > 0 && magic < 0 {
+            dynasm!(self.ops
+                ; vpaddd Ry(S2), Ry(S2), Ry(a)
+            );
+        } else if divisor < 0 && magic > 0 {
+            dynasm!(self.ops
+                ; vpsubd Ry(S2), Ry(S2), Ry(a)
+            );
+        }
+        if shift > 0 {
+            dynasm!(self.ops
+                ; vpsrad Ry(S2), Ry(S2), shift as i8
+            );
+        }
+        dynasm!(self.ops
+            ; vpsrld Ry(S3), Ry(S2), 31
+            ; vpaddd Ry(target), Ry(S2), Ry(S3)
+        );
+    }
+
+    /// Divides lane by lane with idiv. If the division would trap in any active lane, the block is
+    /// handed over to the scalar code. Inactive lanes are divided by 1 instead.
+    fn emit_division_by_lanes(&mut self, target: u8, a: u8, b: u8, remainder: bool) {
+        let mask_displacement = self.slot_displacement(self.mask_slot);
+        let (dividend_slot, divisor_slot) = match self.division_slots {
+            Some(slots) => slots,
+            None => {
+                let slots = (self.next_slot(), self.next_slot());
+                self.division_slots = Some(slots);
+                slots
+            }
+        };
+        let dividend = self.slot_displacement(dividend_slot);
+        let divisor = self.slot_displacement(divisor_slot);
+        dynasm!(self.ops
+            // Lanes which divide by 0 or divide INT_MIN by -1
+            ; vpxor Ry(S2), Ry(S2), Ry(S2)
+            ; vpcmpeqd Ry(S2), Ry(S2), Ry(b)
+            ; vpcmpeqd Ry(S3), Ry(S3), Ry(S3)
+            ; vpcmpeqd Ry(S3), Ry(S3), Ry(b)
+            ; vmovdqu [rbp + divisor], Ry(S2)
+        );
+        self.broadcast(S2, i32::MIN);
+        dynasm!(self.ops
+            ; vpcmpeqd Ry(S2), Ry(S2), Ry(a)
+            ; vpand Ry(S2), Ry(S2), Ry(S3)
+            ; vpor Ry(S2), Ry(S2), [rbp + divisor]
+            ; vpand Ry(S2), Ry(S2), [rbp + mask_displacement]
+            ; vptest Ry(S2), Ry(S2)
+            ; jnz =>self.bail_label
+        );
+        self.broadcast(S2, 1);
+        dynasm!(self.ops
+            ; vmovdqu Ry(S3), [rbp + mask_displacement]
+            ; vpblendvb Ry(S2), Ry(S2), Ry(b), Ry(S3)
+            ; vmovdqu [rbp + dividend], Ry(a)
+            ; vmovdqu [rbp + divisor], Ry(S2)
+        );
+        for lane in 0..LANES as i32 {
+            dynasm!(self.ops
+                ; mov eax, DWORD [rbp + dividend + 4 * lane]
+                ; cdq
+                ; idiv DWORD [rbp + divisor + 4 * lane]
+            );
+            if remainder {
+                dynasm!(self.ops
+                    ; mov DWORD [rbp + dividend + 4 * lane], edx
+                );
+            } else {
+                dynasm!(self.ops
+                    ; mov DWORD [rbp + dividend + 4 * lane], eax
+                );
+            }
+        }
+        dynasm!(self.ops
+            ; vmovdqu Ry(target), [rbp + dividend]
+        );
+    }
+
+    /// Evaluates both branches for the lanes which take them and blends the results. A branch is
+    /// skipped if no active lane takes it.
+    fn compile_if(&mut self, condition: &Expr, then_expr: &Expr, else_expr: &Expr, depth: usize) -> Result<(), Error> {
+        self.compile_expr(condition, depth)?;
+        let condition = self.load(depth, S0);
+        let mask_displacement = self.slot_displacement(self.mask_slot);
+        let then_mask_slot = self.next_slot();
+        let else_mask_slot = self.next_slot();
+        dynasm!(self.ops
+            ; vpxor Ry(S1), Ry(S1), Ry(S1)
+            ; vpcmpeqd Ry(S1), Ry(S1), Ry(condition)
+            ; vpandn Ry(S2), Ry(S1), [rbp + mask_displacement]
+            ; vpand Ry(S1), Ry(S1), [rbp + mask_displacement]
+        );
+        self.store_mask(then_mask_slot, S2);
+        self.store_mask(else_mask_slot, S1);
+
+        let outer_mask_slot = self.mask_slot;
+        for (expr, mask_slot, depth) in [(then_expr, then_mask_slot, depth), (else_expr, else_mask_slot, depth + 1)] {
+            let skip_label = self.ops.new_dynamic_label();
+            let displacement = self.slot_displacement(mask_slot);
+            dynasm!(self.ops
+                ; vmovdqu Ry(S1), [rbp + displacement]
+                ; vptest Ry(S1), Ry(S1)
+                ; jz =>skip_label
+            );
+            self.mask_slot = mask_slot;
+            self.compile_expr(expr, depth)?;
+            dynasm!(self.ops
+                ; =>skip_label
+            );
+        }
+        self.mask_slot = outer_mask_slot;
+
+        let then_value = self.load(depth, S0);
+        let else_value = self.load(depth + 1, S1);
+        let target = self.target(depth, S0);
+        let displacement = self.slot_displacement(then_mask_slot);
+        dynasm!(self.ops
+            ; vmovdqu Ry(S2), [rbp + displacement]
+            ; vpblendvb Ry(target), Ry(else_value), Ry(then_value), Ry(S2)
+        );
+        self.store(depth, target);
+        Ok(())
+    }
+}